@@ -0,0 +1,61 @@
+// compares the interpreter with and without fused ops, and native code when built with
+// the jit feature, run with `cargo bench` or `cargo bench --features jit`
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tinywasm::execution::{
+    config::Config, linker::Linker, module::Module, runtime::Runtime, value::Value,
+};
+
+const ROUNDS: u32 = 5;
+
+fn configs() -> Vec<(&'static str, Config)> {
+    let interpreted = |fuse| {
+        let mut config = Config::new();
+        config.fuse(fuse);
+        #[cfg(feature = "jit")]
+        config.jit(false);
+        config
+    };
+    let configs = vec![
+        ("unfused", interpreted(false)),
+        ("fused", interpreted(true)),
+    ];
+    #[cfg(feature = "jit")]
+    let configs = [configs, vec![("jit", Config::new())]].concat();
+    configs
+}
+
+fn bench(name: &str, wasm: &[u8], func: &str, args: Vec<Value>) -> Result<()> {
+    let mut times = vec![];
+    for (config_name, config) in configs() {
+        let module = Module::new_with_config(wasm, &config)?;
+        let mut runtime = Runtime::new(());
+        Linker::new().instantiate(&mut runtime, &module)?;
+
+        // the fastest round is the least disturbed by the rest of the machine
+        let mut best = Duration::MAX;
+        for _ in 0..ROUNDS {
+            let start = Instant::now();
+            runtime.call(func, args.clone())?;
+            best = best.min(start.elapsed());
+        }
+        times.push(format!("{config_name} {best:?}"));
+    }
+    println!("{name}: {}", times.join(", "));
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let fib = wat::parse_file("src/fixtures/fib.wat")?;
+    bench("fib(27)", &fib, "fib", vec![Value::I32(27)])?;
+
+    let multi_value = wat::parse_file("src/fixtures/multi_value.wat")?;
+    bench(
+        "loop_sum(10000000)",
+        &multi_value,
+        "loop_sum",
+        vec![Value::I32(10_000_000)],
+    )?;
+    Ok(())
+}
@@ -2,15 +2,25 @@ use super::types::Block;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instruction {
+    Unreachable,
+    Block(Block),
+    Loop(Block),
     If(Block),
+    Else,
     End,
+    Br(u32),
+    BrIf(u32),
     Return,
     LocalGet(u32),
     LocalSet(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
     I32Store { align: u32, offset: u32 },
     I32Const(i32),
+    I64Const(i64),
     I32Lts,
     I32Add,
     I32Sub,
     Call(u32),
+    CallIndirect { type_idx: u32, table_idx: u32 },
 }
@@ -1,20 +1,21 @@
 use super::{
     instruction::Instruction,
     opcode::Opcode,
-    section::{Function, SectionCode},
+    section::{Function, LazyCode, SectionCode},
     types::{
-        Block, BlockType, Data, Export, ExportDesc, FuncType, FunctionLocal, Import, ImportDesc,
-        Limits, Memory, ValueType,
+        Block, BlockType, ConstExpr, Data, Element, Export, ExportDesc, FuncType, FunctionLocal,
+        Global, GlobalType, Import, ImportDesc, Limits, Memory, RefType, Table, ValueType,
     },
 };
 use nom::{
     bytes::complete::{tag, take},
+    error::{Error, ErrorKind},
     multi::many0,
     number::complete::{le_u32, le_u8},
     sequence::pair,
     IResult,
 };
-use nom_leb128::{leb128_i32, leb128_u32};
+use nom_leb128::{leb128_i32, leb128_i64, leb128_u32};
 use num_traits::FromPrimitive as _;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -23,9 +24,14 @@ pub struct Module {
     pub version: u32,
     pub memory_section: Option<Vec<Memory>>,
     pub data_section: Option<Vec<Data>>,
+    pub table_section: Option<Vec<Table>>,
+    pub element_section: Option<Vec<Element>>,
+    pub global_section: Option<Vec<Global>>,
     pub type_section: Option<Vec<FuncType>>,
     pub function_section: Option<Vec<u32>>,
     pub code_section: Option<Vec<Function>>,
+    // set instead of the code section when the module is decoded lazily
+    pub lazy_code_section: Option<LazyCode>,
     pub export_section: Option<Vec<Export>>,
     pub import_section: Option<Vec<Import>>,
 }
@@ -37,9 +43,13 @@ impl Default for Module {
             version: 1,
             memory_section: None,
             data_section: None,
+            table_section: None,
+            element_section: None,
+            global_section: None,
             type_section: None,
             function_section: None,
             code_section: None,
+            lazy_code_section: None,
             export_section: None,
             import_section: None,
         }
@@ -48,12 +58,19 @@ impl Default for Module {
 
 impl Module {
     pub fn new(input: &[u8]) -> anyhow::Result<Module> {
-        let (_, module) =
-            Module::decode(input).map_err(|e| anyhow::anyhow!("failed to parse wasm: {}", e))?;
+        let (_, module) = Module::decode(input, false)
+            .map_err(|e| anyhow::anyhow!("failed to parse wasm: {}", e))?;
         Ok(module)
     }
 
-    fn decode(input: &[u8]) -> IResult<&[u8], Module> {
+    // leaves the function bodies to be decoded one at a time with LazyCode::decode
+    pub fn new_lazy(input: &[u8]) -> anyhow::Result<Module> {
+        let (_, module) = Module::decode(input, true)
+            .map_err(|e| anyhow::anyhow!("failed to parse wasm: {}", e))?;
+        Ok(module)
+    }
+
+    fn decode(input: &[u8], lazy: bool) -> IResult<&[u8], Module> {
         let (input, _) = tag(b"\0asm")(input)?;
         let (input, version) = le_u32(input)?;
 
@@ -82,6 +99,18 @@ impl Module {
                             let (_, data) = deocde_data_section(section_contents)?;
                             module.data_section = Some(data);
                         }
+                        SectionCode::Table => {
+                            let (_, tables) = decode_table_section(section_contents)?;
+                            module.table_section = Some(tables);
+                        }
+                        SectionCode::Global => {
+                            let (_, globals) = decode_global_section(section_contents)?;
+                            module.global_section = Some(globals);
+                        }
+                        SectionCode::Element => {
+                            let (_, elements) = decode_element_section(section_contents)?;
+                            module.element_section = Some(elements);
+                        }
                         SectionCode::Type => {
                             let (_, types) = decode_type_section(section_contents)?;
                             module.type_section = Some(types);
@@ -90,6 +119,10 @@ impl Module {
                             let (_, func_idx_list) = decode_function_section(section_contents)?;
                             module.function_section = Some(func_idx_list);
                         }
+                        SectionCode::Code if lazy => {
+                            let (_, code) = decode_lazy_code_section(section_contents)?;
+                            module.lazy_code_section = Some(code);
+                        }
                         SectionCode::Code => {
                             let (_, funcs) = decode_code_section(section_contents)?;
                             module.code_section = Some(funcs);
@@ -125,8 +158,11 @@ fn decode_section_header(input: &[u8]) -> IResult<&[u8], (SectionCode, u32)> {
 }
 
 fn decode_value_type(input: &[u8]) -> IResult<&[u8], ValueType> {
-    let (input, value_type) = le_u8(input)?;
-    Ok((input, value_type.into()))
+    let (rest, byte) = le_u8(input)?;
+    let Some(value_type) = ValueType::from_u8(byte) else {
+        return invalid(input);
+    };
+    Ok((rest, value_type))
 }
 
 fn decode_type_section(input: &[u8]) -> IResult<&[u8], Vec<FuncType>> {
@@ -183,21 +219,80 @@ fn decode_code_section(input: &[u8]) -> IResult<&[u8], Vec<Function>> {
     Ok((&[], functions))
 }
 
-fn decode_function_body(input: &[u8]) -> IResult<&[u8], Function> {
-    let mut body = Function::default();
-
-    let (mut input, count) = leb128_u32(input)?;
+fn decode_lazy_code_section(input: &[u8]) -> IResult<&[u8], LazyCode> {
+    let mut bodies = vec![];
+    let (mut rest, count) = leb128_u32(input)?;
 
     for _ in 0..count {
-        let (rest, type_count) = leb128_u32(input)?;
-        let (rest, value_type) = le_u8(rest)?;
-        body.locals.push(FunctionLocal {
-            type_count,
-            value_type: value_type.into(),
-        });
-        input = rest;
+        let (body, size) = leb128_u32(rest)?;
+        let start = input.len() - body.len();
+        (rest, _) = take(size)(body)?;
+        bodies.push(start..start + size as usize);
     }
 
+    let code = LazyCode {
+        bytes: input.into(),
+        bodies,
+    };
+    Ok((&[], code))
+}
+
+impl LazyCode {
+    pub fn decode(&self, idx: usize) -> anyhow::Result<Function> {
+        let Some(range) = self.bodies.get(idx) else {
+            anyhow::bail!("unknown function body {}", idx);
+        };
+        let (_, body) = decode_function_body(&self.bytes[range.clone()])
+            .map_err(|e| anyhow::anyhow!("failed to parse wasm: {}", e))?;
+        Ok(body)
+    }
+
+    // the locals of a body and its instructions, which are decoded as they are iterated
+    // instead of being collected
+    pub fn instructions(
+        &self,
+        idx: usize,
+    ) -> anyhow::Result<(Vec<FunctionLocal>, Instructions<'_>)> {
+        let Some(range) = self.bodies.get(idx) else {
+            anyhow::bail!("unknown function body {}", idx);
+        };
+        let (input, locals) = decode_locals(&self.bytes[range.clone()])
+            .map_err(|e| anyhow::anyhow!("failed to parse wasm: {}", e))?;
+        Ok((locals, Instructions { input }))
+    }
+}
+
+pub struct Instructions<'a> {
+    input: &'a [u8],
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = anyhow::Result<Instruction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.input.is_empty() {
+            return None;
+        }
+        match decode_instructions(self.input) {
+            Ok((rest, inst)) => {
+                self.input = rest;
+                Some(Ok(inst))
+            }
+            Err(e) => {
+                self.input = &[];
+                Some(Err(anyhow::anyhow!("failed to parse wasm: {}", e)))
+            }
+        }
+    }
+}
+
+fn decode_function_body(input: &[u8]) -> IResult<&[u8], Function> {
+    let (input, locals) = decode_locals(input)?;
+    let mut body = Function {
+        locals,
+        ..Default::default()
+    };
+
     let mut remaining = input;
 
     while !remaining.is_empty() {
@@ -209,14 +304,52 @@ fn decode_function_body(input: &[u8]) -> IResult<&[u8], Function> {
     Ok((&[], body))
 }
 
+fn decode_locals(input: &[u8]) -> IResult<&[u8], Vec<FunctionLocal>> {
+    let mut locals = vec![];
+
+    let (mut input, count) = leb128_u32(input)?;
+
+    for _ in 0..count {
+        let (rest, type_count) = leb128_u32(input)?;
+        let (rest, value_type) = decode_value_type(rest)?;
+        locals.push(FunctionLocal {
+            type_count,
+            value_type,
+        });
+        input = rest;
+    }
+
+    Ok((input, locals))
+}
+
 fn decode_instructions(input: &[u8]) -> IResult<&[u8], Instruction> {
     let (input, byte) = le_u8(input)?;
-    let op = Opcode::from_u8(byte).unwrap_or_else(|| panic!("invalid opcode: {:X}", byte));
+    let Some(op) = Opcode::from_u8(byte) else {
+        return invalid(input);
+    };
     let (rest, inst) = match op {
+        Opcode::Unreachable => (input, Instruction::Unreachable),
+        Opcode::Block => {
+            let (rest, block) = decode_block(input)?;
+            (rest, Instruction::Block(block))
+        }
+        Opcode::Loop => {
+            let (rest, block) = decode_block(input)?;
+            (rest, Instruction::Loop(block))
+        }
         Opcode::If => {
             let (rest, block) = decode_block(input)?;
             (rest, Instruction::If(block))
         }
+        Opcode::Else => (input, Instruction::Else),
+        Opcode::Br => {
+            let (rest, idx) = leb128_u32(input)?;
+            (rest, Instruction::Br(idx))
+        }
+        Opcode::BrIf => {
+            let (rest, idx) = leb128_u32(input)?;
+            (rest, Instruction::BrIf(idx))
+        }
         Opcode::Return => (input, Instruction::Return),
         Opcode::LocalGet => {
             let (rest, idx) = leb128_u32(input)?;
@@ -226,6 +359,14 @@ fn decode_instructions(input: &[u8]) -> IResult<&[u8], Instruction> {
             let (rest, idx) = leb128_u32(input)?;
             (rest, Instruction::LocalSet(idx))
         }
+        Opcode::GlobalGet => {
+            let (rest, idx) = leb128_u32(input)?;
+            (rest, Instruction::GlobalGet(idx))
+        }
+        Opcode::GlobalSet => {
+            let (rest, idx) = leb128_u32(input)?;
+            (rest, Instruction::GlobalSet(idx))
+        }
         Opcode::I32Store => {
             let (rest, align) = leb128_u32(input)?;
             let (rest, offset) = leb128_u32(rest)?;
@@ -235,6 +376,10 @@ fn decode_instructions(input: &[u8]) -> IResult<&[u8], Instruction> {
             let (rest, value) = leb128_i32(input)?;
             (rest, Instruction::I32Const(value))
         }
+        Opcode::I64Const => {
+            let (rest, value) = leb128_i64(input)?;
+            (rest, Instruction::I64Const(value))
+        }
         Opcode::I32LtS => (input, Instruction::I32Lts),
         Opcode::I32Add => (input, Instruction::I32Add),
         Opcode::I32Sub => (input, Instruction::I32Sub),
@@ -243,6 +388,17 @@ fn decode_instructions(input: &[u8]) -> IResult<&[u8], Instruction> {
             let (rest, idx) = leb128_u32(input)?;
             (rest, Instruction::Call(idx))
         }
+        Opcode::CallIndirect => {
+            let (rest, type_idx) = leb128_u32(input)?;
+            let (rest, table_idx) = leb128_u32(rest)?;
+            (
+                rest,
+                Instruction::CallIndirect {
+                    type_idx,
+                    table_idx,
+                },
+            )
+        }
     };
     Ok((rest, inst))
 }
@@ -257,7 +413,10 @@ fn decode_export_section(input: &[u8]) -> IResult<&[u8], Vec<Export>> {
         let (rest, idx) = leb128_u32(rest)?;
         let desc = match export_kind {
             0x00 => ExportDesc::Func(idx),
-            _ => unimplemented!("unsupported export kind: {:X}", export_kind),
+            0x01 => ExportDesc::Table(idx),
+            0x02 => ExportDesc::Memory(idx),
+            0x03 => ExportDesc::Global(idx),
+            _ => return invalid(rest),
         };
         exports.push(Export { name, desc });
         input = rest;
@@ -279,7 +438,19 @@ fn decode_import_section(input: &[u8]) -> IResult<&[u8], Vec<Import>> {
                 let (rest, idx) = leb128_u32(rest)?;
                 (rest, ImportDesc::Func(idx))
             }
-            _ => unimplemented!("unsupported import kind: {:X}", import_kind),
+            0x01 => {
+                let (rest, table) = decode_table(rest)?;
+                (rest, ImportDesc::Table(table))
+            }
+            0x02 => {
+                let (rest, limits) = decode_limits(rest)?;
+                (rest, ImportDesc::Memory(Memory { limits }))
+            }
+            0x03 => {
+                let (rest, global_type) = decode_global_type(rest)?;
+                (rest, ImportDesc::Global(global_type))
+            }
+            _ => return invalid(rest),
         };
 
         imports.push(Import {
@@ -300,13 +471,81 @@ fn decode_memory_section(input: &[u8]) -> IResult<&[u8], Memory> {
     Ok((input, Memory { limits }))
 }
 
+fn decode_table_section(input: &[u8]) -> IResult<&[u8], Vec<Table>> {
+    let (mut input, count) = leb128_u32(input)?;
+    let mut tables = vec![];
+    for _ in 0..count {
+        let (rest, table) = decode_table(input)?;
+        tables.push(table);
+        input = rest;
+    }
+    Ok((input, tables))
+}
+
+fn decode_table(input: &[u8]) -> IResult<&[u8], Table> {
+    let (rest, byte) = le_u8(input)?;
+    let Some(elem_type) = RefType::from_u8(byte) else {
+        return invalid(input);
+    };
+    let (rest, limits) = decode_limits(rest)?;
+    Ok((rest, Table { elem_type, limits }))
+}
+
+fn decode_global_section(input: &[u8]) -> IResult<&[u8], Vec<Global>> {
+    let (mut input, count) = leb128_u32(input)?;
+    let mut globals = vec![];
+    for _ in 0..count {
+        let (rest, global_type) = decode_global_type(input)?;
+        let (rest, init) = decode_const_expr(rest)?;
+        globals.push(Global { global_type, init });
+        input = rest;
+    }
+    Ok((input, globals))
+}
+
+fn decode_global_type(input: &[u8]) -> IResult<&[u8], GlobalType> {
+    let (input, value_type) = decode_value_type(input)?;
+    let (input, mutable) = le_u8(input)?;
+    Ok((
+        input,
+        GlobalType {
+            value_type,
+            mutable: mutable == 0x01,
+        },
+    ))
+}
+
+fn decode_const_expr(input: &[u8]) -> IResult<&[u8], ConstExpr> {
+    let (input, byte) = le_u8(input)?;
+    let Some(op) = Opcode::from_u8(byte) else {
+        return invalid(input);
+    };
+    let (input, expr) = match op {
+        Opcode::I32Const => {
+            let (rest, value) = leb128_i32(input)?;
+            (rest, ConstExpr::I32Const(value))
+        }
+        Opcode::I64Const => {
+            let (rest, value) = leb128_i64(input)?;
+            (rest, ConstExpr::I64Const(value))
+        }
+        Opcode::GlobalGet => {
+            let (rest, idx) = leb128_u32(input)?;
+            (rest, ConstExpr::GlobalGet(idx))
+        }
+        _ => return invalid(input),
+    };
+    let (input, _) = tag(&[Opcode::End as u8][..])(input)?;
+    Ok((input, expr))
+}
+
 fn decode_limits(input: &[u8]) -> IResult<&[u8], Limits> {
     let (input, (flags, min)) = pair(leb128_u32, leb128_u32)(input)?;
-    let max = if flags == 0 {
-        None
+    let (input, max) = if flags == 0 {
+        (input, None)
     } else {
-        let (_, max) = leb128_u32(input)?;
-        Some(max)
+        let (input, max) = leb128_u32(input)?;
+        (input, Some(max))
     };
 
     Ok((input, Limits { min, max }))
@@ -337,16 +576,59 @@ fn deocde_data_section(input: &[u8]) -> IResult<&[u8], Vec<Data>> {
     Ok((input, data))
 }
 
+fn decode_element_section(input: &[u8]) -> IResult<&[u8], Vec<Element>> {
+    let (mut input, count) = leb128_u32(input)?;
+    let mut elements = vec![];
+    for _ in 0..count {
+        let (rest, flags) = leb128_u32(input)?;
+        // only active segments for table 0 are supported
+        if flags != 0 {
+            return invalid(rest);
+        }
+        let (rest, offset) = decode_expr(rest)?;
+        let (mut rest, size) = leb128_u32(rest)?;
+        let mut init = vec![];
+        for _ in 0..size {
+            let (next, func_idx) = leb128_u32(rest)?;
+            init.push(func_idx);
+            rest = next;
+        }
+        elements.push(Element {
+            table_index: 0,
+            offset,
+            init,
+        });
+        input = rest;
+    }
+    Ok((input, elements))
+}
+
 fn decode_block(input: &[u8]) -> IResult<&[u8], Block> {
-    let (input, byte) = le_u8(input)?;
+    let (rest, byte) = le_u8(input)?;
 
-    let block_type = if byte == 0x40 {
-        BlockType::Void
-    } else {
-        BlockType::Value(vec![byte.into()])
+    let (rest, block_type) = match byte {
+        0x40 => (rest, BlockType::Void),
+        0x7C..=0x7F => {
+            let (rest, value_type) = decode_value_type(input)?;
+            (rest, BlockType::Value(vec![value_type]))
+        }
+        _ => {
+            // type index is encoded as a positive signed 33-bit integer, a negative one
+            // would be a value type this runtime does not know
+            let (rest, idx) = leb128_i64(input)?;
+            let Ok(idx) = u32::try_from(idx) else {
+                return invalid(input);
+            };
+            (rest, BlockType::FuncType(idx))
+        }
     };
 
-    Ok((input, Block { block_type }))
+    Ok((rest, Block { block_type }))
+}
+
+// malformed input is reported as a decode error instead of a panic
+fn invalid<T>(input: &[u8]) -> IResult<&[u8], T> {
+    Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)))
 }
 
 fn decode_name(input: &[u8]) -> IResult<&[u8], String> {
@@ -365,8 +647,9 @@ mod tests {
         module::Module,
         section::Function,
         types::{
-            Block, BlockType, Data, Export, ExportDesc, FuncType, FunctionLocal, Import,
-            ImportDesc, Limits, Memory, ValueType,
+            Block, BlockType, ConstExpr, Data, Element, Export, ExportDesc, FuncType,
+            FunctionLocal, Global, GlobalType, Import, ImportDesc, Limits, Memory, RefType, Table,
+            ValueType,
         },
     };
     use anyhow::Result;
@@ -699,4 +982,240 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn decode_block_type() -> Result<()> {
+        let wasm = wat::parse_str(
+            "(module (func (result i32 i32)
+              (block)
+              (loop (result i32) (i32.const 1))
+              (block (param i32) (result i32 i32) (i32.const 2))))",
+        )?;
+        let module = Module::new(&wasm)?;
+        assert_eq!(
+            module,
+            Module {
+                type_section: Some(vec![
+                    FuncType {
+                        params: vec![],
+                        results: vec![ValueType::I32, ValueType::I32],
+                    },
+                    FuncType {
+                        params: vec![ValueType::I32],
+                        results: vec![ValueType::I32, ValueType::I32],
+                    },
+                ]),
+                function_section: Some(vec![0]),
+                code_section: Some(vec![Function {
+                    locals: vec![],
+                    code: vec![
+                        Instruction::Block(Block {
+                            block_type: BlockType::Void
+                        }),
+                        Instruction::End,
+                        Instruction::Loop(Block {
+                            block_type: BlockType::Value(vec![ValueType::I32])
+                        }),
+                        Instruction::I32Const(1),
+                        Instruction::End,
+                        Instruction::Block(Block {
+                            block_type: BlockType::FuncType(1)
+                        }),
+                        Instruction::I32Const(2),
+                        Instruction::End,
+                        Instruction::End,
+                    ],
+                }]),
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unknown_block_type() -> Result<()> {
+        let mut wasm = wat::parse_str("(module (func (block)))")?;
+        let at = wasm
+            .windows(4)
+            .position(|bytes| bytes == [0x02, 0x40, 0x0b, 0x0b])
+            .unwrap();
+        // funcref is a negative s33 but not a block result type
+        wasm[at + 1] = 0x70;
+        assert!(Module::new(&wasm).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unknown_types() -> Result<()> {
+        // a table of an unknown element type 0x37
+        let mut wasm = wat::parse_str("(module (table 1 funcref))")?;
+        let at = wasm.iter().rposition(|byte| *byte == 0x70).unwrap();
+        wasm[at] = 0x37;
+        assert_eq!(wasm.len(), 14);
+        assert!(Module::new(&wasm).is_err());
+
+        let tests = vec![
+            ("(module (global i32 (i32.const 1)))", 0x7f),
+            ("(module (import \"env\" \"g\" (global i64)))", 0x7e),
+            ("(module (func (param i32)))", 0x7f),
+            ("(module (func (local i64)))", 0x7e),
+        ];
+        for (wat, byte) in tests {
+            let mut wasm = wat::parse_str(wat)?;
+            let at = wasm.iter().rposition(|b| *b == byte).unwrap();
+            wasm[at] = 0x37;
+            assert!(Module::new(&wasm).is_err(), "{wat}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unsupported_module() -> Result<()> {
+        let wasm = wat::parse_str("(module (global f32 (f32.const 1)))")?;
+        assert!(Module::new(&wasm).is_err());
+
+        let mut wasm = wat::parse_str("(module (global i32 (i32.const 1)))")?;
+        let at = wasm
+            .windows(3)
+            .position(|bytes| bytes == [0x41, 0x01, 0x0b])
+            .unwrap();
+        wasm[at] = 0xff;
+        assert!(Module::new(&wasm).is_err());
+
+        let wasm = wat::parse_str("(module (func $f) (elem func $f))")?;
+        assert!(Module::new(&wasm).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decode_export_memory() -> Result<()> {
+        let wasm = wat::parse_str("(module (memory (export \"memory\") 1))")?;
+        let module = Module::new(&wasm)?;
+        assert_eq!(
+            module,
+            Module {
+                memory_section: Some(vec![Memory {
+                    limits: Limits { min: 1, max: None }
+                }]),
+                export_section: Some(vec![Export {
+                    name: "memory".into(),
+                    desc: ExportDesc::Memory(0),
+                }]),
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decode_table() -> Result<()> {
+        let wasm = wat::parse_str(
+            "(module (table (export \"table\") 2 4 funcref) (elem (i32.const 1) $f) (func $f))",
+        )?;
+        let module = Module::new(&wasm)?;
+        assert_eq!(
+            module,
+            Module {
+                type_section: Some(vec![FuncType::default()]),
+                function_section: Some(vec![0]),
+                table_section: Some(vec![Table {
+                    elem_type: RefType::FuncRef,
+                    limits: Limits {
+                        min: 2,
+                        max: Some(4)
+                    },
+                }]),
+                element_section: Some(vec![Element {
+                    table_index: 0,
+                    offset: 1,
+                    init: vec![0],
+                }]),
+                export_section: Some(vec![Export {
+                    name: "table".into(),
+                    desc: ExportDesc::Table(0),
+                }]),
+                code_section: Some(vec![Function {
+                    locals: vec![],
+                    code: vec![Instruction::End],
+                }]),
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decode_global() -> Result<()> {
+        let wasm = wat::parse_str(
+            r#"(module
+                (import "env" "base" (global i32))
+                (import "env" "memory" (memory 1 2))
+                (global (export "counter") (mut i32) (global.get 0))
+                (global i64 (i64.const -1)))"#,
+        )?;
+        let module = Module::new(&wasm)?;
+        assert_eq!(
+            module,
+            Module {
+                import_section: Some(vec![
+                    Import {
+                        module: "env".into(),
+                        field: "base".into(),
+                        desc: ImportDesc::Global(GlobalType {
+                            value_type: ValueType::I32,
+                            mutable: false,
+                        }),
+                    },
+                    Import {
+                        module: "env".into(),
+                        field: "memory".into(),
+                        desc: ImportDesc::Memory(Memory {
+                            limits: Limits {
+                                min: 1,
+                                max: Some(2)
+                            },
+                        }),
+                    },
+                ]),
+                global_section: Some(vec![
+                    Global {
+                        global_type: GlobalType {
+                            value_type: ValueType::I32,
+                            mutable: true,
+                        },
+                        init: ConstExpr::GlobalGet(0),
+                    },
+                    Global {
+                        global_type: GlobalType {
+                            value_type: ValueType::I64,
+                            mutable: false,
+                        },
+                        init: ConstExpr::I64Const(-1),
+                    },
+                ]),
+                export_section: Some(vec![Export {
+                    name: "counter".into(),
+                    desc: ExportDesc::Global(1),
+                }]),
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decode_lazily() -> Result<()> {
+        let wasm = wat::parse_file("src/fixtures/fib.wat")?;
+        let mut module = Module::new_lazy(&wasm)?;
+        let code = module.lazy_code_section.take().unwrap();
+        let funcs = (0..code.bodies.len())
+            .map(|idx| code.decode(idx))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut eager = Module::new(&wasm)?;
+        assert_eq!(eager.code_section.take(), Some(funcs));
+        assert_eq!(module, eager);
+        assert!(code.decode(code.bodies.len()).is_err());
+        Ok(())
+    }
 }
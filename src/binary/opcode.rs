@@ -2,15 +2,25 @@ use num_derive::FromPrimitive;
 
 #[derive(Debug, FromPrimitive, PartialEq)]
 pub enum Opcode {
+    Unreachable = 0x00,
+    Block = 0x02,
+    Loop = 0x03,
     If = 0x04,
+    Else = 0x05,
     End = 0x0B,
+    Br = 0x0C,
+    BrIf = 0x0D,
     Return = 0x0F,
     LocalGet = 0x20,
     LocalSet = 0x21,
+    GlobalGet = 0x23,
+    GlobalSet = 0x24,
     I32Store = 0x36,
     I32Const = 0x41,
+    I64Const = 0x42,
     I32LtS = 0x48,
     I32Add = 0x6A,
     I32Sub = 0x6B,
     Call = 0x10,
+    CallIndirect = 0x11,
 }
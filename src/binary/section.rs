@@ -1,3 +1,5 @@
+use std::{ops::Range, sync::Arc};
+
 use super::{instruction::Instruction, types::FunctionLocal};
 use num_derive::FromPrimitive;
 
@@ -7,8 +9,11 @@ pub enum SectionCode {
     Type = 0x01,
     Import = 0x02,
     Function = 0x03,
+    Table = 0x04,
     Memory = 0x05,
+    Global = 0x06,
     Export = 0x07,
+    Element = 0x09,
     Code = 0x0a,
     Data = 0x0b,
 }
@@ -18,3 +23,10 @@ pub struct Function {
     pub locals: Vec<FunctionLocal>,
     pub code: Vec<Instruction>,
 }
+
+// the code section with the function bodies left undecoded, each is decoded when needed
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct LazyCode {
+    pub bytes: Arc<[u8]>,
+    pub bodies: Vec<Range<usize>>,
+}
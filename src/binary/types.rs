@@ -1,23 +1,17 @@
+use num_derive::FromPrimitive;
+
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct FuncType {
     pub params: Vec<ValueType>,
     pub results: Vec<ValueType>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, FromPrimitive)]
 pub enum ValueType {
-    I32, // 0x7F
-    I64, // 0x7E
-}
-
-impl From<u8> for ValueType {
-    fn from(value: u8) -> Self {
-        match value {
-            0x7F => Self::I32,
-            0x7E => Self::I64,
-            _ => panic!("invalid value type: {:X}", value),
-        }
-    }
+    I32 = 0x7F,
+    I64 = 0x7E,
+    F32 = 0x7D,
+    F64 = 0x7C,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -29,6 +23,9 @@ pub struct FunctionLocal {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ExportDesc {
     Func(u32),
+    Table(u32),
+    Memory(u32),
+    Global(u32),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -40,6 +37,9 @@ pub struct Export {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ImportDesc {
     Func(u32),
+    Table(Table),
+    Memory(Memory),
+    Global(GlobalType),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -60,6 +60,44 @@ pub struct Memory {
     pub limits: Limits,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, FromPrimitive)]
+pub enum RefType {
+    FuncRef = 0x70,
+    ExternRef = 0x6F,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    pub elem_type: RefType,
+    pub limits: Limits,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Element {
+    pub table_index: u32,
+    pub offset: u32,
+    pub init: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalType {
+    pub value_type: ValueType,
+    pub mutable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConstExpr {
+    I32Const(i32),
+    I64Const(i64),
+    GlobalGet(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Global {
+    pub global_type: GlobalType,
+    pub init: ConstExpr,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Data {
     pub memory_index: u32,
@@ -76,13 +114,26 @@ pub struct Block {
 pub enum BlockType {
     Void,
     Value(Vec<ValueType>),
+    FuncType(u32),
 }
 
 impl BlockType {
-    pub fn result_count(&self) -> usize {
+    pub fn param_count(&self, func_types: &[FuncType]) -> usize {
+        match self {
+            Self::Void | Self::Value(_) => 0,
+            Self::FuncType(idx) => func_types
+                .get(*idx as usize)
+                .map_or(0, |func_type| func_type.params.len()),
+        }
+    }
+
+    pub fn result_count(&self, func_types: &[FuncType]) -> usize {
         match self {
             Self::Void => 0,
             Self::Value(value_types) => value_types.len(),
+            Self::FuncType(idx) => func_types
+                .get(*idx as usize)
+                .map_or(0, |func_type| func_type.results.len()),
         }
     }
 }
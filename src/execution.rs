@@ -1,5 +1,21 @@
+pub mod artifact;
+pub mod caller;
+pub mod compile;
+pub mod config;
+pub mod func;
+#[cfg(feature = "jit")]
+pub mod guard;
 pub mod import;
+pub mod instance;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod linker;
+pub mod memory;
+pub mod module;
+pub mod pool;
+pub mod resumable;
 pub mod runtime;
+pub mod snapshot;
 pub mod store;
 pub mod value;
 pub mod wasi;
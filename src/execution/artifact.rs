@@ -0,0 +1,760 @@
+use super::{
+    compile::Op,
+    config::Config,
+    module::{Module, ModuleInner},
+    snapshot::{decode_max, write_max, write_u32},
+    store::Func,
+};
+use crate::binary::types::{
+    ConstExpr, Data, Element, Export, ExportDesc, FuncType, Global, GlobalType, Import, ImportDesc,
+    Limits, Memory, RefType, Table, ValueType,
+};
+use anyhow::{bail, Result};
+use nom::{
+    bytes::complete::{tag, take},
+    error::{Error, ErrorKind},
+    multi::count,
+    number::complete::{le_i32, le_i64, le_u32, le_u64, le_u8},
+    IResult,
+};
+
+const MAGIC: &[u8] = b"\0twm";
+const VERSION: u32 = 1;
+// the lowered code is only understood by the runtime that wrote it
+const RUNTIME_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+// 64 bit FNV-1a, enough to tell artifacts apart, not to defend against tampering
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+impl Module {
+    // the validated and lowered module, native code is generated again when loading and
+    // functions not called yet are lowered now
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let payload = encode_module(&self.inner)?;
+        let mut buf = MAGIC.to_vec();
+        buf.extend(VERSION.to_le_bytes());
+        write_str(&mut buf, RUNTIME_VERSION);
+        buf.extend(fnv1a(&payload).to_le_bytes());
+        buf.extend(payload);
+        Ok(buf)
+    }
+
+    pub fn deserialize(artifact: &[u8]) -> Result<Self> {
+        Self::deserialize_with_config(artifact, &Config::default())
+    }
+
+    pub fn deserialize_with_config(artifact: &[u8], config: &Config) -> Result<Self> {
+        let (inner, funcs) = match decode_artifact(artifact) {
+            Ok(([], decoded)) => decoded?,
+            Ok(_) => bail!("failed to parse artifact: trailing bytes"),
+            Err(e) => bail!("failed to parse artifact: {}", e),
+        };
+        Self::finish(inner, funcs, config)
+    }
+
+    // also rejects an artifact of another version of the wasm binary
+    pub fn deserialize_for(wasm: impl AsRef<[u8]>, artifact: &[u8]) -> Result<Self> {
+        let module = Self::deserialize(artifact)?;
+        if module.inner.source_hash != Some(fnv1a(wasm.as_ref())) {
+            bail!("artifact was not compiled from this wasm binary");
+        }
+        Ok(module)
+    }
+}
+
+fn encode_module(module: &ModuleInner) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    match module.source_hash {
+        None => buf.push(0),
+        Some(hash) => {
+            buf.push(1);
+            buf.extend(hash.to_le_bytes());
+        }
+    }
+
+    write_u32(&mut buf, module.func_types.len());
+    for func_type in &module.func_types {
+        write_func_type(&mut buf, func_type);
+    }
+
+    write_u32(&mut buf, module.imports.len());
+    for import in &module.imports {
+        write_str(&mut buf, &import.module);
+        write_str(&mut buf, &import.field);
+        match &import.desc {
+            ImportDesc::Func(idx) => {
+                buf.push(0);
+                buf.extend(idx.to_le_bytes());
+            }
+            ImportDesc::Table(table) => {
+                buf.push(1);
+                write_table(&mut buf, table);
+            }
+            ImportDesc::Memory(memory) => {
+                buf.push(2);
+                write_limits(&mut buf, &memory.limits);
+            }
+            ImportDesc::Global(global_type) => {
+                buf.push(3);
+                write_global_type(&mut buf, global_type);
+            }
+        }
+    }
+
+    write_u32(&mut buf, module.funcs.len());
+    for (idx, (func_type, _)) in module.funcs.iter().enumerate() {
+        let func = module.code(idx)?;
+        write_func_type(&mut buf, func_type);
+        write_types(&mut buf, &func.locals);
+        write_u32(&mut buf, func.ops.len());
+        for op in &func.ops {
+            write_op(&mut buf, op);
+        }
+        for cost in &func.costs {
+            buf.extend(cost.to_le_bytes());
+        }
+        write_u32(&mut buf, func.reg_count);
+    }
+
+    write_u32(&mut buf, module.tables.len());
+    for table in &module.tables {
+        write_table(&mut buf, table);
+    }
+
+    write_u32(&mut buf, module.memories.len());
+    for memory in &module.memories {
+        write_limits(&mut buf, &memory.limits);
+    }
+
+    write_u32(&mut buf, module.globals.len());
+    for global in &module.globals {
+        write_global_type(&mut buf, &global.global_type);
+        match global.init {
+            ConstExpr::I32Const(value) => {
+                buf.push(0);
+                buf.extend(value.to_le_bytes());
+            }
+            ConstExpr::I64Const(value) => {
+                buf.push(1);
+                buf.extend(value.to_le_bytes());
+            }
+            ConstExpr::GlobalGet(idx) => {
+                buf.push(2);
+                buf.extend(idx.to_le_bytes());
+            }
+        }
+    }
+
+    write_u32(&mut buf, module.exports.len());
+    for export in &module.exports {
+        write_str(&mut buf, &export.name);
+        let (kind, idx) = match export.desc {
+            ExportDesc::Func(idx) => (0, idx),
+            ExportDesc::Table(idx) => (1, idx),
+            ExportDesc::Memory(idx) => (2, idx),
+            ExportDesc::Global(idx) => (3, idx),
+        };
+        buf.push(kind);
+        buf.extend(idx.to_le_bytes());
+    }
+
+    write_u32(&mut buf, module.elements.len());
+    for element in &module.elements {
+        buf.extend(element.table_index.to_le_bytes());
+        buf.extend(element.offset.to_le_bytes());
+        write_u32(&mut buf, element.init.len());
+        for idx in &element.init {
+            buf.extend(idx.to_le_bytes());
+        }
+    }
+
+    write_u32(&mut buf, module.datas.len());
+    for data in &module.datas {
+        buf.extend(data.memory_index.to_le_bytes());
+        buf.extend(data.offset.to_le_bytes());
+        write_u32(&mut buf, data.init.len());
+        buf.extend(&data.init);
+    }
+    Ok(buf)
+}
+
+pub(super) fn write_str(buf: &mut Vec<u8>, value: &str) {
+    write_u32(buf, value.len());
+    buf.extend(value.as_bytes());
+}
+
+pub(super) fn write_types(buf: &mut Vec<u8>, types: &[ValueType]) {
+    write_u32(buf, types.len());
+    for value_type in types {
+        buf.push(match value_type {
+            ValueType::I32 => 0x7F,
+            ValueType::I64 => 0x7E,
+            ValueType::F32 => 0x7D,
+            ValueType::F64 => 0x7C,
+        });
+    }
+}
+
+fn write_func_type(buf: &mut Vec<u8>, func_type: &FuncType) {
+    write_types(buf, &func_type.params);
+    write_types(buf, &func_type.results);
+}
+
+fn write_limits(buf: &mut Vec<u8>, limits: &Limits) {
+    buf.extend(limits.min.to_le_bytes());
+    write_max(buf, limits.max);
+}
+
+fn write_table(buf: &mut Vec<u8>, table: &Table) {
+    buf.push(match table.elem_type {
+        RefType::FuncRef => 0x70,
+        RefType::ExternRef => 0x6F,
+    });
+    write_limits(buf, &table.limits);
+}
+
+fn write_global_type(buf: &mut Vec<u8>, global_type: &GlobalType) {
+    write_types(buf, std::slice::from_ref(&global_type.value_type));
+    buf.push(global_type.mutable.into());
+}
+
+// an op is its tag followed by its fields, a constant takes 8 bytes and the others 4
+fn write_op(buf: &mut Vec<u8>, op: &Op) {
+    let (tag, fields): (u8, &[u32]) = match *op {
+        Op::Unreachable => (0, &[]),
+        Op::Nop => (1, &[]),
+        Op::Copy { dst, src } => (2, &[dst, src]),
+        Op::Const { dst, value } => {
+            buf.push(3);
+            buf.extend(dst.to_le_bytes());
+            buf.extend(value.to_le_bytes());
+            return;
+        }
+        Op::GlobalGet { dst, idx } => (4, &[dst, idx]),
+        Op::GlobalSet { idx, src } => (5, &[idx, src]),
+        Op::I32Store {
+            addr,
+            value,
+            offset,
+        } => (6, &[addr, value, offset]),
+        Op::I32Add { dst, lhs, rhs } => (7, &[dst, lhs, rhs]),
+        Op::I32Sub { dst, lhs, rhs } => (8, &[dst, lhs, rhs]),
+        Op::I32Lts { dst, lhs, rhs } => (9, &[dst, lhs, rhs]),
+        Op::Jump { target } => (10, &[target]),
+        Op::JumpIfZero { cond, target } => (11, &[cond, target]),
+        Op::JumpIfNonZero { cond, target } => (12, &[cond, target]),
+        Op::Call { func, args } => (13, &[func, args]),
+        Op::CallIndirect {
+            type_idx,
+            table_idx,
+            elem,
+            args,
+        } => (14, &[type_idx, table_idx, elem, args]),
+        Op::TakeResults { dst, count } => (15, &[dst, count]),
+        Op::Return { src } => (16, &[src]),
+        Op::I32AddImm { dst, lhs, imm } => (17, &[dst, lhs, imm as u32]),
+        Op::I32SubImm { dst, lhs, imm } => (18, &[dst, lhs, imm as u32]),
+        Op::I32LtsImm { dst, lhs, imm } => (19, &[dst, lhs, imm as u32]),
+        Op::JumpIfLts { lhs, rhs, target } => (20, &[lhs, rhs, target]),
+        Op::JumpIfNotLts { lhs, rhs, target } => (21, &[lhs, rhs, target]),
+        Op::JumpIfLtsImm { lhs, imm, target } => (22, &[lhs, imm as u32, target]),
+        Op::JumpIfNotLtsImm { lhs, imm, target } => (23, &[lhs, imm as u32, target]),
+    };
+    buf.push(tag);
+    for field in fields {
+        buf.extend(field.to_le_bytes());
+    }
+}
+
+type Lowered = (ModuleInner, Vec<(FuncType, Func)>);
+type Decoded = Result<Lowered>;
+
+// the checks that make an artifact unusable are reported as errors of their own
+fn decode_artifact(input: &[u8]) -> IResult<&[u8], Decoded> {
+    let (input, _) = tag(MAGIC)(input)?;
+    let (input, version) = le_u32(input)?;
+    if version != VERSION {
+        let e = anyhow::anyhow!("unsupported artifact version {}", version);
+        return Ok((&[], Err(e)));
+    }
+    let (input, runtime_version) = decode_str(input)?;
+    if runtime_version != RUNTIME_VERSION {
+        let e = anyhow::anyhow!(
+            "artifact was written by runtime version {}, expected {}",
+            runtime_version,
+            RUNTIME_VERSION
+        );
+        return Ok((&[], Err(e)));
+    }
+    let (payload, hash) = le_u64(input)?;
+    if hash != fnv1a(payload) {
+        return Ok((&[], Err(anyhow::anyhow!("artifact is corrupted"))));
+    }
+    let (input, decoded) = decode_module(payload)?;
+    Ok((input, Ok(decoded)))
+}
+
+fn decode_module(input: &[u8]) -> IResult<&[u8], Lowered> {
+    let (input, has_hash) = le_u8(input)?;
+    let (input, source_hash) = match has_hash {
+        0 => (input, None),
+        _ => {
+            let (input, hash) = le_u64(input)?;
+            (input, Some(hash))
+        }
+    };
+    let (input, func_types) = decode_vec(input, decode_func_type)?;
+    let (input, imports) = decode_vec(input, decode_import)?;
+    let (input, funcs) = decode_vec(input, decode_func)?;
+    let (input, tables) = decode_vec(input, decode_table)?;
+    let (input, memories) = decode_vec(input, |input| {
+        let (input, limits) = decode_limits(input)?;
+        Ok((input, Memory { limits }))
+    })?;
+    let (input, globals) = decode_vec(input, decode_global)?;
+    let (input, exports) = decode_vec(input, decode_export)?;
+    let (input, elements) = decode_vec(input, |input| {
+        let (input, table_index) = le_u32(input)?;
+        let (input, offset) = le_u32(input)?;
+        let (input, init) = decode_vec(input, le_u32)?;
+        let element = Element {
+            table_index,
+            offset,
+            init,
+        };
+        Ok((input, element))
+    })?;
+    let (input, datas) = decode_vec(input, |input| {
+        let (input, memory_index) = le_u32(input)?;
+        let (input, offset) = le_u32(input)?;
+        let (input, len) = le_u32(input)?;
+        let (input, init) = take(len)(input)?;
+        let data = Data {
+            memory_index,
+            offset,
+            init: init.to_vec(),
+        };
+        Ok((input, data))
+    })?;
+
+    let inner = ModuleInner {
+        func_types,
+        imports,
+        funcs: vec![],
+        tables,
+        memories,
+        globals,
+        exports,
+        elements,
+        datas,
+        source_hash,
+        // set by Module::finish from the config the artifact is loaded with
+        config: Config::default(),
+        lazy: None,
+        #[cfg(target_os = "linux")]
+        memory_image: Default::default(),
+    };
+    Ok((input, (inner, funcs)))
+}
+
+fn fail<T>(input: &[u8]) -> IResult<&[u8], T> {
+    Err(nom::Err::Failure(Error::new(input, ErrorKind::Tag)))
+}
+
+pub(super) fn decode_vec<'a, T>(
+    input: &'a [u8],
+    decode: impl FnMut(&'a [u8]) -> IResult<&'a [u8], T>,
+) -> IResult<&'a [u8], Vec<T>> {
+    let (input, len) = le_u32(input)?;
+    count(decode, len as usize)(input)
+}
+
+pub(super) fn decode_str(input: &[u8]) -> IResult<&[u8], String> {
+    let (rest, len) = le_u32(input)?;
+    let (rest, bytes) = take(len)(rest)?;
+    match std::str::from_utf8(bytes) {
+        Ok(value) => Ok((rest, value.to_string())),
+        Err(_) => fail(input),
+    }
+}
+
+pub(super) fn decode_value_type(input: &[u8]) -> IResult<&[u8], ValueType> {
+    let (rest, value_type) = le_u8(input)?;
+    match value_type {
+        0x7F => Ok((rest, ValueType::I32)),
+        0x7E => Ok((rest, ValueType::I64)),
+        0x7D => Ok((rest, ValueType::F32)),
+        0x7C => Ok((rest, ValueType::F64)),
+        _ => fail(input),
+    }
+}
+
+fn decode_func_type(input: &[u8]) -> IResult<&[u8], FuncType> {
+    let (input, params) = decode_vec(input, decode_value_type)?;
+    let (input, results) = decode_vec(input, decode_value_type)?;
+    Ok((input, FuncType { params, results }))
+}
+
+fn decode_limits(input: &[u8]) -> IResult<&[u8], Limits> {
+    let (input, min) = le_u32(input)?;
+    let (input, max) = decode_max(input)?;
+    Ok((input, Limits { min, max }))
+}
+
+fn decode_table(input: &[u8]) -> IResult<&[u8], Table> {
+    let (rest, elem_type) = le_u8(input)?;
+    let elem_type = match elem_type {
+        0x70 => RefType::FuncRef,
+        0x6F => RefType::ExternRef,
+        _ => return fail(input),
+    };
+    let (rest, limits) = decode_limits(rest)?;
+    Ok((rest, Table { elem_type, limits }))
+}
+
+fn decode_global_type(input: &[u8]) -> IResult<&[u8], GlobalType> {
+    let (input, _) = tag(&1u32.to_le_bytes()[..])(input)?;
+    let (input, value_type) = decode_value_type(input)?;
+    let (input, mutable) = le_u8(input)?;
+    let global_type = GlobalType {
+        value_type,
+        mutable: mutable != 0,
+    };
+    Ok((input, global_type))
+}
+
+fn decode_import(input: &[u8]) -> IResult<&[u8], Import> {
+    let (input, module) = decode_str(input)?;
+    let (input, field) = decode_str(input)?;
+    let (rest, kind) = le_u8(input)?;
+    let (rest, desc) = match kind {
+        0 => le_u32(rest).map(|(rest, idx)| (rest, ImportDesc::Func(idx)))?,
+        1 => decode_table(rest).map(|(rest, table)| (rest, ImportDesc::Table(table)))?,
+        2 => {
+            let (rest, limits) = decode_limits(rest)?;
+            (rest, ImportDesc::Memory(Memory { limits }))
+        }
+        3 => decode_global_type(rest)
+            .map(|(rest, global_type)| (rest, ImportDesc::Global(global_type)))?,
+        _ => return fail(input),
+    };
+    let import = Import {
+        module,
+        field,
+        desc,
+    };
+    Ok((rest, import))
+}
+
+fn decode_global(input: &[u8]) -> IResult<&[u8], Global> {
+    let (input, global_type) = decode_global_type(input)?;
+    let (rest, kind) = le_u8(input)?;
+    let (rest, init) = match kind {
+        0 => le_i32(rest).map(|(rest, value)| (rest, ConstExpr::I32Const(value)))?,
+        1 => le_i64(rest).map(|(rest, value)| (rest, ConstExpr::I64Const(value)))?,
+        2 => le_u32(rest).map(|(rest, idx)| (rest, ConstExpr::GlobalGet(idx)))?,
+        _ => return fail(input),
+    };
+    Ok((rest, Global { global_type, init }))
+}
+
+fn decode_export(input: &[u8]) -> IResult<&[u8], Export> {
+    let (input, name) = decode_str(input)?;
+    let (rest, kind) = le_u8(input)?;
+    let (rest, idx) = le_u32(rest)?;
+    let desc = match kind {
+        0 => ExportDesc::Func(idx),
+        1 => ExportDesc::Table(idx),
+        2 => ExportDesc::Memory(idx),
+        3 => ExportDesc::Global(idx),
+        _ => return fail(input),
+    };
+    Ok((rest, Export { name, desc }))
+}
+
+// the instructions are not stored, a loaded function only runs its ops
+fn decode_func(input: &[u8]) -> IResult<&[u8], (FuncType, Func)> {
+    let (input, func_type) = decode_func_type(input)?;
+    let (input, locals) = decode_vec(input, decode_value_type)?;
+    let (input, len) = le_u32(input)?;
+    let (input, ops) = count(decode_op, len as usize)(input)?;
+    let (input, costs) = count(le_u32, len as usize)(input)?;
+    let (input, reg_count) = le_u32(input)?;
+    let func = Func {
+        locals,
+        ops,
+        costs,
+        reg_count: reg_count as usize,
+        ..Default::default()
+    };
+    Ok((input, (func_type, func)))
+}
+
+fn fields<const N: usize>(mut input: &[u8]) -> IResult<&[u8], [u32; N]> {
+    let mut fields = [0; N];
+    for field in fields.iter_mut() {
+        (input, *field) = le_u32(input)?;
+    }
+    Ok((input, fields))
+}
+
+fn decode_op(input: &[u8]) -> IResult<&[u8], Op> {
+    let (rest, tag) = le_u8(input)?;
+    Ok(match tag {
+        0 => (rest, Op::Unreachable),
+        1 => (rest, Op::Nop),
+        2 => fields(rest).map(|(rest, [dst, src])| (rest, Op::Copy { dst, src }))?,
+        3 => {
+            let (rest, dst) = le_u32(rest)?;
+            let (rest, value) = le_u64(rest)?;
+            (rest, Op::Const { dst, value })
+        }
+        4 => fields(rest).map(|(rest, [dst, idx])| (rest, Op::GlobalGet { dst, idx }))?,
+        5 => fields(rest).map(|(rest, [idx, src])| (rest, Op::GlobalSet { idx, src }))?,
+        6 => fields(rest).map(|(rest, [addr, value, offset])| {
+            let op = Op::I32Store {
+                addr,
+                value,
+                offset,
+            };
+            (rest, op)
+        })?,
+        7 => fields(rest).map(|(rest, [dst, lhs, rhs])| (rest, Op::I32Add { dst, lhs, rhs }))?,
+        8 => fields(rest).map(|(rest, [dst, lhs, rhs])| (rest, Op::I32Sub { dst, lhs, rhs }))?,
+        9 => fields(rest).map(|(rest, [dst, lhs, rhs])| (rest, Op::I32Lts { dst, lhs, rhs }))?,
+        10 => fields(rest).map(|(rest, [target])| (rest, Op::Jump { target }))?,
+        11 => fields(rest).map(|(rest, [cond, target])| (rest, Op::JumpIfZero { cond, target }))?,
+        12 => {
+            fields(rest).map(|(rest, [cond, target])| (rest, Op::JumpIfNonZero { cond, target }))?
+        }
+        13 => fields(rest).map(|(rest, [func, args])| (rest, Op::Call { func, args }))?,
+        14 => fields(rest).map(|(rest, [type_idx, table_idx, elem, args])| {
+            let op = Op::CallIndirect {
+                type_idx,
+                table_idx,
+                elem,
+                args,
+            };
+            (rest, op)
+        })?,
+        15 => fields(rest).map(|(rest, [dst, count])| (rest, Op::TakeResults { dst, count }))?,
+        16 => fields(rest).map(|(rest, [src])| (rest, Op::Return { src }))?,
+        17 => fields(rest).map(|(rest, [dst, lhs, imm])| {
+            let imm = imm as i32;
+            (rest, Op::I32AddImm { dst, lhs, imm })
+        })?,
+        18 => fields(rest).map(|(rest, [dst, lhs, imm])| {
+            let imm = imm as i32;
+            (rest, Op::I32SubImm { dst, lhs, imm })
+        })?,
+        19 => fields(rest).map(|(rest, [dst, lhs, imm])| {
+            let imm = imm as i32;
+            (rest, Op::I32LtsImm { dst, lhs, imm })
+        })?,
+        20 => fields(rest)
+            .map(|(rest, [lhs, rhs, target])| (rest, Op::JumpIfLts { lhs, rhs, target }))?,
+        21 => fields(rest)
+            .map(|(rest, [lhs, rhs, target])| (rest, Op::JumpIfNotLts { lhs, rhs, target }))?,
+        22 => fields(rest).map(|(rest, [lhs, imm, target])| {
+            let imm = imm as i32;
+            (rest, Op::JumpIfLtsImm { lhs, imm, target })
+        })?,
+        23 => fields(rest).map(|(rest, [lhs, imm, target])| {
+            let imm = imm as i32;
+            (rest, Op::JumpIfNotLtsImm { lhs, imm, target })
+        })?,
+        _ => return fail(input),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_artifact, fnv1a};
+    use crate::execution::{
+        compile::Op, config::Config, linker::Linker, module::Module, runtime::Runtime, store::Func,
+        value::Value,
+    };
+    use anyhow::Result;
+
+    #[test]
+    fn serialize_and_deserialize() -> Result<()> {
+        let wasm = wat::parse_file("src/fixtures/link_provider.wat")?;
+        let artifact = Module::new(&wasm)?.serialize()?;
+        let module = Module::deserialize_for(&wasm, &artifact)?;
+        assert_eq!(module.serialize()?, artifact);
+
+        let mut runtime = Runtime::new(());
+        Linker::new().instantiate(&mut runtime, &module)?;
+        assert_eq!(runtime.call("incr", vec![])?, vec![Value::I32(1)]);
+
+        let wasm = wat::parse_file("src/fixtures/fib.wat")?;
+        let module = Module::deserialize(&Module::new(&wasm)?.serialize()?)?;
+        let mut runtime = Runtime::new(());
+        Linker::new().instantiate(&mut runtime, &module)?;
+        assert_eq!(
+            runtime.call("fib", vec![Value::I32(10)])?,
+            vec![Value::I32(89)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_stale_artifact() -> Result<()> {
+        let wasm = wat::parse_file("src/fixtures/fib.wat")?;
+        let artifact = Module::new(&wasm)?.serialize()?;
+
+        let other = wat::parse_file("src/fixtures/link_provider.wat")?;
+        let result = Module::deserialize_for(other, &artifact);
+        assert_eq!(
+            result.err().map(|e| e.to_string()),
+            Some("artifact was not compiled from this wasm binary".to_string())
+        );
+
+        // the runtime version follows the magic and the format version
+        let mut stale = artifact.clone();
+        stale[12] ^= 1;
+        let result = Module::deserialize(&stale).err().unwrap();
+        assert!(result.to_string().starts_with("artifact was written by"));
+
+        let mut corrupted = artifact.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let result = Module::deserialize(&corrupted).err().unwrap();
+        assert_eq!(result.to_string(), "artifact is corrupted");
+
+        let mut version = artifact.clone();
+        version[4] = 2;
+        let result = Module::deserialize(&version).err().unwrap();
+        assert_eq!(result.to_string(), "unsupported artifact version 2");
+
+        assert!(Module::deserialize(&artifact[..artifact.len() - 1]).is_err());
+        assert!(Module::deserialize(&wasm).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_ops() -> Result<()> {
+        let verify = |file: &str, edit: &dyn Fn(&mut Func)| -> Result<String> {
+            let wasm = wat::parse_file(format!("src/fixtures/{file}"))?;
+            let artifact = Module::new(&wasm)?.serialize()?;
+            let Ok(([], Ok((inner, mut funcs)))) = decode_artifact(&artifact) else {
+                panic!("failed to decode artifact");
+            };
+            edit(&mut funcs.last_mut().expect("func").1);
+            let result = Module::finish(inner, funcs, &Config::new());
+            Ok(result.err().map(|e| e.to_string()).unwrap_or_default())
+        };
+        fn position(func: &Func, find: fn(&Op) -> bool) -> usize {
+            func.ops.iter().position(find).expect("op")
+        }
+
+        type Edit = Box<dyn Fn(&mut Func)>;
+        let tests: Vec<(&str, Edit, &str)> = vec![
+            (
+                "fib.wat",
+                Box::new(|func| func.reg_count = 0),
+                "0 registers cannot hold 1 locals",
+            ),
+            (
+                "fib.wat",
+                Box::new(|func| func.costs.truncate(10)),
+                "10 costs for 11 ops",
+            ),
+            (
+                "fib.wat",
+                Box::new(|func| {
+                    func.ops.truncate(10);
+                    func.costs.truncate(10);
+                }),
+                "function does not end with a return",
+            ),
+            (
+                "fib.wat",
+                Box::new(|func| func.ops[0] = Op::Const { dst: 99, value: 0 }),
+                "register 99 out of range",
+            ),
+            (
+                "fib.wat",
+                Box::new(|func| {
+                    let idx = position(func, |op| matches!(op, Op::Return { .. }));
+                    func.ops[idx] = Op::Return { src: 4 };
+                }),
+                "register 4 out of range",
+            ),
+            (
+                "fib.wat",
+                Box::new(|func| func.ops[0] = Op::Jump { target: 11 }),
+                "jump target 11 out of range",
+            ),
+            (
+                "fib.wat",
+                Box::new(|func| {
+                    let idx = position(func, |op| matches!(op, Op::TakeResults { .. }));
+                    func.ops[0] = Op::Jump { target: idx as u32 };
+                }),
+                "jump target 5 takes results",
+            ),
+            (
+                "fib.wat",
+                Box::new(|func| {
+                    let idx = position(func, |op| matches!(op, Op::Call { .. }));
+                    func.ops[idx] = Op::Call { func: 1, args: 2 };
+                }),
+                "unknown function 1",
+            ),
+            (
+                "fib.wat",
+                Box::new(|func| {
+                    let idx = position(func, |op| matches!(op, Op::TakeResults { .. }));
+                    func.ops[idx] = Op::TakeResults { dst: 2, count: 100 };
+                }),
+                "taking 100 results of a call returning 1",
+            ),
+            (
+                "fib.wat",
+                Box::new(|func| {
+                    let idx = position(func, |op| matches!(op, Op::TakeResults { .. }));
+                    func.ops[idx] = Op::Nop;
+                }),
+                "results of a call are not taken",
+            ),
+            (
+                "link_provider.wat",
+                Box::new(|func| {
+                    let idx = position(func, |op| matches!(op, Op::GlobalSet { .. }));
+                    func.ops[idx] = Op::GlobalSet { idx: 1, src: 0 };
+                }),
+                "global is immutable",
+            ),
+            (
+                "link_provider.wat",
+                Box::new(|func| {
+                    let idx = position(func, |op| matches!(op, Op::GlobalGet { .. }));
+                    func.ops[idx] = Op::GlobalGet { dst: 0, idx: 7 };
+                }),
+                "unknown global 7",
+            ),
+        ];
+        for (file, edit, want) in tests {
+            let idx = if file == "fib.wat" { 0 } else { 1 };
+            assert_eq!(
+                verify(file, &*edit)?,
+                format!("failed to verify function {idx}: {want}"),
+                "{want}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn hash() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
@@ -0,0 +1,206 @@
+use std::ops::{Deref, DerefMut};
+
+use super::{
+    func::{Func, TypedFunc, WasmParams, WasmResults},
+    instance::Instance,
+    runtime::Runtime,
+    value::Value,
+};
+use anyhow::{anyhow, bail, Result};
+
+pub struct Caller<'a, T> {
+    runtime: &'a mut Runtime<T>,
+    instance: Option<Instance>,
+}
+
+impl<'a, T> Caller<'a, T> {
+    pub(crate) fn new(runtime: &'a mut Runtime<T>, instance: Option<Instance>) -> Self {
+        Self { runtime, instance }
+    }
+
+    // exports are looked up in the instance of the calling wasm function
+    fn instance(&self) -> Result<Instance> {
+        match self.instance {
+            Some(instance) => Ok(instance),
+            None => self.runtime.instance(),
+        }
+    }
+
+    pub fn get_export(&self, name: impl AsRef<str>) -> Option<Extern> {
+        self.instance().ok()?.get_export(self.runtime, name)
+    }
+
+    pub fn get_func(&self, name: impl AsRef<str>) -> Result<Func> {
+        self.instance()?.get_func(self.runtime, name)
+    }
+
+    pub fn get_table(&self, name: impl AsRef<str>) -> Result<Table> {
+        self.instance()?.get_table(self.runtime, name)
+    }
+
+    pub fn get_memory(&self, name: impl AsRef<str>) -> Result<Memory> {
+        self.instance()?.get_memory(self.runtime, name)
+    }
+
+    pub fn get_global(&self, name: impl AsRef<str>) -> Result<Global> {
+        self.instance()?.get_global(self.runtime, name)
+    }
+
+    pub fn get_typed_func<Params, Results>(
+        &self,
+        name: impl AsRef<str>,
+    ) -> Result<TypedFunc<Params, Results>>
+    where
+        Params: WasmParams,
+        Results: WasmResults,
+    {
+        self.instance()?.get_typed_func(self.runtime, name)
+    }
+}
+
+impl<T> Deref for Caller<'_, T> {
+    type Target = Runtime<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.runtime
+    }
+}
+
+impl<T> DerefMut for Caller<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.runtime
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extern {
+    Func(Func),
+    Table(Table),
+    Memory(Memory),
+    Global(Global),
+}
+
+impl Extern {
+    pub fn into_func(self) -> Option<Func> {
+        match self {
+            Extern::Func(func) => Some(func),
+            _ => None,
+        }
+    }
+
+    pub fn into_table(self) -> Option<Table> {
+        match self {
+            Extern::Table(table) => Some(table),
+            _ => None,
+        }
+    }
+
+    pub fn into_memory(self) -> Option<Memory> {
+        match self {
+            Extern::Memory(memory) => Some(memory),
+            _ => None,
+        }
+    }
+
+    pub fn into_global(self) -> Option<Global> {
+        match self {
+            Extern::Global(global) => Some(global),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Table {
+    pub(crate) idx: usize,
+}
+
+impl Table {
+    pub fn size<T>(&self, runtime: &Runtime<T>) -> u32 {
+        runtime.store.tables[self.idx].elem.len() as u32
+    }
+
+    pub fn get<T>(&self, runtime: &Runtime<T>, idx: u32) -> Option<Func> {
+        let elem = runtime.store.tables[self.idx].elem.get(idx as usize)?;
+        elem.map(|idx| Func {
+            store: runtime.store.id,
+            idx,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Memory {
+    pub(crate) idx: usize,
+}
+
+impl Memory {
+    pub fn data<'a, T>(&self, runtime: &'a Runtime<T>) -> &'a [u8] {
+        &runtime.store.memories[self.idx].data
+    }
+
+    pub fn data_mut<'a, T>(&self, runtime: &'a mut Runtime<T>) -> &'a mut [u8] {
+        &mut runtime.store.memories[self.idx].data
+    }
+
+    // in pages
+    pub fn size<T>(&self, runtime: &Runtime<T>) -> u32 {
+        runtime.store.memories[self.idx].size()
+    }
+
+    // returns the previous size in pages, the new pages are zeroed
+    pub fn grow<T>(&self, runtime: &mut Runtime<T>, delta: u32) -> Result<u32> {
+        runtime.store.memories[self.idx].grow(delta)
+    }
+
+    pub fn read<T>(&self, runtime: &Runtime<T>, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let data = self.data(runtime);
+        let end = offset
+            .checked_add(buf.len())
+            .ok_or(anyhow!("out of bounds memory access"))?;
+        let Some(src) = data.get(offset..end) else {
+            bail!("out of bounds memory access");
+        };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    pub fn write<T>(&self, runtime: &mut Runtime<T>, offset: usize, buf: &[u8]) -> Result<()> {
+        let data = self.data_mut(runtime);
+        let end = offset
+            .checked_add(buf.len())
+            .ok_or(anyhow!("out of bounds memory access"))?;
+        let Some(dst) = data.get_mut(offset..end) else {
+            bail!("out of bounds memory access");
+        };
+        dst.copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Global {
+    pub(crate) idx: usize,
+}
+
+impl Global {
+    pub fn get<T>(&self, runtime: &Runtime<T>) -> Value {
+        runtime.store.globals[self.idx].value
+    }
+
+    pub fn set<T>(&self, runtime: &mut Runtime<T>, value: Value) -> Result<()> {
+        let global = &mut runtime.store.globals[self.idx];
+        if !global.mutable {
+            bail!("cannot set immutable global");
+        }
+        if global.value.value_type() != value.value_type() {
+            bail!(
+                "type mismatch for global: expected {:?}, got {:?}",
+                global.value.value_type(),
+                value.value_type()
+            );
+        }
+        global.value = value;
+        Ok(())
+    }
+}
@@ -0,0 +1,1082 @@
+use super::{config::Config, store::Func, value::Value};
+use crate::binary::{
+    instruction::Instruction,
+    types::{Block, BlockType, FuncType, GlobalType, ValueType},
+};
+use anyhow::{anyhow, bail, Result};
+
+// registers are the function's locals followed by one slot per operand stack height,
+// they hold untyped bits because the types are checked here
+pub type Reg = u32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Unreachable,
+    // charges the fuel of instructions that left no op behind
+    Nop,
+    Copy {
+        dst: Reg,
+        src: Reg,
+    },
+    Const {
+        dst: Reg,
+        value: u64,
+    },
+    GlobalGet {
+        dst: Reg,
+        idx: u32,
+    },
+    GlobalSet {
+        idx: u32,
+        src: Reg,
+    },
+    I32Store {
+        addr: Reg,
+        value: Reg,
+        offset: u32,
+    },
+    I32Add {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    I32Sub {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    I32Lts {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Jump {
+        target: u32,
+    },
+    JumpIfZero {
+        cond: Reg,
+        target: u32,
+    },
+    JumpIfNonZero {
+        cond: Reg,
+        target: u32,
+    },
+    // the arguments start at args, the results are left on the value stack
+    Call {
+        func: u32,
+        args: Reg,
+    },
+    CallIndirect {
+        type_idx: u32,
+        table_idx: u32,
+        elem: Reg,
+        args: Reg,
+    },
+    // moves the results of a call from the value stack into registers
+    TakeResults {
+        dst: Reg,
+        count: u32,
+    },
+    Return {
+        src: Reg,
+    },
+    // fused from a constant or a comparison and the op using it
+    I32AddImm {
+        dst: Reg,
+        lhs: Reg,
+        imm: i32,
+    },
+    I32SubImm {
+        dst: Reg,
+        lhs: Reg,
+        imm: i32,
+    },
+    I32LtsImm {
+        dst: Reg,
+        lhs: Reg,
+        imm: i32,
+    },
+    JumpIfLts {
+        lhs: Reg,
+        rhs: Reg,
+        target: u32,
+    },
+    JumpIfNotLts {
+        lhs: Reg,
+        rhs: Reg,
+        target: u32,
+    },
+    JumpIfLtsImm {
+        lhs: Reg,
+        imm: i32,
+        target: u32,
+    },
+    JumpIfNotLtsImm {
+        lhs: Reg,
+        imm: i32,
+        target: u32,
+    },
+}
+
+impl Op {
+    // the register written by an op with a single result
+    fn dst_mut(&mut self) -> Option<&mut Reg> {
+        match self {
+            Op::Copy { dst, .. }
+            | Op::Const { dst, .. }
+            | Op::GlobalGet { dst, .. }
+            | Op::I32Add { dst, .. }
+            | Op::I32Sub { dst, .. }
+            | Op::I32Lts { dst, .. }
+            | Op::TakeResults { dst, count: 1 } => Some(dst),
+            _ => None,
+        }
+    }
+
+    fn target_mut(&mut self) -> Option<&mut u32> {
+        match self {
+            Op::Jump { target }
+            | Op::JumpIfZero { target, .. }
+            | Op::JumpIfNonZero { target, .. }
+            | Op::JumpIfLts { target, .. }
+            | Op::JumpIfNotLts { target, .. }
+            | Op::JumpIfLtsImm { target, .. }
+            | Op::JumpIfNotLtsImm { target, .. } => Some(target),
+            _ => None,
+        }
+    }
+
+    fn set_target(&mut self, to: usize) {
+        if let Some(target) = self.target_mut() {
+            *target = to as u32;
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum ControlKind {
+    Block,
+    Loop,
+    If,
+}
+
+struct Control {
+    kind: ControlKind,
+    // operand stack height below the block's params
+    height: usize,
+    params: Vec<ValueType>,
+    results: Vec<ValueType>,
+    // where a branch to a loop continues
+    start: usize,
+    // jumps to the end of the block, patched once the end is reached
+    exits: Vec<usize>,
+    // the jump over the then branch, patched at else or end
+    else_jump: Option<usize>,
+}
+
+struct Compiler<'a> {
+    func_types: &'a [FuncType],
+    callees: &'a [FuncType],
+    globals: &'a [GlobalType],
+    locals: Vec<ValueType>,
+    results: &'a [ValueType],
+    ops: Vec<Op>,
+    costs: Vec<u32>,
+    // the register holding each operand, a local until a local.get has to be copied
+    operands: Vec<Reg>,
+    types: Vec<ValueType>,
+    max_height: usize,
+    controls: Vec<Control>,
+    // fuel of the instructions since the last op
+    pending: u32,
+    // skipping the code after a branch, return or unreachable until the end of its block
+    unreachable: bool,
+    dead_depth: usize,
+    // the last op writes only this register, so a local.set may redirect it
+    last_dst: Option<Reg>,
+    finished: bool,
+}
+
+// lowers a function body from the stack machine form into register based ops,
+// each op is charged as many fuel units as the instructions it replaces
+pub fn compile(
+    func: &mut Func,
+    func_type: &FuncType,
+    func_types: &[FuncType],
+    callees: &[FuncType],
+    globals: &[GlobalType],
+    config: &Config,
+) -> Result<()> {
+    let mut locals = func_type.params.clone();
+    locals.extend(func.locals.iter().cloned());
+    let local_count = locals.len();
+    let mut compiler = Compiler {
+        func_types,
+        callees,
+        globals,
+        locals,
+        results: &func_type.results,
+        ops: vec![],
+        costs: vec![],
+        operands: vec![],
+        types: vec![],
+        max_height: 0,
+        controls: vec![],
+        pending: 0,
+        unreachable: false,
+        dead_depth: 0,
+        last_dst: None,
+        finished: false,
+    };
+    for inst in &func.body {
+        if compiler.finished {
+            bail!("instructions after the end of the function");
+        }
+        compiler.translate(inst)?;
+    }
+    if !compiler.finished {
+        bail!("missing end of function");
+    }
+
+    if config.fuse {
+        fuse(&mut compiler.ops, &mut compiler.costs, local_count as Reg);
+    }
+    func.ops = compiler.ops;
+    func.costs = compiler.costs;
+    func.reg_count = local_count + compiler.max_height;
+    Ok(())
+}
+
+// replaces pairs of ops with one op doing the work of both, the first op must only write
+// an operand slot read by the second, which is dead afterwards because the operand is popped
+fn fuse(ops: &mut Vec<Op>, costs: &mut Vec<u32>, local_count: Reg) {
+    // a jump into the middle of a pair would skip the first op
+    let mut targets = vec![false; ops.len() + 1];
+    for op in ops.iter_mut() {
+        if let Some(target) = op.target_mut() {
+            targets[*target as usize] = true;
+        }
+    }
+
+    let mut fused: Vec<Op> = Vec::with_capacity(ops.len());
+    let mut fused_costs: Vec<u32> = Vec::with_capacity(costs.len());
+    // the new index of every op, and of the end for jumps past the last op
+    let mut moved = Vec::with_capacity(ops.len() + 1);
+    for (idx, op) in ops.drain(..).enumerate() {
+        let cost = costs[idx];
+        let pair = match fused.last() {
+            Some(prev) if !targets[idx] => fuse_pair(prev, &op, local_count),
+            _ => None,
+        };
+        match pair {
+            Some(pair) => {
+                moved.push(fused.len() - 1);
+                *fused.last_mut().unwrap() = pair;
+                *fused_costs.last_mut().unwrap() += cost;
+            }
+            None => {
+                moved.push(fused.len());
+                fused.push(op);
+                fused_costs.push(cost);
+            }
+        }
+    }
+    moved.push(fused.len());
+
+    for op in fused.iter_mut() {
+        if let Some(target) = op.target_mut() {
+            *target = moved[*target as usize] as u32;
+        }
+    }
+    *ops = fused;
+    *costs = fused_costs;
+}
+
+fn fuse_pair(first: &Op, second: &Op, local_count: Reg) -> Option<Op> {
+    let temp = match first {
+        Op::Const { dst, .. } | Op::I32Lts { dst, .. } | Op::I32LtsImm { dst, .. }
+            if *dst >= local_count =>
+        {
+            *dst
+        }
+        _ => return None,
+    };
+    // a constant is an i32 when an i32 op reads it
+    let imm = match first {
+        Op::Const { value, .. } => Some(*value as u32 as i32),
+        _ => None,
+    };
+    Some(match (first, second, imm) {
+        (_, Op::I32Add { dst, lhs, rhs }, Some(imm)) if *rhs == temp && *lhs != temp => {
+            Op::I32AddImm {
+                dst: *dst,
+                lhs: *lhs,
+                imm,
+            }
+        }
+        (_, Op::I32Add { dst, lhs, rhs }, Some(imm)) if *lhs == temp && *rhs != temp => {
+            Op::I32AddImm {
+                dst: *dst,
+                lhs: *rhs,
+                imm,
+            }
+        }
+        (_, Op::I32Sub { dst, lhs, rhs }, Some(imm)) if *rhs == temp && *lhs != temp => {
+            Op::I32SubImm {
+                dst: *dst,
+                lhs: *lhs,
+                imm,
+            }
+        }
+        (_, Op::I32Lts { dst, lhs, rhs }, Some(imm)) if *rhs == temp && *lhs != temp => {
+            Op::I32LtsImm {
+                dst: *dst,
+                lhs: *lhs,
+                imm,
+            }
+        }
+        (Op::I32Lts { lhs, rhs, .. }, Op::JumpIfZero { cond, target }, None) if *cond == temp => {
+            Op::JumpIfNotLts {
+                lhs: *lhs,
+                rhs: *rhs,
+                target: *target,
+            }
+        }
+        (Op::I32Lts { lhs, rhs, .. }, Op::JumpIfNonZero { cond, target }, None)
+            if *cond == temp =>
+        {
+            Op::JumpIfLts {
+                lhs: *lhs,
+                rhs: *rhs,
+                target: *target,
+            }
+        }
+        (Op::I32LtsImm { lhs, imm, .. }, Op::JumpIfZero { cond, target }, None)
+            if *cond == temp =>
+        {
+            Op::JumpIfNotLtsImm {
+                lhs: *lhs,
+                imm: *imm,
+                target: *target,
+            }
+        }
+        (Op::I32LtsImm { lhs, imm, .. }, Op::JumpIfNonZero { cond, target }, None)
+            if *cond == temp =>
+        {
+            Op::JumpIfLtsImm {
+                lhs: *lhs,
+                imm: *imm,
+                target: *target,
+            }
+        }
+        _ => return None,
+    })
+}
+
+impl Compiler<'_> {
+    fn translate(&mut self, inst: &Instruction) -> Result<()> {
+        if self.unreachable {
+            match inst {
+                Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) => {
+                    self.dead_depth += 1;
+                    return Ok(());
+                }
+                Instruction::End if self.dead_depth > 0 => {
+                    self.dead_depth -= 1;
+                    return Ok(());
+                }
+                Instruction::Else | Instruction::End if self.dead_depth == 0 => {}
+                _ => return Ok(()),
+            }
+        }
+
+        match inst {
+            Instruction::Unreachable => {
+                self.pending += 1;
+                self.emit(Op::Unreachable);
+                self.unreachable = true;
+            }
+            Instruction::Block(block) => {
+                self.pending += 1;
+                self.materialize(0);
+                self.push_control(ControlKind::Block, block)?;
+            }
+            Instruction::Loop(block) => {
+                // a branch back to the loop only pays for the loop and what follows it
+                self.materialize(0);
+                self.flush();
+                self.pending += 1;
+                self.last_dst = None;
+                self.push_control(ControlKind::Loop, block)?;
+            }
+            Instruction::If(block) => {
+                self.pending += 1;
+                let cond = self.pop(ValueType::I32)?;
+                self.materialize(0);
+                let jump = self.emit(Op::JumpIfZero { cond, target: 0 });
+                self.push_control(ControlKind::If, block)?;
+                if let Some(control) = self.controls.last_mut() {
+                    control.else_jump = Some(jump);
+                }
+            }
+            Instruction::Else => {
+                let Some(control) = self.controls.last() else {
+                    bail!("else outside of if");
+                };
+                if control.kind != ControlKind::If {
+                    bail!("else outside of if");
+                }
+                let (height, params, results) = (
+                    control.height,
+                    control.params.clone(),
+                    control.results.clone(),
+                );
+                if !self.unreachable {
+                    self.pending += 1;
+                    self.move_values(height, &results)?;
+                    let jump = self.emit(Op::Jump { target: 0 });
+                    self.control_mut(0)?.exits.push(jump);
+                }
+                let landing = self.ops.len();
+                if let Some(jump) = self.control_mut(0)?.else_jump.take() {
+                    self.ops[jump].set_target(landing);
+                }
+                self.last_dst = None;
+                self.truncate(height);
+                for value_type in params {
+                    self.push_slot(value_type);
+                }
+                self.unreachable = false;
+            }
+            Instruction::End => {
+                let Some(control) = self.controls.pop() else {
+                    if !self.unreachable {
+                        self.pending += 1;
+                        self.materialize(0);
+                        let src = self.results_start(self.results)?;
+                        self.emit(Op::Return { src });
+                    }
+                    self.finished = true;
+                    return Ok(());
+                };
+                if !self.unreachable {
+                    self.move_values(control.height, &control.results)?;
+                    self.flush();
+                }
+                let landing = self.ops.len();
+                for jump in control.exits.into_iter().chain(control.else_jump) {
+                    self.ops[jump].set_target(landing);
+                }
+                self.last_dst = None;
+                self.pending += 1;
+                self.truncate(control.height);
+                for value_type in control.results {
+                    self.push_slot(value_type);
+                }
+                self.unreachable = false;
+            }
+            Instruction::Br(depth) => {
+                self.pending += 1;
+                self.branch(*depth as usize)?;
+                self.unreachable = true;
+            }
+            Instruction::BrIf(depth) => {
+                self.pending += 1;
+                let cond = self.pop(ValueType::I32)?;
+                self.branch_if(cond, *depth as usize)?;
+            }
+            Instruction::Return => {
+                self.pending += 1;
+                self.materialize(0);
+                let src = self.results_start(self.results)?;
+                self.emit(Op::Return { src });
+                self.unreachable = true;
+            }
+            Instruction::LocalGet(idx) => {
+                self.pending += 1;
+                let Some(value_type) = self.locals.get(*idx as usize) else {
+                    bail!("unknown local {}", idx);
+                };
+                self.push(*idx, value_type.clone());
+            }
+            Instruction::LocalSet(idx) => {
+                self.pending += 1;
+                let Some(value_type) = self.locals.get(*idx as usize) else {
+                    bail!("unknown local {}", idx);
+                };
+                let src = self.pop(value_type.clone())?;
+                // operands still reading the local need their value before it changes
+                self.materialize_local(*idx);
+                if src == *idx {
+                    return Ok(());
+                }
+                // only a temporary may be retargeted, a local written by the last op must keep
+                // its value
+                if self.last_dst == Some(src) && src as usize >= self.locals.len() {
+                    if let Some(dst) = self.ops.last_mut().and_then(Op::dst_mut) {
+                        *dst = *idx;
+                    }
+                    self.last_dst = None;
+                } else {
+                    self.emit(Op::Copy { dst: *idx, src });
+                }
+            }
+            Instruction::GlobalGet(idx) => {
+                self.pending += 1;
+                let dst = self.push_slot(self.global_type(*idx)?);
+                self.emit(Op::GlobalGet { dst, idx: *idx });
+            }
+            Instruction::GlobalSet(idx) => {
+                self.pending += 1;
+                let src = self.pop(self.global_type(*idx)?)?;
+                self.emit(Op::GlobalSet { idx: *idx, src });
+            }
+            Instruction::I32Store { align: _, offset } => {
+                self.pending += 1;
+                let value = self.pop(ValueType::I32)?;
+                let addr = self.pop(ValueType::I32)?;
+                self.emit(Op::I32Store {
+                    addr,
+                    value,
+                    offset: *offset,
+                });
+            }
+            Instruction::I32Const(value) => {
+                self.pending += 1;
+                let dst = self.push_slot(ValueType::I32);
+                self.emit(Op::Const {
+                    dst,
+                    value: Value::I32(*value).to_bits(),
+                });
+            }
+            Instruction::I64Const(value) => {
+                self.pending += 1;
+                let dst = self.push_slot(ValueType::I64);
+                self.emit(Op::Const {
+                    dst,
+                    value: Value::I64(*value).to_bits(),
+                });
+            }
+            Instruction::I32Add | Instruction::I32Sub | Instruction::I32Lts => {
+                self.pending += 1;
+                let rhs = self.pop(ValueType::I32)?;
+                let lhs = self.pop(ValueType::I32)?;
+                let dst = self.push_slot(ValueType::I32);
+                self.emit(match inst {
+                    Instruction::I32Add => Op::I32Add { dst, lhs, rhs },
+                    Instruction::I32Sub => Op::I32Sub { dst, lhs, rhs },
+                    _ => Op::I32Lts { dst, lhs, rhs },
+                });
+            }
+            Instruction::Call(idx) => {
+                self.pending += 1;
+                let Some(func_type) = self.callees.get(*idx as usize) else {
+                    bail!("unknown function {}", idx);
+                };
+                let args = self.take_args(&func_type.params)?;
+                self.emit(Op::Call { func: *idx, args });
+                self.take_results(args, &func_type.results);
+            }
+            Instruction::CallIndirect {
+                type_idx,
+                table_idx,
+            } => {
+                self.pending += 1;
+                let Some(func_type) = self.func_types.get(*type_idx as usize) else {
+                    bail!("unknown type {}", type_idx);
+                };
+                let elem = self.pop(ValueType::I32)?;
+                let args = self.take_args(&func_type.params)?;
+                self.emit(Op::CallIndirect {
+                    type_idx: *type_idx,
+                    table_idx: *table_idx,
+                    elem,
+                    args,
+                });
+                self.take_results(args, &func_type.results);
+            }
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, mut op: Op) -> usize {
+        self.last_dst = op.dst_mut().map(|dst| *dst);
+        self.ops.push(op);
+        self.costs.push(std::mem::take(&mut self.pending));
+        self.ops.len() - 1
+    }
+
+    // instructions before a jump target are paid for by an op of their own
+    fn flush(&mut self) {
+        if self.pending > 0 {
+            self.emit(Op::Nop);
+        }
+    }
+
+    fn slot(&self, height: usize) -> Reg {
+        (self.locals.len() + height) as Reg
+    }
+
+    fn push(&mut self, reg: Reg, value_type: ValueType) {
+        self.operands.push(reg);
+        self.types.push(value_type);
+        self.max_height = self.max_height.max(self.operands.len());
+    }
+
+    fn push_slot(&mut self, value_type: ValueType) -> Reg {
+        let reg = self.slot(self.operands.len());
+        self.push(reg, value_type);
+        reg
+    }
+
+    fn pop(&mut self, expected: ValueType) -> Result<Reg> {
+        let (Some(reg), Some(value_type)) = (self.operands.pop(), self.types.pop()) else {
+            bail!("not found value in the stack");
+        };
+        if value_type != expected {
+            bail!(
+                "type mismatch: expected {:?}, got {:?}",
+                expected,
+                value_type
+            );
+        }
+        Ok(reg)
+    }
+
+    fn truncate(&mut self, height: usize) {
+        self.operands.truncate(height);
+        self.types.truncate(height);
+    }
+
+    // the top operands must have these types
+    fn check_top(&self, expected: &[ValueType]) -> Result<usize> {
+        let Some(height) = self.types.len().checked_sub(expected.len()) else {
+            bail!("not found value in the stack");
+        };
+        if self.types[height..] != *expected {
+            bail!(
+                "type mismatch: expected {:?}, got {:?}",
+                expected,
+                &self.types[height..]
+            );
+        }
+        Ok(height)
+    }
+
+    fn global_type(&self, idx: u32) -> Result<ValueType> {
+        let Some(global) = self.globals.get(idx as usize) else {
+            bail!("unknown global {}", idx);
+        };
+        Ok(global.value_type.clone())
+    }
+
+    // copies the operands from height on that still live in a local into their slots
+    fn materialize(&mut self, from: usize) {
+        for height in from..self.operands.len() {
+            let slot = self.slot(height);
+            if self.operands[height] != slot {
+                let src = self.operands[height];
+                self.emit(Op::Copy { dst: slot, src });
+                self.operands[height] = slot;
+            }
+        }
+    }
+
+    fn materialize_local(&mut self, local: Reg) {
+        for height in 0..self.operands.len() {
+            if self.operands[height] == local {
+                let slot = self.slot(height);
+                self.emit(Op::Copy {
+                    dst: slot,
+                    src: local,
+                });
+                self.operands[height] = slot;
+            }
+        }
+    }
+
+    fn results_start(&self, results: &[ValueType]) -> Result<Reg> {
+        let height = self.check_top(results)?;
+        Ok(self.slot(height))
+    }
+
+    // moves the top operands down to the slots from height on
+    fn move_values(&mut self, height: usize, values: &[ValueType]) -> Result<()> {
+        self.materialize(0);
+        let from = self.check_top(values)?;
+        if from < height {
+            bail!("not found value in the stack");
+        }
+        if from != height {
+            for offset in 0..values.len() {
+                let (dst, src) = (self.slot(height + offset), self.slot(from + offset));
+                self.emit(Op::Copy { dst, src });
+            }
+        }
+        Ok(())
+    }
+
+    fn take_args(&mut self, params: &[ValueType]) -> Result<Reg> {
+        let height = self.check_top(params)?;
+        self.materialize(height);
+        self.truncate(height);
+        Ok(self.slot(height))
+    }
+
+    fn take_results(&mut self, dst: Reg, results: &[ValueType]) {
+        for value_type in results {
+            self.push_slot(value_type.clone());
+        }
+        if !results.is_empty() {
+            self.emit(Op::TakeResults {
+                dst,
+                count: results.len() as u32,
+            });
+        }
+    }
+
+    fn block_types(&self, block: &Block) -> Result<(Vec<ValueType>, Vec<ValueType>)> {
+        Ok(match &block.block_type {
+            BlockType::Void => (vec![], vec![]),
+            BlockType::Value(results) => (vec![], results.clone()),
+            BlockType::FuncType(idx) => {
+                let Some(func_type) = self.func_types.get(*idx as usize) else {
+                    bail!("unknown type {}", idx);
+                };
+                (func_type.params.clone(), func_type.results.clone())
+            }
+        })
+    }
+
+    fn push_control(&mut self, kind: ControlKind, block: &Block) -> Result<()> {
+        let (params, results) = self.block_types(block)?;
+        let height = self.check_top(&params)?;
+        self.controls.push(Control {
+            kind,
+            height,
+            params,
+            results,
+            start: self.ops.len(),
+            exits: vec![],
+            else_jump: None,
+        });
+        Ok(())
+    }
+
+    fn control_mut(&mut self, depth: usize) -> Result<&mut Control> {
+        let len = self.controls.len();
+        len.checked_sub(depth + 1)
+            .and_then(|idx| self.controls.get_mut(idx))
+            .ok_or(anyhow!("unknown label {}", depth))
+    }
+
+    // the values a branch carries to its target, the params of a loop or the results of a block
+    fn branch_values(&mut self, depth: usize) -> Result<(usize, Vec<ValueType>)> {
+        let control = self.control_mut(depth)?;
+        Ok(match control.kind {
+            ControlKind::Loop => (control.height, control.params.clone()),
+            _ => (control.height, control.results.clone()),
+        })
+    }
+
+    fn jump_to(&mut self, depth: usize, cond: Option<Reg>) -> Result<()> {
+        let control = self.control_mut(depth)?;
+        let (is_loop, start) = (control.kind == ControlKind::Loop, control.start as u32);
+        let target = if is_loop { start } else { 0 };
+        let jump = self.emit(match cond {
+            None => Op::Jump { target },
+            Some(cond) => Op::JumpIfNonZero { cond, target },
+        });
+        if !is_loop {
+            self.control_mut(depth)?.exits.push(jump);
+        }
+        Ok(())
+    }
+
+    fn branch(&mut self, depth: usize) -> Result<()> {
+        self.materialize(0);
+        // the outermost label is the function body itself
+        if depth == self.controls.len() {
+            let src = self.results_start(self.results)?;
+            self.emit(Op::Return { src });
+            return Ok(());
+        }
+        let (height, values) = self.branch_values(depth)?;
+        self.move_values(height, &values)?;
+        self.jump_to(depth, None)
+    }
+
+    fn branch_if(&mut self, cond: Reg, depth: usize) -> Result<()> {
+        self.materialize(0);
+        if depth < self.controls.len() {
+            let (height, values) = self.branch_values(depth)?;
+            if self.results_start(&values)? == self.slot(height) {
+                return self.jump_to(depth, Some(cond));
+            }
+        }
+        // the values have to be moved first, so the branch is taken by skipping over it
+        let skip = self.emit(Op::JumpIfZero { cond, target: 0 });
+        self.branch(depth)?;
+        let landing = self.ops.len();
+        self.ops[skip].set_target(landing);
+        self.last_dst = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compile, Op};
+    use crate::{
+        binary::{
+            instruction::Instruction,
+            types::{Block, BlockType, FuncType, ValueType},
+        },
+        execution::{config::Config, store::Func},
+    };
+    use anyhow::Result;
+
+    fn compile_with(
+        config: &Config,
+        func_type: &FuncType,
+        locals: Vec<ValueType>,
+        body: Vec<Instruction>,
+    ) -> Result<Func> {
+        let mut func = Func {
+            locals,
+            body,
+            ..Default::default()
+        };
+        compile(&mut func, func_type, &[], &[], &[], config)?;
+        Ok(func)
+    }
+
+    // the lowering is checked without fusion, which has tests of its own
+    fn compile_body(
+        func_type: &FuncType,
+        locals: Vec<ValueType>,
+        body: Vec<Instruction>,
+    ) -> Result<Func> {
+        compile_with(Config::new().fuse(false), func_type, locals, body)
+    }
+
+    #[test]
+    fn lower_locals_into_registers() -> Result<()> {
+        let func_type = FuncType {
+            params: vec![ValueType::I32, ValueType::I32],
+            results: vec![ValueType::I32],
+        };
+        let func = compile_body(
+            &func_type,
+            vec![ValueType::I32],
+            vec![
+                Instruction::LocalGet(0),
+                Instruction::LocalGet(1),
+                Instruction::I32Add,
+                Instruction::LocalSet(2),
+                Instruction::LocalGet(2),
+                Instruction::I32Const(1),
+                Instruction::I32Sub,
+                Instruction::End,
+            ],
+        )?;
+        assert_eq!(
+            func.ops,
+            vec![
+                Op::I32Add {
+                    dst: 2,
+                    lhs: 0,
+                    rhs: 1
+                },
+                Op::Const { dst: 4, value: 1 },
+                Op::I32Sub {
+                    dst: 3,
+                    lhs: 2,
+                    rhs: 4
+                },
+                Op::Return { src: 3 },
+            ]
+        );
+        // every instruction is still charged once
+        assert_eq!(func.costs, vec![3, 3, 1, 1]);
+        assert_eq!(func.reg_count, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_between_locals() -> Result<()> {
+        let func_type = FuncType {
+            params: vec![ValueType::I32],
+            results: vec![ValueType::I32],
+        };
+        let func = compile_body(
+            &func_type,
+            vec![ValueType::I32, ValueType::I32],
+            vec![
+                Instruction::LocalGet(0),
+                Instruction::LocalSet(2),
+                Instruction::LocalGet(2),
+                Instruction::LocalSet(1),
+                Instruction::LocalGet(2),
+                Instruction::End,
+            ],
+        )?;
+        // the copy into local 2 is not retargeted to local 1
+        assert_eq!(
+            func.ops,
+            vec![
+                Op::Copy { dst: 2, src: 0 },
+                Op::Copy { dst: 1, src: 2 },
+                Op::Copy { dst: 3, src: 2 },
+                Op::Return { src: 3 },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn lower_branches() -> Result<()> {
+        let func_type = FuncType {
+            params: vec![ValueType::I32],
+            results: vec![ValueType::I32],
+        };
+        let result = BlockType::Value(vec![ValueType::I32]);
+        let func = compile_body(
+            &func_type,
+            vec![],
+            vec![
+                Instruction::Block(Block {
+                    block_type: result.clone(),
+                }),
+                Instruction::I32Const(1),
+                Instruction::I32Const(2),
+                Instruction::LocalGet(0),
+                Instruction::BrIf(0),
+                Instruction::Unreachable,
+                Instruction::I32Const(3),
+                Instruction::End,
+                Instruction::End,
+            ],
+        )?;
+        assert_eq!(
+            func.ops,
+            vec![
+                Op::Const { dst: 1, value: 1 },
+                Op::Const { dst: 2, value: 2 },
+                Op::JumpIfZero { cond: 0, target: 5 },
+                Op::Copy { dst: 1, src: 2 },
+                Op::Jump { target: 6 },
+                Op::Unreachable,
+                Op::Return { src: 1 },
+            ]
+        );
+        assert_eq!(func.costs, vec![2, 1, 2, 0, 0, 1, 2]);
+
+        let result = compile_body(&func_type, vec![], vec![Instruction::I32Add]);
+        assert_eq!(
+            result.err().map(|e| e.to_string()),
+            Some("not found value in the stack".into())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_type_mismatch() -> Result<()> {
+        let func_type = FuncType {
+            params: vec![ValueType::I64],
+            results: vec![ValueType::I32],
+        };
+        let result = compile_body(
+            &func_type,
+            vec![],
+            vec![
+                Instruction::LocalGet(0),
+                Instruction::I32Const(1),
+                Instruction::I32Add,
+                Instruction::End,
+            ],
+        );
+        assert_eq!(
+            result.err().map(|e| e.to_string()),
+            Some("type mismatch: expected I32, got I64".into())
+        );
+
+        let result = compile_body(
+            &func_type,
+            vec![],
+            vec![Instruction::I64Const(1), Instruction::End],
+        );
+        assert_eq!(
+            result.err().map(|e| e.to_string()),
+            Some("type mismatch: expected [I32], got [I64]".into())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fuse_ops() -> Result<()> {
+        let func_type = FuncType {
+            params: vec![ValueType::I32],
+            results: vec![ValueType::I32],
+        };
+        let result = vec![ValueType::I32];
+        // the shape of fib: if (n < 2) n else n - 1 + (n - 2)
+        let body = vec![
+            Instruction::LocalGet(0),
+            Instruction::I32Const(2),
+            Instruction::I32Lts,
+            Instruction::If(Block {
+                block_type: BlockType::Value(result),
+            }),
+            Instruction::LocalGet(0),
+            Instruction::Else,
+            Instruction::LocalGet(0),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::I32Const(2),
+            Instruction::LocalGet(0),
+            Instruction::I32Add,
+            Instruction::I32Add,
+            Instruction::End,
+            Instruction::End,
+        ];
+        let func = compile_with(&Config::new(), &func_type, vec![], body.clone())?;
+        assert_eq!(
+            func.ops,
+            vec![
+                Op::JumpIfNotLtsImm {
+                    lhs: 0,
+                    imm: 2,
+                    target: 3
+                },
+                Op::Copy { dst: 1, src: 0 },
+                Op::Jump { target: 6 },
+                Op::I32SubImm {
+                    dst: 1,
+                    lhs: 0,
+                    imm: 1
+                },
+                Op::I32AddImm {
+                    dst: 2,
+                    lhs: 0,
+                    imm: 2
+                },
+                Op::I32Add {
+                    dst: 1,
+                    lhs: 1,
+                    rhs: 2
+                },
+                Op::Return { src: 1 },
+            ]
+        );
+
+        // fusing moves the fuel of the first op into the fused op
+        let unfused = compile_body(&func_type, vec![], body)?;
+        assert!(unfused.ops.len() > func.ops.len());
+        assert_eq!(
+            func.costs.iter().sum::<u32>(),
+            unfused.costs.iter().sum::<u32>()
+        );
+        Ok(())
+    }
+}
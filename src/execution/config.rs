@@ -0,0 +1,86 @@
+// settings for how a module is compiled, passed to Module::new_with_config
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub(crate) fuse: bool,
+    pub(crate) lazy: bool,
+    #[cfg(target_os = "linux")]
+    pub(crate) mmap_memory: bool,
+    #[cfg(feature = "jit")]
+    pub(crate) jit: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            fuse: true,
+            lazy: true,
+            #[cfg(target_os = "linux")]
+            mmap_memory: false,
+            #[cfg(feature = "jit")]
+            jit: true,
+        }
+    }
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // fusing common op sequences into one op is on by default, turning it off keeps
+    // the compiled code one op per group of wasm instructions, which is easier to debug
+    pub fn fuse(&mut self, fuse: bool) -> &mut Self {
+        self.fuse = fuse;
+        self
+    }
+
+    // functions are validated while their instructions are read in Module::new and only
+    // decoded and lowered on their first call by default, turning it off decodes and lowers
+    // every function in Module::new as well
+    pub fn lazy(&mut self, lazy: bool) -> &mut Self {
+        self.lazy = lazy;
+        self
+    }
+
+    // memories defined by the module reserve 8GiB of address space with mmap instead of
+    // living in a vector, so they grow in place, and native code leaves the bounds checks
+    // to the guard pages past the end of the memory
+    #[cfg(target_os = "linux")]
+    pub fn mmap_memory(&mut self, mmap_memory: bool) -> &mut Self {
+        self.mmap_memory = mmap_memory;
+        self
+    }
+
+    // functions are compiled to native code when the jit feature is enabled, calls made
+    // while fuel is set are still interpreted and no function is compiled lazily
+    #[cfg(feature = "jit")]
+    pub fn jit(&mut self, jit: bool) -> &mut Self {
+        self.jit = jit;
+        self
+    }
+
+    // faults in the guard region are only turned into traps where the handler knows the
+    // registers of the native code
+    #[cfg(feature = "jit")]
+    pub(crate) fn guard_pages(&self) -> bool {
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        return self.mmap_memory;
+        #[cfg(not(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        )))]
+        false
+    }
+
+    // native code is generated for the whole module at once, which needs every function
+    pub(crate) fn compiles_lazily(&self) -> bool {
+        #[cfg(feature = "jit")]
+        if self.jit {
+            return false;
+        }
+        self.lazy
+    }
+}
@@ -0,0 +1,295 @@
+use std::{marker::PhantomData, sync::Arc};
+
+use super::{
+    caller::Caller,
+    import::ImportFunc,
+    runtime::{check_args, Runtime},
+    value::Value,
+};
+use crate::binary::types::{FuncType, ValueType};
+use anyhow::{anyhow, bail, Result};
+
+pub trait WasmTy: Sized {
+    fn value_type() -> ValueType;
+    fn from_value(value: Value) -> Result<Self>;
+    fn into_value(self) -> Value;
+}
+
+macro_rules! impl_wasm_ty {
+    ($ty:ty, $variant:ident) => {
+        impl WasmTy for $ty {
+            fn value_type() -> ValueType {
+                ValueType::$variant
+            }
+
+            fn from_value(value: Value) -> Result<Self> {
+                match value {
+                    Value::$variant(value) => Ok(value),
+                    _ => bail!(
+                        "type mismatch: expected {:?}, got {:?}",
+                        ValueType::$variant,
+                        value.value_type()
+                    ),
+                }
+            }
+
+            fn into_value(self) -> Value {
+                Value::$variant(self)
+            }
+        }
+    };
+}
+
+impl_wasm_ty!(i32, I32);
+impl_wasm_ty!(i64, I64);
+impl_wasm_ty!(f32, F32);
+impl_wasm_ty!(f64, F64);
+
+pub trait WasmParams {
+    fn value_types() -> Vec<ValueType>;
+    fn into_values(self) -> Vec<Value>;
+}
+
+pub trait WasmResults: Sized {
+    fn value_types() -> Vec<ValueType>;
+    fn from_values(values: Vec<Value>) -> Result<Self>;
+}
+
+impl<T: WasmTy> WasmParams for T {
+    fn value_types() -> Vec<ValueType> {
+        vec![T::value_type()]
+    }
+
+    fn into_values(self) -> Vec<Value> {
+        vec![self.into_value()]
+    }
+}
+
+impl<T: WasmTy> WasmResults for T {
+    fn value_types() -> Vec<ValueType> {
+        vec![T::value_type()]
+    }
+
+    fn from_values(values: Vec<Value>) -> Result<Self> {
+        let (value,) = <(T,)>::from_values(values)?;
+        Ok(value)
+    }
+}
+
+macro_rules! impl_wasm_tuple {
+    ($($t:ident)*) => {
+        #[allow(non_snake_case)]
+        impl<$($t: WasmTy),*> WasmParams for ($($t,)*) {
+            fn value_types() -> Vec<ValueType> {
+                vec![$($t::value_type()),*]
+            }
+
+            fn into_values(self) -> Vec<Value> {
+                let ($($t,)*) = self;
+                vec![$($t.into_value()),*]
+            }
+        }
+
+        #[allow(non_snake_case, unused_mut, unused_variables)]
+        impl<$($t: WasmTy),*> WasmResults for ($($t,)*) {
+            fn value_types() -> Vec<ValueType> {
+                vec![$($t::value_type()),*]
+            }
+
+            fn from_values(values: Vec<Value>) -> Result<Self> {
+                let count = <Self as WasmResults>::value_types().len();
+                if values.len() != count {
+                    bail!("invalid number of results: expected {}, got {}", count, values.len());
+                }
+                let mut values = values.into_iter();
+                $(let $t = $t::from_value(values.next().unwrap())?;)*
+                Ok(($($t,)*))
+            }
+        }
+    };
+}
+
+impl_wasm_tuple!();
+impl_wasm_tuple!(A1);
+impl_wasm_tuple!(A1 A2);
+impl_wasm_tuple!(A1 A2 A3);
+impl_wasm_tuple!(A1 A2 A3 A4);
+impl_wasm_tuple!(A1 A2 A3 A4 A5);
+impl_wasm_tuple!(A1 A2 A3 A4 A5 A6);
+impl_wasm_tuple!(A1 A2 A3 A4 A5 A6 A7);
+impl_wasm_tuple!(A1 A2 A3 A4 A5 A6 A7 A8);
+
+pub trait WasmRet {
+    fn value_types() -> Vec<ValueType>;
+    fn into_values(self) -> Result<Vec<Value>>;
+}
+
+impl<T: WasmParams> WasmRet for T {
+    fn value_types() -> Vec<ValueType> {
+        T::value_types()
+    }
+
+    fn into_values(self) -> Result<Vec<Value>> {
+        Ok(WasmParams::into_values(self))
+    }
+}
+
+impl<T: WasmParams> WasmRet for Result<T> {
+    fn value_types() -> Vec<ValueType> {
+        T::value_types()
+    }
+
+    fn into_values(self) -> Result<Vec<Value>> {
+        self.map(WasmParams::into_values)
+    }
+}
+
+// host functions are Fn rather than FnMut because a host function may be entered again
+// through a call back into wasm while it runs, state that changes between calls lives
+// behind a Mutex or an atomic instead
+pub trait IntoFunc<T, Params, Results> {
+    fn into_func(self) -> (FuncType, ImportFunc<T>);
+}
+
+macro_rules! impl_into_func {
+    ($($t:ident)*) => {
+        #[allow(non_snake_case, unused_mut, unused_variables)]
+        impl<T, F, R, $($t),*> IntoFunc<T, ($($t,)*), R> for F
+        where
+            F: Fn($($t),*) -> R + Send + Sync + 'static,
+            $($t: WasmTy,)*
+            R: WasmRet,
+        {
+            fn into_func(self) -> (FuncType, ImportFunc<T>) {
+                let func_type = FuncType {
+                    params: vec![$($t::value_type()),*],
+                    results: R::value_types(),
+                };
+                let func = move |_: Caller<'_, T>, args: Vec<Value>| {
+                    let mut args = args.into_iter();
+                    $(let $t = $t::from_value(args.next().ok_or(anyhow!("not found argument"))?)?;)*
+                    self($($t),*).into_values()
+                };
+                (func_type, Arc::new(func))
+            }
+        }
+
+        #[allow(non_snake_case, unused_mut, unused_variables)]
+        impl<'a, T, F, R, $($t),*> IntoFunc<T, (Caller<'a, T>, $($t,)*), R> for F
+        where
+            F: for<'b> Fn(Caller<'b, T>, $($t),*) -> R + Send + Sync + 'static,
+            $($t: WasmTy,)*
+            R: WasmRet,
+        {
+            fn into_func(self) -> (FuncType, ImportFunc<T>) {
+                let func_type = FuncType {
+                    params: vec![$($t::value_type()),*],
+                    results: R::value_types(),
+                };
+                let func = move |caller: Caller<'_, T>, args: Vec<Value>| {
+                    let mut args = args.into_iter();
+                    $(let $t = $t::from_value(args.next().ok_or(anyhow!("not found argument"))?)?;)*
+                    self(caller, $($t),*).into_values()
+                };
+                (func_type, Arc::new(func))
+            }
+        }
+    };
+}
+
+impl_into_func!();
+impl_into_func!(A1);
+impl_into_func!(A1 A2);
+impl_into_func!(A1 A2 A3);
+impl_into_func!(A1 A2 A3 A4);
+impl_into_func!(A1 A2 A3 A4 A5);
+impl_into_func!(A1 A2 A3 A4 A5 A6);
+impl_into_func!(A1 A2 A3 A4 A5 A6 A7);
+impl_into_func!(A1 A2 A3 A4 A5 A6 A7 A8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Func {
+    // the store the index belongs to
+    pub(crate) store: u64,
+    pub(crate) idx: usize,
+}
+
+impl Func {
+    pub(crate) fn check_store<T>(&self, runtime: &Runtime<T>) -> Result<()> {
+        check_store(self.store, self.idx, runtime)
+    }
+
+    pub fn func_type<'a, T>(&self, runtime: &'a Runtime<T>) -> Result<&'a FuncType> {
+        self.check_store(runtime)?;
+        let Some(func) = runtime.store.funcs.get(self.idx) else {
+            bail!("not found func {}", self.idx);
+        };
+        Ok(func.func_type())
+    }
+
+    pub fn call<T>(&self, runtime: &mut Runtime<T>, args: Vec<Value>) -> Result<Vec<Value>> {
+        check_args(
+            &format!("func {}", self.idx),
+            self.func_type(runtime)?,
+            &args,
+        )?;
+        runtime.invoke(self.idx, args)
+    }
+
+    pub fn typed<T, Params, Results>(
+        &self,
+        runtime: &Runtime<T>,
+    ) -> Result<TypedFunc<Params, Results>>
+    where
+        Params: WasmParams,
+        Results: WasmResults,
+    {
+        let func_type = self.func_type(runtime)?;
+        let expected = FuncType {
+            params: Params::value_types(),
+            results: Results::value_types(),
+        };
+        if *func_type != expected {
+            bail!(
+                "type mismatch for func {}: expected {:?}, got {:?}",
+                self.idx,
+                expected,
+                func_type
+            );
+        }
+        Ok(TypedFunc::new(*self))
+    }
+}
+
+// handles are plain indices, used with another runtime they would name another function
+fn check_store<T>(store: u64, idx: usize, runtime: &Runtime<T>) -> Result<()> {
+    if store != runtime.store.id {
+        bail!("func {} belongs to another runtime", idx);
+    }
+    Ok(())
+}
+
+pub struct TypedFunc<Params, Results> {
+    func: Func,
+    _marker: PhantomData<fn(Params) -> Results>,
+}
+
+impl<Params, Results> TypedFunc<Params, Results>
+where
+    Params: WasmParams,
+    Results: WasmResults,
+{
+    fn new(func: Func) -> Self {
+        Self {
+            func,
+            _marker: PhantomData,
+        }
+    }
+
+    // the type was checked against the function when the handle was made
+    pub fn call<T>(&self, runtime: &mut Runtime<T>, params: Params) -> Result<Results> {
+        self.func.check_store(runtime)?;
+        let values = runtime.invoke(self.func.idx, params.into_values())?;
+        Results::from_values(values)
+    }
+}
@@ -0,0 +1,245 @@
+// native code compiled for mapped memories has no bounds checks, an access past the end
+// of the memory faults in its guard region and the fault handler makes the call return
+// the out of bounds trap code instead
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) use imp::{call, unguarded};
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub(crate) use fallback::{call, unguarded};
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod fallback {
+    use crate::execution::jit::{Context, Entry};
+
+    // code is never compiled without bounds checks here
+    pub(crate) unsafe fn call(
+        entry: Entry,
+        ctx: *mut Context,
+        buf: *mut u64,
+        _mem_base: *mut u8,
+    ) -> u32 {
+        unsafe { entry(ctx, buf) }
+    }
+
+    pub(crate) fn unguarded<R>(f: impl FnOnce() -> R) -> R {
+        f()
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod imp {
+    use std::{
+        arch::global_asm,
+        cell::Cell,
+        mem, ptr,
+        sync::{Once, OnceLock},
+    };
+
+    use crate::execution::{
+        jit::{Context, Entry, TRAP_OUT_OF_BOUNDS},
+        memory::RESERVED_LEN,
+    };
+
+    // calls entry after saving the callee saved registers and storing the stack pointer
+    // in sp, returning from tinywasm_guard_trap with that stack pointer restores them
+    #[cfg(target_arch = "x86_64")]
+    global_asm!(
+        ".globl tinywasm_call_guarded",
+        ".p2align 4",
+        "tinywasm_call_guarded:",
+        "push rbp",
+        "mov rbp, rsp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "sub rsp, 8",
+        "mov [rcx], rsp",
+        "mov rax, rdi",
+        "mov rdi, rsi",
+        "mov rsi, rdx",
+        "call rax",
+        "2:",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        ".globl tinywasm_guard_trap",
+        "tinywasm_guard_trap:",
+        "mov eax, {trap}",
+        "jmp 2b",
+        trap = const TRAP_OUT_OF_BOUNDS,
+    );
+
+    #[cfg(target_arch = "aarch64")]
+    global_asm!(
+        ".globl tinywasm_call_guarded",
+        ".p2align 2",
+        "tinywasm_call_guarded:",
+        "stp x29, x30, [sp, #-16]!",
+        "mov x29, sp",
+        "stp x19, x20, [sp, #-16]!",
+        "stp x21, x22, [sp, #-16]!",
+        "stp x23, x24, [sp, #-16]!",
+        "stp x25, x26, [sp, #-16]!",
+        "stp x27, x28, [sp, #-16]!",
+        "stp d8, d9, [sp, #-16]!",
+        "stp d10, d11, [sp, #-16]!",
+        "stp d12, d13, [sp, #-16]!",
+        "stp d14, d15, [sp, #-16]!",
+        "mov x9, sp",
+        "str x9, [x3]",
+        "mov x9, x0",
+        "mov x0, x1",
+        "mov x1, x2",
+        "blr x9",
+        "2:",
+        "ldp d14, d15, [sp], #16",
+        "ldp d12, d13, [sp], #16",
+        "ldp d10, d11, [sp], #16",
+        "ldp d8, d9, [sp], #16",
+        "ldp x27, x28, [sp], #16",
+        "ldp x25, x26, [sp], #16",
+        "ldp x23, x24, [sp], #16",
+        "ldp x21, x22, [sp], #16",
+        "ldp x19, x20, [sp], #16",
+        "ldp x29, x30, [sp], #16",
+        "ret",
+        ".globl tinywasm_guard_trap",
+        "tinywasm_guard_trap:",
+        "mov w0, #{trap}",
+        "b 2b",
+        trap = const TRAP_OUT_OF_BOUNDS,
+    );
+
+    unsafe extern "C" {
+        fn tinywasm_call_guarded(
+            entry: Entry,
+            ctx: *mut libc::c_void,
+            buf: *mut u64,
+            sp: *mut usize,
+        ) -> u32;
+        fn tinywasm_guard_trap();
+    }
+
+    thread_local! {
+        // the stack pointer of the innermost guarded call while its native code runs,
+        // zero while the runtime runs
+        static SP: Cell<usize> = const { Cell::new(0) };
+        // the memory reservation of that call
+        static BASE: Cell<usize> = const { Cell::new(0) };
+    }
+
+    static INSTALL: Once = Once::new();
+    static PREVIOUS: OnceLock<[libc::sigaction; 2]> = OnceLock::new();
+    const SIGNALS: [libc::c_int; 2] = [libc::SIGSEGV, libc::SIGBUS];
+
+    pub(crate) unsafe fn call(
+        entry: Entry,
+        ctx: *mut Context,
+        buf: *mut u64,
+        mem_base: *mut u8,
+    ) -> u32 {
+        INSTALL.call_once(install);
+        let (sp, base) = (SP.get(), BASE.get());
+        BASE.set(mem_base as usize);
+        let code = unsafe { tinywasm_call_guarded(entry, ctx.cast(), buf, SP.with(Cell::as_ptr)) };
+        SP.set(sp);
+        BASE.set(base);
+        code
+    }
+
+    // a fault in the runtime called back from native code is not a trap
+    pub(crate) fn unguarded<R>(f: impl FnOnce() -> R) -> R {
+        let sp = SP.replace(0);
+        let result = f();
+        SP.set(sp);
+        result
+    }
+
+    fn install() {
+        let previous = SIGNALS.map(|signal| unsafe {
+            let mut previous: libc::sigaction = mem::zeroed();
+            libc::sigaction(signal, ptr::null(), &mut previous);
+            previous
+        });
+        // the previous handlers must be known before the new one can run
+        let _ = PREVIOUS.set(previous);
+        for signal in SIGNALS {
+            unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = handle as *const () as usize;
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_NODEFER;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(signal, &action, ptr::null_mut());
+            }
+        }
+    }
+
+    extern "C" fn handle(signal: libc::c_int, info: *mut libc::siginfo_t, uc: *mut libc::c_void) {
+        let (sp, base) = (SP.get(), BASE.get());
+        let addr = unsafe { (*info).si_addr() } as usize;
+        if sp != 0 && addr.wrapping_sub(base) < RESERVED_LEN {
+            let trap = tinywasm_guard_trap as *const () as usize;
+            unsafe { resume_at(uc as *mut libc::ucontext_t, trap, sp) };
+            return;
+        }
+
+        let Some(previous) = PREVIOUS.get() else {
+            return;
+        };
+        let previous = &previous[SIGNALS.iter().position(|s| *s == signal).unwrap_or(0)];
+        unsafe {
+            match previous.sa_sigaction {
+                libc::SIG_DFL | libc::SIG_IGN => {
+                    // the fault happens again once the previous action is back in place
+                    libc::sigaction(signal, previous, ptr::null_mut());
+                }
+                handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+                    let handler: extern "C" fn(
+                        libc::c_int,
+                        *mut libc::siginfo_t,
+                        *mut libc::c_void,
+                    ) = mem::transmute(handler);
+                    handler(signal, info, uc);
+                }
+                handler => {
+                    let handler: extern "C" fn(libc::c_int) = mem::transmute(handler);
+                    handler(signal);
+                }
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn resume_at(uc: *mut libc::ucontext_t, pc: usize, sp: usize) {
+        let gregs = unsafe { &mut (*uc).uc_mcontext.gregs };
+        gregs[libc::REG_RIP as usize] = pc as i64;
+        gregs[libc::REG_RSP as usize] = sp as i64;
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe fn resume_at(uc: *mut libc::ucontext_t, pc: usize, sp: usize) {
+        let mcontext = unsafe { &mut (*uc).uc_mcontext };
+        mcontext.pc = pc as u64;
+        mcontext.sp = sp as u64;
+    }
+}
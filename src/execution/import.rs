@@ -1,7 +1,35 @@
 use anyhow::Result;
-use std::collections::HashMap;
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
 
-use super::{store::Store, value::Value};
+use super::{caller::Caller, value::Value};
 
-pub type ImportFunc = Box<dyn FnMut(&mut Store, Vec<Value>) -> Result<Option<Value>>>;
-pub type Import = HashMap<String, HashMap<String, ImportFunc>>;
+pub type ImportFunc<T> = Arc<dyn Fn(Caller<'_, T>, Vec<Value>) -> Result<Vec<Value>> + Send + Sync>;
+
+// functions added with Runtime::add_import, by module and function name
+pub type Import<T> = HashMap<String, HashMap<String, ImportFunc<T>>>;
+
+pub type AsyncImportFunc<T> = Arc<
+    dyn for<'a> Fn(
+            Caller<'a, T>,
+            Vec<Value>,
+        ) -> Pin<Box<dyn Future<Output = Result<Vec<Value>>> + Send + 'a>>
+        + Send
+        + Sync,
+>;
+
+pub enum HostFunc<T> {
+    Sync(ImportFunc<T>),
+    Async(AsyncImportFunc<T>),
+    // has no implementation, calling it suspends a resumable invocation
+    Resumable,
+}
+
+impl<T> Clone for HostFunc<T> {
+    fn clone(&self) -> Self {
+        match self {
+            HostFunc::Sync(func) => HostFunc::Sync(func.clone()),
+            HostFunc::Async(func) => HostFunc::Async(func.clone()),
+            HostFunc::Resumable => HostFunc::Resumable,
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use super::{
+    caller::{Extern, Global, Memory, Table},
+    func::{Func, TypedFunc, WasmParams, WasmResults},
+    resumable::ResumableCall,
+    runtime::{check_args, Runtime},
+    value::Value,
+};
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instance {
+    pub(crate) idx: usize,
+}
+
+impl Instance {
+    pub fn get_export<T>(&self, runtime: &Runtime<T>, name: impl AsRef<str>) -> Option<Extern> {
+        let module = runtime.store.instances.get(self.idx)?;
+        module.exports.get(name.as_ref()).copied()
+    }
+
+    pub fn exports<T>(&self, runtime: &Runtime<T>) -> Vec<(String, Extern)> {
+        let Some(module) = runtime.store.instances.get(self.idx) else {
+            return vec![];
+        };
+        module
+            .exports
+            .iter()
+            .map(|(name, value)| (name.clone(), *value))
+            .collect()
+    }
+
+    pub fn get_func<T>(&self, runtime: &Runtime<T>, name: impl AsRef<str>) -> Result<Func> {
+        let name = name.as_ref();
+        self.get_export(runtime, name)
+            .and_then(Extern::into_func)
+            .ok_or(anyhow!("not found export function: {}", name))
+    }
+
+    pub fn get_table<T>(&self, runtime: &Runtime<T>, name: impl AsRef<str>) -> Result<Table> {
+        let name = name.as_ref();
+        self.get_export(runtime, name)
+            .and_then(Extern::into_table)
+            .ok_or(anyhow!("not found export table: {}", name))
+    }
+
+    pub fn get_memory<T>(&self, runtime: &Runtime<T>, name: impl AsRef<str>) -> Result<Memory> {
+        let name = name.as_ref();
+        self.get_export(runtime, name)
+            .and_then(Extern::into_memory)
+            .ok_or(anyhow!("not found export memory: {}", name))
+    }
+
+    pub fn get_global<T>(&self, runtime: &Runtime<T>, name: impl AsRef<str>) -> Result<Global> {
+        let name = name.as_ref();
+        self.get_export(runtime, name)
+            .and_then(Extern::into_global)
+            .ok_or(anyhow!("not found export global: {}", name))
+    }
+
+    pub fn get_typed_func<T, Params, Results>(
+        &self,
+        runtime: &Runtime<T>,
+        name: impl AsRef<str>,
+    ) -> Result<TypedFunc<Params, Results>>
+    where
+        Params: WasmParams,
+        Results: WasmResults,
+    {
+        self.get_func(runtime, name)?.typed(runtime)
+    }
+
+    pub fn call<T>(
+        &self,
+        runtime: &mut Runtime<T>,
+        name: impl AsRef<str>,
+        args: Vec<Value>,
+    ) -> Result<Vec<Value>> {
+        let name = name.as_ref();
+        let func = self.get_func(runtime, name)?;
+        check_args(name, func.func_type(runtime)?, &args)?;
+        runtime.invoke(func.idx, args)
+    }
+
+    pub async fn call_async<T>(
+        &self,
+        runtime: &mut Runtime<T>,
+        name: impl AsRef<str>,
+        args: Vec<Value>,
+    ) -> Result<Vec<Value>> {
+        let name = name.as_ref();
+        let func = self.get_func(runtime, name)?;
+        check_args(name, func.func_type(runtime)?, &args)?;
+        runtime.invoke_async(func.idx, args).await
+    }
+
+    pub fn call_resumable<T>(
+        &self,
+        runtime: &mut Runtime<T>,
+        name: impl AsRef<str>,
+        args: Vec<Value>,
+    ) -> Result<ResumableCall> {
+        let name = name.as_ref();
+        let func = self.get_func(runtime, name)?;
+        check_args(name, func.func_type(runtime)?, &args)?;
+        runtime.invoke_resumable(func.idx, args)
+    }
+}
@@ -0,0 +1,903 @@
+use std::{
+    any::Any,
+    mem::offset_of,
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+    sync::Arc,
+};
+
+use super::{
+    caller::Caller,
+    compile::{Op, Reg},
+    guard,
+    import::HostFunc,
+    instance::Instance,
+    runtime::{push_results, stack_bottom, take_values, unsupported_host_call, Runtime},
+    store::{Func, FuncInst},
+};
+use crate::binary::types::FuncType;
+use anyhow::{anyhow, bail, Result};
+use cranelift_codegen::{
+    ir::{
+        condcodes::IntCC, types, AbiParam, Block, InstBuilder, MemFlags, Signature, StackSlotData,
+        StackSlotKind, Type, Value,
+    },
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+
+// native calls nest on the machine stack, unlike the interpreter's frames
+pub(crate) const MAX_DEPTH: u64 = 10_000;
+
+// returned by native code, an error from a trampoline is left in the context
+const TRAP_UNREACHABLE: i64 = 1;
+pub(crate) const TRAP_OUT_OF_BOUNDS: i64 = 2;
+const TRAP_CALL_STACK_EXHAUSTED: i64 = 3;
+const TRAP_ERROR: u32 = 4;
+
+// native code takes the context and a buffer holding the arguments, which it overwrites
+// with the results, and returns 0 or a trap code
+pub(crate) type Entry = unsafe extern "C" fn(*mut Context, *mut u64) -> u32;
+type CallFn = unsafe extern "C" fn(*mut Context, u32, *mut u64) -> u32;
+type CallIndirectFn = unsafe extern "C" fn(*mut Context, u32, u32, u32, *mut u64) -> u32;
+
+// the fields read by native code come first, at the offsets it was compiled with
+#[repr(C)]
+pub(crate) struct Context {
+    mem_base: *mut u8,
+    mem_len: u64,
+    depth: u64,
+    call: CallFn,
+    call_indirect: CallIndirectFn,
+    runtime: *mut (),
+    instance: usize,
+    error: Option<anyhow::Error>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+// owns the executable memory of a module's native functions
+struct Code {
+    module: Option<JITModule>,
+}
+
+// the module is not changed after its functions are finalized
+unsafe impl Send for Code {}
+unsafe impl Sync for Code {}
+
+impl Drop for Code {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // every function pointing into the memory holds this code
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct NativeFunc {
+    _code: Arc<Code>,
+    entry: Entry,
+    // compiled without bounds checks, only runs on a guarded memory
+    guarded: bool,
+}
+
+fn supported(op: &Op) -> bool {
+    !matches!(op, Op::GlobalGet { .. } | Op::GlobalSet { .. })
+}
+
+// compiles the functions whose ops all have a native form, the others stay interpreted
+pub(crate) fn compile_module(
+    funcs: &mut [(FuncType, Func)],
+    func_types: &[FuncType],
+    callees: &[FuncType],
+    guarded: bool,
+) -> Result<()> {
+    let compiled: Vec<bool> = funcs
+        .iter()
+        .map(|(_, func)| func.ops.iter().all(supported))
+        .collect();
+    if !compiled.contains(&true) {
+        return Ok(());
+    }
+
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed")?;
+    let isa = cranelift_native::builder()
+        .map_err(|e| anyhow!("unsupported host: {}", e))?
+        .finish(settings::Flags::new(flags))?;
+    let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+    let ptr_type = module.target_config().pointer_type();
+    let mut signature = module.make_signature();
+    signature.params.push(AbiParam::new(ptr_type));
+    signature.params.push(AbiParam::new(ptr_type));
+    signature.returns.push(AbiParam::new(types::I32));
+
+    let mut ids = vec![];
+    for (idx, compiled) in compiled.iter().enumerate() {
+        ids.push(match compiled {
+            true => {
+                Some(module.declare_function(&format!("func{idx}"), Linkage::Local, &signature)?)
+            }
+            false => None,
+        });
+    }
+
+    // calls to imports and to interpreted functions go through the runtime
+    let types = ModuleTypes {
+        func_types,
+        callees,
+        imported: callees.len() - funcs.len(),
+    };
+    let mut ctx = module.make_context();
+    let mut builder_ctx = FunctionBuilderContext::new();
+    for (idx, (func_type, func)) in funcs.iter().enumerate() {
+        let Some(id) = ids[idx] else {
+            continue;
+        };
+        ctx.func.signature = signature.clone();
+        let translator = Translator {
+            builder: FunctionBuilder::new(&mut ctx.func, &mut builder_ctx),
+            module: &mut module,
+            ptr_type,
+            blocks: vec![],
+            vmctx: None,
+            regs: None,
+            buf: None,
+            guarded,
+        };
+        translator.translate(func_type, func, &types, &ids)?;
+        module.define_function(id, &mut ctx)?;
+        module.clear_context(&mut ctx);
+    }
+    module.finalize_definitions()?;
+
+    let entries: Vec<_> = ids
+        .iter()
+        .map(|id| id.map(|id| module.get_finalized_function(id)))
+        .collect();
+    let code = Arc::new(Code {
+        module: Some(module),
+    });
+    for ((_, func), entry) in funcs.iter_mut().zip(entries) {
+        if let Some(entry) = entry {
+            func.native = Some(NativeFunc {
+                _code: code.clone(),
+                entry: unsafe { std::mem::transmute::<*const u8, Entry>(entry) },
+                guarded,
+            });
+        }
+    }
+    Ok(())
+}
+
+struct ModuleTypes<'a> {
+    func_types: &'a [FuncType],
+    callees: &'a [FuncType],
+    imported: usize,
+}
+
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    module: &'a mut JITModule,
+    ptr_type: Type,
+    // one block per op, and one for the end
+    blocks: Vec<Block>,
+    vmctx: Option<Value>,
+    regs: Option<Value>,
+    // the arguments and results of calls
+    buf: Option<Value>,
+    // accesses past the end of the memory fault in its guard region
+    guarded: bool,
+}
+
+impl Translator<'_> {
+    fn translate(
+        mut self,
+        func_type: &FuncType,
+        func: &Func,
+        module: &ModuleTypes,
+        ids: &[Option<FuncId>],
+    ) -> Result<()> {
+        let entry = self.builder.create_block();
+        self.builder.append_block_params_for_function_params(entry);
+        self.blocks = (0..=func.ops.len())
+            .map(|_| self.builder.create_block())
+            .collect();
+        self.builder.switch_to_block(entry);
+        let params = self.builder.block_params(entry);
+        let (vmctx, regs) = (params[0], params[1]);
+        self.vmctx = Some(vmctx);
+        self.regs = Some(regs);
+
+        for reg in 0..func.reg_count {
+            let var = Variable::from_u32(reg as u32);
+            self.builder.declare_var(var, types::I64);
+            let value = if reg < func_type.params.len() {
+                self.builder
+                    .ins()
+                    .load(types::I64, MemFlags::trusted(), regs, 8 * reg as i32)
+            } else {
+                self.builder.ins().iconst(types::I64, 0)
+            };
+            self.builder.def_var(var, value);
+        }
+
+        let mut buf_len = 0;
+        for op in &func.ops {
+            let callee = match op {
+                Op::Call { func, .. } => &module.callees[*func as usize],
+                Op::CallIndirect { type_idx, .. } => &module.func_types[*type_idx as usize],
+                _ => continue,
+            };
+            buf_len = buf_len.max(callee.params.len().max(callee.results.len()));
+        }
+        let slot = self.builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            (8 * buf_len.max(1)) as u32,
+            3,
+        ));
+        self.buf = Some(self.builder.ins().stack_addr(self.ptr_type, slot, 0));
+
+        // the depth is given back by every return, a trap abandons the whole call
+        let depth = self.load_ctx(types::I64, offset_of!(Context, depth));
+        let body = self.builder.create_block();
+        let exhausted = self.builder.create_block();
+        self.builder.ins().brif(depth, body, &[], exhausted, &[]);
+        self.builder.switch_to_block(exhausted);
+        self.trap(TRAP_CALL_STACK_EXHAUSTED);
+        self.builder.switch_to_block(body);
+        let depth = self.builder.ins().iadd_imm(depth, -1);
+        self.store_ctx(depth, offset_of!(Context, depth));
+        self.builder.ins().jump(self.blocks[0], &[]);
+
+        for (pc, op) in func.ops.iter().enumerate() {
+            self.builder.switch_to_block(self.blocks[pc]);
+            let next = self.blocks[pc + 1];
+            let falls_through = self.translate_op(op, pc, func_type, module, ids)?;
+            if falls_through {
+                self.builder.ins().jump(next, &[]);
+            }
+        }
+        // the compiled code always returns before its end
+        self.builder.switch_to_block(self.blocks[func.ops.len()]);
+        self.trap(TRAP_UNREACHABLE);
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+        Ok(())
+    }
+
+    // returns whether control continues with the next op
+    fn translate_op(
+        &mut self,
+        op: &Op,
+        pc: usize,
+        func_type: &FuncType,
+        module: &ModuleTypes,
+        ids: &[Option<FuncId>],
+    ) -> Result<bool> {
+        let next = self.blocks[pc + 1];
+        match op {
+            Op::Unreachable => {
+                self.trap(TRAP_UNREACHABLE);
+                return Ok(false);
+            }
+            Op::Nop => {}
+            Op::Copy { dst, src } => {
+                let value = self.get(*src);
+                self.set(*dst, value);
+            }
+            Op::Const { dst, value } => {
+                let value = self.builder.ins().iconst(types::I64, *value as i64);
+                self.set(*dst, value);
+            }
+            Op::GlobalGet { .. } | Op::GlobalSet { .. } => bail!("unsupported op {:?}", op),
+            Op::I32Store {
+                addr,
+                value,
+                offset,
+            } => {
+                let value = self.get_i32(*value);
+                let addr = self.get_i32(*addr);
+                let addr = self.builder.ins().uextend(types::I64, addr);
+                let at = self.builder.ins().iadd_imm(addr, i64::from(*offset));
+                if !self.guarded {
+                    let end = self.builder.ins().iadd_imm(at, 4);
+                    let len = self.load_ctx(types::I64, offset_of!(Context, mem_len));
+                    let out_of_bounds =
+                        self.builder
+                            .ins()
+                            .icmp(IntCC::UnsignedGreaterThan, end, len);
+                    let (store, trap) = (self.builder.create_block(), self.builder.create_block());
+                    self.builder
+                        .ins()
+                        .brif(out_of_bounds, trap, &[], store, &[]);
+                    self.builder.switch_to_block(trap);
+                    self.trap(TRAP_OUT_OF_BOUNDS);
+                    self.builder.switch_to_block(store);
+                }
+                let base = self.load_ctx(self.ptr_type, offset_of!(Context, mem_base));
+                let at = self.builder.ins().iadd(base, at);
+                self.builder.ins().store(MemFlags::new(), value, at, 0);
+            }
+            Op::I32Add { dst, lhs, rhs } => {
+                let (lhs, rhs) = (self.get_i32(*lhs), self.get_i32(*rhs));
+                let value = self.builder.ins().iadd(lhs, rhs);
+                self.set_i32(*dst, value);
+            }
+            Op::I32Sub { dst, lhs, rhs } => {
+                let (lhs, rhs) = (self.get_i32(*lhs), self.get_i32(*rhs));
+                let value = self.builder.ins().isub(lhs, rhs);
+                self.set_i32(*dst, value);
+            }
+            Op::I32Lts { dst, lhs, rhs } => {
+                let (lhs, rhs) = (self.get_i32(*lhs), self.get_i32(*rhs));
+                let value = self.builder.ins().icmp(IntCC::SignedLessThan, lhs, rhs);
+                let value = self.builder.ins().uextend(types::I64, value);
+                self.set(*dst, value);
+            }
+            Op::I32AddImm { dst, lhs, imm } => {
+                let lhs = self.get_i32(*lhs);
+                let value = self.builder.ins().iadd_imm(lhs, i64::from(*imm));
+                self.set_i32(*dst, value);
+            }
+            Op::I32SubImm { dst, lhs, imm } => {
+                let lhs = self.get_i32(*lhs);
+                let value = self.builder.ins().iadd_imm(lhs, -i64::from(*imm));
+                self.set_i32(*dst, value);
+            }
+            Op::I32LtsImm { dst, lhs, imm } => {
+                let lhs = self.get_i32(*lhs);
+                let value =
+                    self.builder
+                        .ins()
+                        .icmp_imm(IntCC::SignedLessThan, lhs, i64::from(*imm));
+                let value = self.builder.ins().uextend(types::I64, value);
+                self.set(*dst, value);
+            }
+            Op::Jump { target } => {
+                self.builder.ins().jump(self.blocks[*target as usize], &[]);
+                return Ok(false);
+            }
+            Op::JumpIfZero { cond, target } => {
+                let cond = self.get_i32(*cond);
+                let target = self.blocks[*target as usize];
+                self.builder.ins().brif(cond, next, &[], target, &[]);
+                return Ok(false);
+            }
+            Op::JumpIfNonZero { cond, target } => {
+                let cond = self.get_i32(*cond);
+                let target = self.blocks[*target as usize];
+                self.builder.ins().brif(cond, target, &[], next, &[]);
+                return Ok(false);
+            }
+            Op::JumpIfLts { lhs, rhs, target } | Op::JumpIfNotLts { lhs, rhs, target } => {
+                let (lhs, rhs) = (self.get_i32(*lhs), self.get_i32(*rhs));
+                let cond = self.builder.ins().icmp(IntCC::SignedLessThan, lhs, rhs);
+                self.branch(cond, matches!(op, Op::JumpIfLts { .. }), *target, next);
+                return Ok(false);
+            }
+            Op::JumpIfLtsImm { lhs, imm, target } | Op::JumpIfNotLtsImm { lhs, imm, target } => {
+                let lhs = self.get_i32(*lhs);
+                let cond = self
+                    .builder
+                    .ins()
+                    .icmp_imm(IntCC::SignedLessThan, lhs, i64::from(*imm));
+                self.branch(cond, matches!(op, Op::JumpIfLtsImm { .. }), *target, next);
+                return Ok(false);
+            }
+            Op::Call { func, args } => {
+                let callee = &module.callees[*func as usize];
+                self.store_args(*args, callee.params.len());
+                let (vmctx, buf) = (self.vmctx(), self.buf());
+                let own = (*func as usize).checked_sub(module.imported);
+                let code = match own.and_then(|idx| ids[idx]) {
+                    Some(id) => {
+                        let callee = self.module.declare_func_in_func(id, self.builder.func);
+                        let call = self.builder.ins().call(callee, &[vmctx, buf]);
+                        self.builder.inst_results(call)[0]
+                    }
+                    None => {
+                        let signature = self.signature(&[types::I32]);
+                        let trampoline = self.load_ctx(self.ptr_type, offset_of!(Context, call));
+                        let func = self.builder.ins().iconst(types::I32, i64::from(*func));
+                        let call = self.builder.ins().call_indirect(
+                            signature,
+                            trampoline,
+                            &[vmctx, func, buf],
+                        );
+                        self.builder.inst_results(call)[0]
+                    }
+                };
+                self.check_trap(code);
+            }
+            Op::CallIndirect {
+                type_idx,
+                table_idx,
+                elem,
+                args,
+            } => {
+                let params = module.func_types[*type_idx as usize].params.len();
+                self.store_args(*args, params);
+                let (vmctx, buf) = (self.vmctx(), self.buf());
+                let signature = self.signature(&[types::I32, types::I32, types::I32]);
+                let trampoline = self.load_ctx(self.ptr_type, offset_of!(Context, call_indirect));
+                let type_idx = self.builder.ins().iconst(types::I32, i64::from(*type_idx));
+                let table_idx = self.builder.ins().iconst(types::I32, i64::from(*table_idx));
+                let elem = self.get_i32(*elem);
+                let call = self.builder.ins().call_indirect(
+                    signature,
+                    trampoline,
+                    &[vmctx, type_idx, table_idx, elem, buf],
+                );
+                let code = self.builder.inst_results(call)[0];
+                self.check_trap(code);
+            }
+            Op::TakeResults { dst, count } => {
+                let buf = self.buf();
+                for offset in 0..*count {
+                    let value = self.builder.ins().load(
+                        types::I64,
+                        MemFlags::trusted(),
+                        buf,
+                        8 * offset as i32,
+                    );
+                    self.set(dst + offset, value);
+                }
+            }
+            Op::Return { src } => {
+                let regs = self.regs();
+                for offset in 0..func_type.results.len() as u32 {
+                    let value = self.get(src + offset);
+                    self.builder
+                        .ins()
+                        .store(MemFlags::trusted(), value, regs, 8 * offset as i32);
+                }
+                let depth = self.load_ctx(types::I64, offset_of!(Context, depth));
+                let depth = self.builder.ins().iadd_imm(depth, 1);
+                self.store_ctx(depth, offset_of!(Context, depth));
+                let ok = self.builder.ins().iconst(types::I32, 0);
+                self.builder.ins().return_(&[ok]);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn vmctx(&self) -> Value {
+        self.vmctx.expect("entry block is translated first")
+    }
+
+    fn regs(&self) -> Value {
+        self.regs.expect("entry block is translated first")
+    }
+
+    fn buf(&self) -> Value {
+        self.buf.expect("entry block is translated first")
+    }
+
+    fn get(&mut self, reg: Reg) -> Value {
+        self.builder.use_var(Variable::from_u32(reg))
+    }
+
+    fn set(&mut self, reg: Reg, value: Value) {
+        self.builder.def_var(Variable::from_u32(reg), value);
+    }
+
+    // an i32 is kept zero extended in the low bits, as in the interpreter's registers
+    fn get_i32(&mut self, reg: Reg) -> Value {
+        let value = self.get(reg);
+        self.builder.ins().ireduce(types::I32, value)
+    }
+
+    fn set_i32(&mut self, reg: Reg, value: Value) {
+        let value = self.builder.ins().uextend(types::I64, value);
+        self.set(reg, value);
+    }
+
+    fn load_ctx(&mut self, ty: Type, offset: usize) -> Value {
+        let vmctx = self.vmctx();
+        self.builder
+            .ins()
+            .load(ty, MemFlags::trusted(), vmctx, offset as i32)
+    }
+
+    fn store_ctx(&mut self, value: Value, offset: usize) {
+        let vmctx = self.vmctx();
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, vmctx, offset as i32);
+    }
+
+    fn store_args(&mut self, args: Reg, count: usize) {
+        let buf = self.buf();
+        for offset in 0..count as u32 {
+            let value = self.get(args + offset);
+            self.builder
+                .ins()
+                .store(MemFlags::trusted(), value, buf, 8 * offset as i32);
+        }
+    }
+
+    fn branch(&mut self, cond: Value, if_true: bool, target: u32, next: Block) {
+        let target = self.blocks[target as usize];
+        let (then, otherwise) = if if_true {
+            (target, next)
+        } else {
+            (next, target)
+        };
+        self.builder.ins().brif(cond, then, &[], otherwise, &[]);
+    }
+
+    fn trap(&mut self, code: i64) {
+        let code = self.builder.ins().iconst(types::I32, code);
+        self.builder.ins().return_(&[code]);
+    }
+
+    // a trap in a callee is passed on to the caller
+    fn check_trap(&mut self, code: Value) {
+        let (trap, ok) = (self.builder.create_block(), self.builder.create_block());
+        self.builder.ins().brif(code, trap, &[], ok, &[]);
+        self.builder.switch_to_block(trap);
+        self.builder.ins().return_(&[code]);
+        self.builder.switch_to_block(ok);
+    }
+
+    // the signature of a trampoline taking these values between the context and the buffer
+    fn signature(&mut self, values: &[Type]) -> cranelift_codegen::ir::SigRef {
+        let mut signature = Signature::new(self.module.isa().default_call_conv());
+        signature.params.push(AbiParam::new(self.ptr_type));
+        for value in values {
+            signature.params.push(AbiParam::new(*value));
+        }
+        signature.params.push(AbiParam::new(self.ptr_type));
+        signature.returns.push(AbiParam::new(types::I32));
+        self.builder.import_signature(signature)
+    }
+}
+
+impl Context {
+    // memory may have grown or moved while the runtime ran a call for native code
+    fn refresh_memory<T>(&mut self, runtime: &mut Runtime<T>) {
+        let module = &runtime.store.instances[self.instance];
+        (self.mem_base, self.mem_len) = match module.mem_addrs.first() {
+            Some(addr) => {
+                let data = &mut runtime.store.memories[*addr].data;
+                (data.as_mut_ptr(), data.len() as u64)
+            }
+            None => (ptr::null_mut(), 0),
+        };
+    }
+
+    // a panic is carried over the native frames and resumed once they have returned
+    fn finish<T>(
+        &mut self,
+        runtime: &mut Runtime<T>,
+        result: std::thread::Result<Result<()>>,
+    ) -> u32 {
+        self.refresh_memory(runtime);
+        match result {
+            Ok(Ok(())) => 0,
+            Ok(Err(e)) => {
+                self.error = Some(e);
+                TRAP_ERROR
+            }
+            Err(payload) => {
+                self.panic = Some(payload);
+                TRAP_ERROR
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn call_trampoline<T>(ctx: *mut Context, func: u32, buf: *mut u64) -> u32 {
+    let ctx = unsafe { &mut *ctx };
+    let runtime = unsafe { &mut *(ctx.runtime as *mut Runtime<T>) };
+    let result = guard::unguarded(|| {
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let module = &runtime.store.instances[ctx.instance];
+            let Some(addr) = module.func_addrs.get(func as usize) else {
+                bail!("not found func");
+            };
+            runtime.call_from_native(*addr, ctx.instance, ctx.depth, buf)
+        }))
+    });
+    ctx.finish(runtime, result)
+}
+
+unsafe extern "C" fn call_indirect_trampoline<T>(
+    ctx: *mut Context,
+    type_idx: u32,
+    table_idx: u32,
+    elem: u32,
+    buf: *mut u64,
+) -> u32 {
+    let ctx = unsafe { &mut *ctx };
+    let runtime = unsafe { &mut *(ctx.runtime as *mut Runtime<T>) };
+    let result = guard::unguarded(|| {
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let module = &runtime.store.instances[ctx.instance];
+            let Some(addr) = module.table_addrs.get(table_idx as usize) else {
+                bail!("not found table");
+            };
+            let table = &runtime.store.tables[*addr];
+            let Some(elem) = table.elem.get(elem as usize) else {
+                bail!("undefined element");
+            };
+            let Some(idx) = *elem else {
+                bail!("uninitialized element");
+            };
+            let Some(func) = runtime.store.funcs.get(idx) else {
+                bail!("not found func");
+            };
+            if module.func_types.get(type_idx as usize) != Some(func.func_type()) {
+                bail!("indirect call type mismatch");
+            }
+            runtime.call_from_native(idx, ctx.instance, ctx.depth, buf)
+        }))
+    });
+    ctx.finish(runtime, result)
+}
+
+impl<T> Runtime<T> {
+    // code compiled for a guarded memory is interpreted when the instance's memory is not
+    pub(crate) fn has_native(&self, idx: usize) -> bool {
+        let Some(FuncInst::Internal(func)) = self.store.funcs.get(idx) else {
+            return false;
+        };
+        let Some(native) = func.code().ok().and_then(|code| code.native.clone()) else {
+            return false;
+        };
+        !native.guarded || {
+            let module = &self.store.instances[func.module];
+            module
+                .mem_addrs
+                .first()
+                .is_none_or(|addr| self.store.memories[*addr].data.is_guarded())
+        }
+    }
+
+    // runs native code with the arguments on top of the value stack, which are replaced
+    // by the results, as a frame of the interpreter would leave them
+    pub(crate) fn invoke_native(&mut self, idx: usize, depth: u64) -> Result<()> {
+        let Some(FuncInst::Internal(func)) = self.store.funcs.get(idx) else {
+            bail!("not found func");
+        };
+        let code = func.code()?;
+        let Some(native) = &code.native else {
+            bail!("not found native code");
+        };
+        let (entry, guarded) = (native.entry, native.guarded);
+        let (params, results) = (func.func_type.params.len(), func.func_type.results.len());
+        let mut ctx = Context {
+            mem_base: ptr::null_mut(),
+            mem_len: 0,
+            depth,
+            call: call_trampoline::<T>,
+            call_indirect: call_indirect_trampoline::<T>,
+            runtime: ptr::null_mut(),
+            instance: func.module,
+            error: None,
+            panic: None,
+        };
+        ctx.refresh_memory(self);
+
+        let bottom = stack_bottom(&self.stack, params)?;
+        let mut buf = self.stack.split_off(bottom);
+        buf.resize(params.max(results), 0);
+        ctx.runtime = self as *mut Self as *mut ();
+        let code = match guarded {
+            true => unsafe { guard::call(entry, &mut ctx, buf.as_mut_ptr(), ctx.mem_base) },
+            false => unsafe { entry(&mut ctx, buf.as_mut_ptr()) },
+        };
+        if let Some(payload) = ctx.panic.take() {
+            panic::resume_unwind(payload);
+        }
+
+        match i64::from(code) {
+            0 => {}
+            TRAP_UNREACHABLE => bail!("unreachable"),
+            TRAP_OUT_OF_BOUNDS => bail!("out of bounds memory access"),
+            TRAP_CALL_STACK_EXHAUSTED => bail!("call stack exhausted"),
+            _ => {
+                return Err(ctx.error.take().unwrap_or_else(|| anyhow!("unknown trap")));
+            }
+        }
+        buf.truncate(results);
+        self.stack.extend(buf);
+        Ok(())
+    }
+
+    // native code calls any function that is not its module's native code through here
+    fn call_from_native(
+        &mut self,
+        idx: usize,
+        instance: usize,
+        depth: u64,
+        buf: *mut u64,
+    ) -> Result<()> {
+        let func_type = self.store.funcs[idx].func_type().clone();
+        let (params, results) = (func_type.params.len(), func_type.results.len());
+        // the buffer is as large as the arguments and results of every call made with it
+        let buf = unsafe { slice::from_raw_parts_mut(buf, params.max(results)) };
+        self.stack.extend_from_slice(&buf[..params]);
+
+        match &self.store.funcs[idx] {
+            FuncInst::Internal(_) if self.has_native(idx) => self.invoke_native(idx, depth)?,
+            FuncInst::Internal(_) => {
+                let base = self.call_stack.len();
+                self.interpret(idx, base)?;
+            }
+            FuncInst::External(func) => {
+                let func = func.clone();
+                let HostFunc::Sync(host) = &func.host else {
+                    return Err(unsupported_host_call(&func));
+                };
+                let args = take_values(&mut self.stack, &func_type.params)?;
+                let caller = Caller::new(self, Some(Instance { idx: instance }));
+                let values = host(caller, args)?;
+                push_results(&mut self.stack, &func_type.results, &values)?;
+            }
+        }
+
+        let bottom = stack_bottom(&self.stack, results)?;
+        buf[..results].copy_from_slice(&self.stack[bottom..]);
+        self.stack.truncate(bottom);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::execution::{
+        caller::Caller, config::Config, linker::Linker, module::Module, runtime::Runtime,
+        value::Value,
+    };
+    use anyhow::Result;
+
+    fn instantiate(wasm: &[u8], jit: bool) -> Result<Runtime> {
+        instantiate_with_config(wasm, Config::new().jit(jit))
+    }
+
+    fn instantiate_with_config(wasm: &[u8], config: &Config) -> Result<Runtime> {
+        let module = Module::new_with_config(wasm, config)?;
+        let mut runtime = Runtime::new(());
+        let mut linker = Linker::new();
+        linker.func("env", "fail", |_: Caller<'_, ()>| -> Result<()> {
+            anyhow::bail!("host failure")
+        })?;
+        linker.instantiate(&mut runtime, &module)?;
+        Ok(runtime)
+    }
+
+    #[test]
+    fn native_code_matches_interpreter() -> Result<()> {
+        let tests = vec![
+            ("fib.wat", "fib", vec![Value::I32(20)]),
+            ("fib.wat", "fib", vec![Value::I32(-1)]),
+            (
+                "multi_value.wat",
+                "call_swap",
+                vec![Value::I32(1), Value::I32(2)],
+            ),
+            (
+                "multi_value.wat",
+                "block_param",
+                vec![Value::I32(5), Value::I32(3)],
+            ),
+            ("multi_value.wat", "br_results", vec![]),
+            ("multi_value.wat", "if_else", vec![Value::I32(1)]),
+            ("multi_value.wat", "loop_sum", vec![Value::I32(100)]),
+            ("multi_value.wat", "return_results", vec![]),
+            ("trap.wat", "answer", vec![]),
+            ("trap.wat", "store", vec![Value::I32(65532)]),
+            ("trap.wat", "store", vec![Value::I32(65533)]),
+            ("trap.wat", "store", vec![Value::I32(-1)]),
+            ("trap.wat", "store_then_trap", vec![]),
+            ("trap.wat", "nested", vec![]),
+            ("trap.wat", "undefined_element", vec![]),
+            ("trap.wat", "call_fail", vec![]),
+        ];
+        for (file, name, args) in tests {
+            let wasm = wat::parse_file(format!("src/fixtures/{file}"))?;
+            let mut outcomes = vec![];
+            for jit in [true, false] {
+                let mut runtime = instantiate(&wasm, jit)?;
+                let idx = runtime.get_func(name)?.idx;
+                assert_eq!(runtime.has_native(idx), jit, "{name}");
+                let result = runtime.call(name, args.clone());
+                let memory = runtime.get_memory("memory").ok();
+                let data = memory.map(|memory| memory.data(&runtime).to_vec());
+                outcomes.push((result.map_err(|e| e.to_string()), data));
+                assert!(runtime.stack.is_empty(), "{name}");
+                assert!(runtime.call_stack.is_empty(), "{name}");
+            }
+            assert_eq!(outcomes[0], outcomes[1], "{name}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fuel_is_counted_by_the_interpreter() -> Result<()> {
+        let wasm = wat::parse_file("src/fixtures/fib.wat")?;
+        let mut runtime = instantiate(&wasm, true)?;
+        runtime.set_fuel(10_000);
+        assert_eq!(
+            runtime.call("fib", vec![Value::I32(10)])?,
+            vec![Value::I32(89)]
+        );
+        assert_eq!(runtime.fuel(), Some(10_000 - 1854));
+        Ok(())
+    }
+
+    #[test]
+    fn exhaust_call_stack() -> Result<()> {
+        let wasm = wat::parse_str("(module (func $f (export \"f\") (call $f)))")?;
+        let mut runtime = instantiate(&wasm, true)?;
+        let result = runtime.call("f", vec![]);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "failed to execute instructions: call stack exhausted"
+        );
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn guard_pages_trap() -> Result<()> {
+        let wasm = wat::parse_file("src/fixtures/trap.wat")?;
+        let mut runtime = instantiate_with_config(&wasm, Config::new().mmap_memory(true))?;
+        let memory = runtime.get_memory("memory")?;
+        assert!(runtime.has_native(runtime.get_func("store")?.idx));
+
+        for addr in [65533, -1] {
+            let result = runtime.call("store", vec![Value::I32(addr)]);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "failed to execute instructions: out of bounds memory access"
+            );
+        }
+
+        // the grown page is committed in place, so the same code reaches it
+        let base = memory.data(&runtime).as_ptr();
+        assert_eq!(memory.grow(&mut runtime, 1)?, 1);
+        assert_eq!(memory.data(&runtime).as_ptr(), base);
+        runtime.call("store", vec![Value::I32(65533)])?;
+        assert_eq!(&memory.data(&runtime)[65533..65537], &7i32.to_le_bytes());
+        let result = runtime.call("store", vec![Value::I32(131069)]);
+        assert!(result.is_err());
+        assert!(runtime.stack.is_empty());
+        assert!(runtime.call_stack.is_empty());
+
+        // the widest access starts just below twice the largest memory
+        let far = r#"
+            (module
+              (memory 1)
+              (func (export "far")
+                (i32.store offset=0xffffffff (i32.const 0xffffffff) (i32.const 7))))
+        "#;
+        let mut far =
+            instantiate_with_config(&wat::parse_str(far)?, Config::new().mmap_memory(true))?;
+        assert!(far.has_native(far.get_func("far")?.idx));
+        assert_eq!(
+            far.call("far", vec![]).unwrap_err().to_string(),
+            "failed to execute instructions: out of bounds memory access"
+        );
+
+        // code without bounds checks is interpreted on a vector backed memory
+        let mut runtime = instantiate_with_config(&wasm, Config::new().mmap_memory(true))?;
+        let idx = runtime.get_func("store")?.idx;
+        let addr = runtime.get_memory("memory")?.idx;
+        runtime.store.memories[addr].data = vec![0; 65536].into();
+        assert!(!runtime.has_native(idx));
+        let result = runtime.call("store", vec![Value::I32(65533)]);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "failed to execute instructions: out of bounds memory access"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+
+use super::{
+    caller::{Caller, Extern},
+    func::{IntoFunc, WasmResults, WasmRet},
+    import::{AsyncImportFunc, HostFunc},
+    instance::Instance,
+    module::Module,
+    runtime::Runtime,
+    value::Value,
+};
+use crate::binary::types::FuncType;
+use anyhow::{bail, Result};
+
+enum Definition<T> {
+    Func(FuncType, HostFunc<T>),
+    Extern(Extern),
+}
+
+pub struct Linker<T = ()> {
+    definitions: HashMap<String, HashMap<String, Definition<T>>>,
+}
+
+impl<T> Default for Linker<T> {
+    fn default() -> Self {
+        Self {
+            definitions: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Linker<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&mut self, module: String, name: String, definition: Definition<T>) -> Result<()> {
+        let definitions = self.definitions.entry(module.clone()).or_default();
+        if definitions.contains_key(&name) {
+            bail!("import {}.{} defined twice", module, name);
+        }
+        definitions.insert(name, definition);
+        Ok(())
+    }
+
+    pub fn func<Params, Results>(
+        &mut self,
+        module: impl Into<String>,
+        name: impl Into<String>,
+        func: impl IntoFunc<T, Params, Results>,
+    ) -> Result<&mut Self> {
+        let (func_type, func) = func.into_func();
+        self.insert(
+            module.into(),
+            name.into(),
+            Definition::Func(func_type, HostFunc::Sync(func)),
+        )?;
+        Ok(self)
+    }
+
+    // the returned future may hold the caller, the guest is suspended until it resolves
+    pub fn func_async<Params, Results, F>(
+        &mut self,
+        module: impl Into<String>,
+        name: impl Into<String>,
+        func: F,
+    ) -> Result<&mut Self>
+    where
+        F: for<'a> Fn(Caller<'a, T>, Params) -> Box<dyn Future<Output = Results> + Send + 'a>
+            + Send
+            + Sync
+            + 'static,
+        Params: WasmResults + 'static,
+        Results: WasmRet + 'static,
+    {
+        let func_type = FuncType {
+            params: <Params as WasmResults>::value_types(),
+            results: Results::value_types(),
+        };
+        let func = async_func(move |caller, args| match Params::from_values(args) {
+            Ok(params) => {
+                let future = Box::into_pin(func(caller, params));
+                Box::pin(async move { future.await.into_values() })
+            }
+            Err(e) => Box::pin(async move { Err(e) }),
+        });
+        self.insert(
+            module.into(),
+            name.into(),
+            Definition::Func(func_type, HostFunc::Async(func)),
+        )?;
+        Ok(self)
+    }
+
+    // calls to the function suspend call_resumable until the embedder resumes with the results
+    pub fn resumable_func(
+        &mut self,
+        module: impl Into<String>,
+        name: impl Into<String>,
+        func_type: FuncType,
+    ) -> Result<&mut Self> {
+        self.insert(
+            module.into(),
+            name.into(),
+            Definition::Func(func_type, HostFunc::Resumable),
+        )?;
+        Ok(self)
+    }
+
+    pub fn define(
+        &mut self,
+        module: impl Into<String>,
+        name: impl Into<String>,
+        value: Extern,
+    ) -> Result<&mut Self> {
+        self.insert(module.into(), name.into(), Definition::Extern(value))?;
+        Ok(self)
+    }
+
+    // makes every export of the instance importable under the module name
+    pub fn instance(
+        &mut self,
+        runtime: &Runtime<T>,
+        module: impl Into<String>,
+        instance: Instance,
+    ) -> Result<&mut Self> {
+        let module = module.into();
+        for (name, value) in instance.exports(runtime) {
+            self.insert(module.clone(), name, Definition::Extern(value))?;
+        }
+        Ok(self)
+    }
+
+    pub fn instantiate(&self, runtime: &mut Runtime<T>, module: &Module) -> Result<Instance> {
+        // the host functions allocated for the imports go away with a failed instantiation
+        let funcs = runtime.store.funcs.len();
+        self.resolve(runtime, module)
+            .inspect_err(|_| runtime.store.funcs.truncate(funcs))
+    }
+
+    fn resolve(&self, runtime: &mut Runtime<T>, module: &Module) -> Result<Instance> {
+        let mut imports = vec![];
+        for import in module.imports() {
+            let Some(definition) = self
+                .definitions
+                .get(&import.module)
+                .and_then(|definitions| definitions.get(&import.field))
+            else {
+                bail!("unknown import: {}.{}", import.module, import.field);
+            };
+            let value = match definition {
+                Definition::Func(func_type, func) => {
+                    let func = runtime.store.alloc_host_func(
+                        &import.module,
+                        &import.field,
+                        func_type.clone(),
+                        func.clone(),
+                    );
+                    Extern::Func(func)
+                }
+                Definition::Extern(value) => *value,
+            };
+            imports.push(value);
+        }
+
+        let idx = runtime.store.instantiate(module, imports)?;
+        Ok(Instance { idx })
+    }
+}
+
+fn async_func<T, F>(func: F) -> AsyncImportFunc<T>
+where
+    F: for<'a> Fn(
+            Caller<'a, T>,
+            Vec<Value>,
+        ) -> Pin<Box<dyn Future<Output = Result<Vec<Value>>> + Send + 'a>>
+        + Send
+        + Sync
+        + 'static,
+{
+    Arc::new(func)
+}
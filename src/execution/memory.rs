@@ -0,0 +1,462 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+use super::store::{MAX_PAGES, PAGE_SIZE};
+use anyhow::{bail, Result};
+
+// the largest memory, 4GiB
+pub const MAX_LEN: usize = MAX_PAGES as usize * PAGE_SIZE as usize;
+// the address space of a mapped memory, a 32 bit address plus a 32 bit offset stays
+// below twice the largest memory, the access itself may reach a few bytes further into
+// the extra page
+pub const RESERVED_LEN: usize = 2 * MAX_LEN + PAGE_SIZE as usize;
+
+// the bytes of a linear memory, held in a vector or, on linux, in a reservation that
+// never moves when the memory grows
+#[derive(Default)]
+pub struct LinearMemory {
+    storage: Storage,
+}
+
+enum Storage {
+    Vec(Vec<u8>),
+    #[cfg(target_os = "linux")]
+    Mmap(mmap::Mmap),
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage::Vec(vec![])
+    }
+}
+
+impl LinearMemory {
+    pub fn new(len: usize) -> Self {
+        Self {
+            storage: Storage::Vec(vec![0; len]),
+        }
+    }
+
+    // reserves the whole 32 bit address space followed by a guard region, only the first
+    // len bytes are accessible
+    #[cfg(target_os = "linux")]
+    pub fn mmap(len: usize) -> Result<Self> {
+        Ok(Self {
+            storage: Storage::Mmap(mmap::Mmap::new(len)?),
+        })
+    }
+
+    // like mmap, with the image mapped copy-on-write at the start of the memory
+    #[cfg(target_os = "linux")]
+    pub fn mmap_image(image: &MemoryImage, len: usize) -> Result<Self> {
+        Ok(Self {
+            storage: Storage::Mmap(mmap::Mmap::with_image(image, len)?),
+        })
+    }
+
+    // maps the image at the start of an empty mapped memory, which then has len bytes
+    #[cfg(target_os = "linux")]
+    pub fn map_image(&mut self, image: &MemoryImage, len: usize) -> Result<()> {
+        match &mut self.storage {
+            Storage::Mmap(mmap) => mmap.map_image(image, len),
+            Storage::Vec(_) => bail!("memory image needs a mapped memory"),
+        }
+    }
+
+    // any address up to the end of the guard region is reserved by this memory, so an
+    // access past its length faults instead of touching another allocation
+    pub fn is_guarded(&self) -> bool {
+        match &self.storage {
+            Storage::Vec(_) => false,
+            #[cfg(target_os = "linux")]
+            Storage::Mmap(_) => true,
+        }
+    }
+
+    // new bytes are zeroed, a mapped memory keeps its base address
+    pub fn resize(&mut self, len: usize) -> Result<()> {
+        if len > MAX_LEN {
+            bail!("memory size {} exceeds the limit of {}", len, MAX_LEN);
+        }
+        match &mut self.storage {
+            Storage::Vec(data) => data.resize(len, 0),
+            #[cfg(target_os = "linux")]
+            Storage::Mmap(mmap) => mmap.resize(len)?,
+        }
+        Ok(())
+    }
+}
+
+impl From<Vec<u8>> for LinearMemory {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            storage: Storage::Vec(data),
+        }
+    }
+}
+
+impl Deref for LinearMemory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.storage {
+            Storage::Vec(data) => data,
+            #[cfg(target_os = "linux")]
+            Storage::Mmap(mmap) => mmap.as_slice(),
+        }
+    }
+}
+
+impl DerefMut for LinearMemory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match &mut self.storage {
+            Storage::Vec(data) => data,
+            #[cfg(target_os = "linux")]
+            Storage::Mmap(mmap) => mmap.as_mut_slice(),
+        }
+    }
+}
+
+// a copy holds the bytes in a vector, the reservation belongs to the original
+impl Clone for LinearMemory {
+    fn clone(&self) -> Self {
+        self.to_vec().into()
+    }
+}
+
+impl PartialEq for LinearMemory {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for LinearMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LinearMemory")
+            .field("len", &self.len())
+            .field("guarded", &self.is_guarded())
+            .finish()
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use mmap::MemoryImage;
+
+#[cfg(target_os = "linux")]
+mod mmap {
+    use std::{
+        fs::File,
+        io,
+        os::{
+            fd::{AsRawFd, FromRawFd},
+            unix::fs::FileExt,
+        },
+        ptr, slice,
+    };
+
+    use super::{PAGE_SIZE, RESERVED_LEN};
+    use crate::binary::types::Data;
+    use anyhow::{bail, Result};
+
+    // the initial contents of a memory in a memory file, which instances map privately so
+    // that only the pages they write to are copied
+    pub struct MemoryImage {
+        file: File,
+        len: usize,
+    }
+
+    impl MemoryImage {
+        // the segments are written in order, as instantiation would copy them
+        pub fn new(datas: &[Data]) -> Result<Self> {
+            let end = datas
+                .iter()
+                .map(|data| data.offset as usize + data.init.len())
+                .max()
+                .unwrap_or(0);
+            let len = end.next_multiple_of(PAGE_SIZE as usize);
+
+            let fd = unsafe { libc::memfd_create(c"tinywasm-memory".as_ptr(), libc::MFD_CLOEXEC) };
+            if fd < 0 {
+                bail!(
+                    "failed to create memory image: {}",
+                    io::Error::last_os_error()
+                );
+            }
+            let file = unsafe { File::from_raw_fd(fd) };
+            file.set_len(len as u64)?;
+            for data in datas {
+                file.write_all_at(&data.init, u64::from(data.offset))?;
+            }
+            Ok(Self { file, len })
+        }
+
+        pub fn len(&self) -> usize {
+            self.len
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len == 0
+        }
+    }
+
+    pub struct Mmap {
+        base: *mut u8,
+        len: usize,
+    }
+
+    // the mapping is owned like the buffer of a vector
+    unsafe impl Send for Mmap {}
+    unsafe impl Sync for Mmap {}
+
+    impl Mmap {
+        pub fn new(len: usize) -> Result<Self> {
+            let base = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    RESERVED_LEN,
+                    libc::PROT_NONE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                    -1,
+                    0,
+                )
+            };
+            if base == libc::MAP_FAILED {
+                bail!("failed to reserve memory: {}", io::Error::last_os_error());
+            }
+            let mut mmap = Self {
+                base: base as *mut u8,
+                len: 0,
+            };
+            mmap.resize(len)?;
+            Ok(mmap)
+        }
+
+        pub fn with_image(image: &MemoryImage, len: usize) -> Result<Self> {
+            let mut mmap = Self::new(0)?;
+            mmap.map_image(image, len)?;
+            Ok(mmap)
+        }
+
+        pub fn map_image(&mut self, image: &MemoryImage, len: usize) -> Result<()> {
+            if image.len > len {
+                bail!("memory image does not fit in the memory");
+            }
+            if self.len != 0 {
+                bail!("memory image needs an empty memory");
+            }
+            if !image.is_empty() {
+                let base = unsafe {
+                    libc::mmap(
+                        self.base as *mut libc::c_void,
+                        image.len,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_PRIVATE | libc::MAP_FIXED,
+                        image.file.as_raw_fd(),
+                        0,
+                    )
+                };
+                if base == libc::MAP_FAILED {
+                    bail!("failed to map memory image: {}", io::Error::last_os_error());
+                }
+                self.len = image.len;
+            }
+            self.resize(len)
+        }
+
+        // the length is a multiple of the wasm page size, which is a multiple of the
+        // host's page size
+        pub fn resize(&mut self, len: usize) -> Result<()> {
+            if !len.is_multiple_of(PAGE_SIZE as usize) {
+                bail!("memory size {} is not a multiple of the page size", len);
+            }
+            if len > self.len {
+                let at = unsafe { self.base.add(self.len) };
+                let result = unsafe {
+                    libc::mprotect(
+                        at as *mut libc::c_void,
+                        len - self.len,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                    )
+                };
+                if result != 0 {
+                    bail!("failed to commit memory: {}", io::Error::last_os_error());
+                }
+            } else if len < self.len {
+                // fresh pages replace the dropped ones, which may belong to an image, so
+                // they are zeroed if they are committed again
+                let at = unsafe { self.base.add(len) } as *mut libc::c_void;
+                let result = unsafe {
+                    libc::mmap(
+                        at,
+                        self.len - len,
+                        libc::PROT_NONE,
+                        libc::MAP_PRIVATE
+                            | libc::MAP_ANONYMOUS
+                            | libc::MAP_NORESERVE
+                            | libc::MAP_FIXED,
+                        -1,
+                        0,
+                    )
+                };
+                if result == libc::MAP_FAILED {
+                    bail!("failed to decommit memory: {}", io::Error::last_os_error());
+                }
+            }
+            self.len = len;
+            Ok(())
+        }
+
+        pub fn as_slice(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.base, self.len) }
+        }
+
+        pub fn as_mut_slice(&mut self) -> &mut [u8] {
+            unsafe { slice::from_raw_parts_mut(self.base, self.len) }
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.base as *mut libc::c_void, RESERVED_LEN) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LinearMemory;
+    use crate::execution::{config::Config, linker::Linker, module::Module, runtime::Runtime};
+    use anyhow::Result;
+
+    #[test]
+    fn grow() -> Result<()> {
+        let wasm = wat::parse_str("(module (memory (export \"memory\") 1 3))")?;
+        let mut runtime = Runtime::instantiate(wasm)?;
+        let memory = runtime.get_memory("memory")?;
+        memory.write(&mut runtime, 0, b"hello")?;
+
+        assert_eq!(memory.grow(&mut runtime, 2)?, 1);
+        assert_eq!(memory.size(&runtime), 3);
+        assert_eq!(&memory.data(&runtime)[0..5], b"hello");
+        assert!(memory.data(&runtime)[65536..].iter().all(|byte| *byte == 0));
+        assert_eq!(
+            memory.grow(&mut runtime, 1).unwrap_err().to_string(),
+            "memory cannot grow beyond 3 pages"
+        );
+        assert_eq!(memory.size(&runtime), 3);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mmap() -> Result<()> {
+        let mut memory = LinearMemory::mmap(65536)?;
+        assert!(memory.is_guarded());
+        memory[65535] = 1;
+        let base = memory.as_ptr();
+
+        memory.resize(3 * 65536)?;
+        assert_eq!(memory.as_ptr(), base);
+        assert_eq!(memory[65535], 1);
+        memory[65536] = 2;
+
+        // pages given back are zeroed when they are committed again
+        memory.resize(65536)?;
+        memory.resize(2 * 65536)?;
+        assert_eq!(memory[65536], 0);
+        assert!(memory.resize(100).is_err());
+
+        let copy = memory.clone();
+        assert!(!copy.is_guarded());
+        assert_eq!(copy, memory);
+
+        // the last byte of a 4 byte access at the largest address and offset is reserved
+        use super::RESERVED_LEN;
+        let end = u32::MAX as usize + u32::MAX as usize + 4;
+        assert!(end <= RESERVED_LEN);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn restore_snapshot_in_place() -> Result<()> {
+        let wat = r#"(module (memory (export "memory") 1) (data (i32.const 0) "hello"))"#;
+        let wasm = wat::parse_str(wat)?;
+        let mut config = Config::new();
+        config.mmap_memory(true);
+        let module = Module::new_with_config(wasm, &config)?;
+        let mut runtime = Runtime::new(());
+        Linker::new().instantiate(&mut runtime, &module)?;
+        let memory = runtime.get_memory("memory")?;
+        let snapshot = runtime.snapshot();
+
+        let base = memory.data(&runtime).as_ptr();
+        memory.grow(&mut runtime, 1)?;
+        memory.write(&mut runtime, 0, b"dirty")?;
+        runtime.restore(&snapshot)?;
+        assert_eq!(memory.data(&runtime).as_ptr(), base);
+        assert_eq!(memory.size(&runtime), 1);
+        assert_eq!(&memory.data(&runtime)[0..5], b"hello");
+        assert!(runtime.store.memories[memory.idx].data.is_guarded());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn instantiate_from_image() -> Result<()> {
+        let wat = r#"
+            (module
+              (memory (export "memory") 2)
+              (data (i32.const 0) "hello")
+              (data (i32.const 70000) "world")
+              (data (i32.const 2) "LL"))
+        "#;
+        let mut config = Config::new();
+        config.mmap_memory(true);
+        let module = Module::new_with_config(wat::parse_str(wat)?, &config)?;
+        let instantiate = || -> Result<Runtime> {
+            let mut runtime = Runtime::new(());
+            Linker::new().instantiate(&mut runtime, &module)?;
+            Ok(runtime)
+        };
+
+        let mut first = instantiate()?;
+        let memory = first.get_memory("memory")?;
+        assert_eq!(
+            module
+                .inner
+                .memory_image
+                .get()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .len(),
+            131072
+        );
+        assert_eq!(&memory.data(&first)[0..5], b"heLLo");
+        assert_eq!(&memory.data(&first)[70000..70005], b"world");
+        memory.write(&mut first, 0, b"dirty")?;
+
+        // writes stay private to the instance that made them
+        let mut second = instantiate()?;
+        assert_eq!(&memory.data(&second)[0..5], b"heLLo");
+        assert_eq!(&memory.data(&first)[0..5], b"dirty");
+
+        // shrinking drops the image's pages instead of reverting to them
+        let data = &mut second.store.memories[memory.idx].data;
+        data.resize(65536)?;
+        data.resize(131072)?;
+        assert!(data[65536..].iter().all(|byte| *byte == 0));
+
+        let wasm = wat::parse_str(r#"(module (memory 1) (data (i32.const 65535) "ab"))"#)?;
+        let module = Module::new_with_config(wasm, &config)?;
+        let result = Linker::new().instantiate(&mut Runtime::new(()), &module);
+        assert_eq!(
+            result.err().map(|e| e.to_string()),
+            Some("data is too large to fit in memory".to_string())
+        );
+        Ok(())
+    }
+}
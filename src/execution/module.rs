@@ -0,0 +1,1086 @@
+use std::{
+    borrow::Borrow,
+    collections::HashSet,
+    fmt,
+    sync::{Arc, OnceLock},
+};
+
+use super::{
+    artifact::fnv1a,
+    compile::{compile, Op, Reg},
+    config::Config,
+    store::Func,
+};
+#[cfg(target_os = "linux")]
+use super::{memory::MemoryImage, store::PAGE_SIZE};
+use crate::binary::{
+    self,
+    instruction::Instruction,
+    section::{Function, LazyCode},
+    types::{
+        BlockType, ConstExpr, Data, Element, Export, ExportDesc, FuncType, FunctionLocal,
+        GlobalType, Import, ImportDesc, Memory, Table, ValueType,
+    },
+};
+use anyhow::{bail, Result};
+
+// a decoded and validated module, instances are created from it without decoding again
+#[derive(Clone)]
+pub struct Module {
+    pub(crate) inner: Arc<ModuleInner>,
+}
+
+pub(crate) struct ModuleInner {
+    pub func_types: Vec<FuncType>,
+    pub imports: Vec<Import>,
+    // the code is set once the function has been lowered, shared by every instance
+    pub funcs: Vec<(FuncType, OnceLock<Arc<Func>>)>,
+    pub tables: Vec<Table>,
+    pub memories: Vec<Memory>,
+    pub globals: Vec<binary::types::Global>,
+    pub exports: Vec<Export>,
+    pub elements: Vec<Element>,
+    pub datas: Vec<Data>,
+    // the hash of the wasm binary, if the module was created from one
+    pub source_hash: Option<u64>,
+    pub config: Config,
+    // the undecoded bodies of a lazily compiled module
+    pub lazy: Option<LazyFuncs>,
+    // built by the first instantiation that maps its memory
+    #[cfg(target_os = "linux")]
+    pub memory_image: OnceLock<Option<MemoryImage>>,
+}
+
+pub(crate) struct LazyFuncs {
+    code: LazyCode,
+    spaces: IndexSpaces,
+    #[cfg(test)]
+    decoded: std::sync::atomic::AtomicUsize,
+}
+
+// the types of the function and global index spaces, imports come first
+pub(crate) struct IndexSpaces {
+    funcs: Vec<FuncType>,
+    globals: Vec<GlobalType>,
+    table_count: usize,
+    memory_count: usize,
+}
+
+impl Module {
+    pub fn new(wasm: impl AsRef<[u8]>) -> Result<Self> {
+        Self::new_with_config(wasm, &Config::default())
+    }
+
+    pub fn new_with_config(wasm: impl AsRef<[u8]>, config: &Config) -> Result<Self> {
+        let wasm = wasm.as_ref();
+        let module = match config.compiles_lazily() {
+            true => binary::module::Module::new_lazy(wasm)?,
+            false => binary::module::Module::new(wasm)?,
+        };
+        Self::build(module, config, Some(fnv1a(wasm)))
+    }
+
+    pub fn from_binary(module: binary::module::Module) -> Result<Self> {
+        Self::from_binary_with_config(module, &Config::default())
+    }
+
+    pub fn from_binary_with_config(
+        module: binary::module::Module,
+        config: &Config,
+    ) -> Result<Self> {
+        Self::build(module, config, None)
+    }
+
+    fn build(
+        module: binary::module::Module,
+        config: &Config,
+        source_hash: Option<u64>,
+    ) -> Result<Self> {
+        let func_types = module.type_section.unwrap_or_default();
+        let type_idxs = module.function_section.unwrap_or_default();
+        // only bodies that are still undecoded can be left for their first call
+        let (code_section, lazy_code) = match module.lazy_code_section {
+            Some(code) if config.compiles_lazily() => (vec![], Some(code)),
+            Some(code) => {
+                let bodies = (0..code.bodies.len()).map(|idx| code.decode(idx));
+                (bodies.collect::<Result<_>>()?, None)
+            }
+            None => (module.code_section.unwrap_or_default(), None),
+        };
+        let body_count = match &lazy_code {
+            Some(code) => code.bodies.len(),
+            None => code_section.len(),
+        };
+        if type_idxs.len() != body_count {
+            bail!("function and code section have inconsistent lengths");
+        }
+
+        let mut own_types = Vec::with_capacity(type_idxs.len());
+        for type_idx in type_idxs {
+            let Some(func_type) = func_types.get(type_idx as usize) else {
+                bail!("unknown type {}", type_idx);
+            };
+            own_types.push(func_type.clone());
+        }
+
+        let mut inner = ModuleInner {
+            func_types,
+            imports: module.import_section.unwrap_or_default(),
+            funcs: vec![],
+            tables: module.table_section.unwrap_or_default(),
+            memories: module.memory_section.unwrap_or_default(),
+            globals: module.global_section.unwrap_or_default(),
+            exports: module.export_section.unwrap_or_default(),
+            elements: module.element_section.unwrap_or_default(),
+            datas: module.data_section.unwrap_or_default(),
+            source_hash,
+            config: config.clone(),
+            lazy: None,
+            #[cfg(target_os = "linux")]
+            memory_image: OnceLock::new(),
+        };
+        let spaces = match inner.index_spaces(&own_types) {
+            Ok(spaces) => spaces,
+            Err(e) => bail!("failed to validate module: {}", e),
+        };
+        if let Err(e) = inner.validate(&spaces) {
+            bail!("failed to validate module: {}", e);
+        }
+
+        if let Some(code) = lazy_code {
+            // an invalid module is still rejected here, the instructions are checked as they
+            // are read and the body is only decoded on its first call
+            for (idx, func_type) in own_types.iter().enumerate() {
+                let (body_locals, body) = code.instructions(idx)?;
+                if let Err(e) = inner.validate_func(&spaces, func_type, &locals(&body_locals), body)
+                {
+                    bail!("failed to validate module: {}", e);
+                }
+            }
+            inner.funcs = own_types
+                .into_iter()
+                .map(|func_type| (func_type, OnceLock::new()))
+                .collect();
+            inner.lazy = Some(LazyFuncs {
+                code,
+                spaces,
+                #[cfg(test)]
+                decoded: Default::default(),
+            });
+            return Ok(Self {
+                inner: Arc::new(inner),
+            });
+        }
+
+        let mut funcs = Vec::with_capacity(own_types.len());
+        for (idx, (func_type, body)) in own_types.into_iter().zip(code_section).enumerate() {
+            let func = inner.lower(&spaces, idx, &func_type, body)?;
+            funcs.push((func_type, func));
+        }
+        Self::finish(inner, funcs, config)
+    }
+
+    // the functions are already lowered, they are verified before native code is generated
+    // from them since an artifact may hold any ops
+    pub(crate) fn finish(
+        mut inner: ModuleInner,
+        #[cfg_attr(not(feature = "jit"), allow(unused_mut))] mut funcs: Vec<(FuncType, Func)>,
+        config: &Config,
+    ) -> Result<Self> {
+        let own_types: Vec<_> = funcs
+            .iter()
+            .map(|(func_type, _)| func_type.clone())
+            .collect();
+        // also checks the imports, exports and segments of a deserialized module
+        let spaces = match inner
+            .index_spaces(&own_types)
+            .and_then(|spaces| inner.validate(&spaces).map(|()| spaces))
+        {
+            Ok(spaces) => spaces,
+            Err(e) => bail!("failed to validate module: {}", e),
+        };
+        for (idx, (func_type, func)) in funcs.iter().enumerate() {
+            if let Err(e) = inner.verify_func(&spaces, func_type, func) {
+                bail!("failed to verify function {}: {}", idx, e);
+            }
+        }
+
+        #[cfg(feature = "jit")]
+        if config.jit {
+            let guarded = config.guard_pages();
+            if let Err(e) =
+                super::jit::compile_module(&mut funcs, &inner.func_types, &spaces.funcs, guarded)
+            {
+                bail!("failed to compile native code: {}", e);
+            }
+        }
+        inner.funcs = funcs
+            .into_iter()
+            .map(|(func_type, func)| (func_type, OnceLock::from(Arc::new(func))))
+            .collect();
+        inner.config = config.clone();
+
+        Ok(Self {
+            inner: Arc::new(inner),
+        })
+    }
+
+    pub fn imports(&self) -> &[Import] {
+        &self.inner.imports
+    }
+
+    pub fn exports(&self) -> &[Export] {
+        &self.inner.exports
+    }
+}
+
+// a validated function failed to lower on its first call, the guest never ran it, so it
+// does not poison the runtime like a trap does
+#[derive(Debug)]
+pub(crate) struct LowerError(anyhow::Error);
+
+impl fmt::Display for LowerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for LowerError {}
+
+// the locals of a body are run length encoded in the binary
+fn locals(body_locals: &[FunctionLocal]) -> Vec<ValueType> {
+    let mut locals = Vec::with_capacity(body_locals.len());
+    for local in body_locals {
+        for _ in 0..local.type_count {
+            locals.push(local.value_type.clone());
+        }
+    }
+    locals
+}
+
+fn function(body: Function) -> Func {
+    Func {
+        locals: locals(&body.locals),
+        body: body.code,
+        ..Default::default()
+    }
+}
+
+impl ModuleInner {
+    // the initial contents of the module's own memory, if every data segment fits in its
+    // minimum size, otherwise instantiation copies the segments and reports the failure
+    #[cfg(target_os = "linux")]
+    pub(crate) fn memory_image(&self) -> Result<Option<&MemoryImage>> {
+        if let Some(image) = self.memory_image.get() {
+            return Ok(image.as_ref());
+        }
+        let image = match self.memories.first() {
+            Some(memory) if self.memories.len() == 1 => {
+                let len = memory.limits.min as usize * PAGE_SIZE as usize;
+                let fits = self.datas.iter().all(|data| {
+                    data.memory_index == 0 && data.offset as usize + data.init.len() <= len
+                });
+                match fits {
+                    true => Some(MemoryImage::new(&self.datas)?),
+                    false => None,
+                }
+            }
+            _ => None,
+        };
+        Ok(self.memory_image.get_or_init(|| image).as_ref())
+    }
+
+    // the code of one of the module's own functions, lowering it on the first call
+    pub(crate) fn code(&self, idx: usize) -> Result<Arc<Func>> {
+        let Some((func_type, code)) = self.funcs.get(idx) else {
+            bail!("not found func");
+        };
+        if let Some(code) = code.get() {
+            return Ok(code.clone());
+        }
+        let Some(lazy) = &self.lazy else {
+            bail!("function {} was not compiled", idx);
+        };
+        #[cfg(test)]
+        lazy.decoded
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        // the body was validated when the module was created
+        let func = lazy
+            .code
+            .decode(idx)
+            .and_then(|body| self.compile_func(&lazy.spaces, idx, func_type, function(body)))
+            .map_err(|e| anyhow::Error::new(LowerError(e)))?;
+        // another thread may have lowered it meanwhile, every instance keeps using the first
+        Ok(code.get_or_init(|| Arc::new(func)).clone())
+    }
+
+    fn lower(
+        &self,
+        spaces: &IndexSpaces,
+        idx: usize,
+        func_type: &FuncType,
+        body: Function,
+    ) -> Result<Func> {
+        let func = function(body);
+        if let Err(e) =
+            self.validate_func(spaces, func_type, &func.locals, func.body.iter().map(Ok))
+        {
+            bail!("failed to validate module: {}", e);
+        }
+        self.compile_func(spaces, idx, func_type, func)
+    }
+
+    fn compile_func(
+        &self,
+        spaces: &IndexSpaces,
+        idx: usize,
+        func_type: &FuncType,
+        mut func: Func,
+    ) -> Result<Func> {
+        if let Err(e) = compile(
+            &mut func,
+            func_type,
+            &self.func_types,
+            &spaces.funcs,
+            &spaces.globals,
+            &self.config,
+        ) {
+            bail!("failed to compile function {}: {}", idx, e);
+        }
+        Ok(func)
+    }
+
+    fn index_spaces(&self, own_funcs: &[FuncType]) -> Result<IndexSpaces> {
+        let mut spaces = IndexSpaces {
+            funcs: vec![],
+            globals: vec![],
+            table_count: self.tables.len(),
+            memory_count: self.memories.len(),
+        };
+        for import in &self.imports {
+            match &import.desc {
+                ImportDesc::Func(type_idx) => {
+                    let Some(func_type) = self.func_types.get(*type_idx as usize) else {
+                        bail!("unknown type {}", type_idx);
+                    };
+                    spaces.funcs.push(func_type.clone());
+                }
+                ImportDesc::Table(_) => spaces.table_count += 1,
+                ImportDesc::Memory(_) => spaces.memory_count += 1,
+                ImportDesc::Global(global_type) => spaces.globals.push(global_type.clone()),
+            }
+        }
+        spaces.funcs.extend(own_funcs.iter().cloned());
+        spaces
+            .globals
+            .extend(self.globals.iter().map(|global| global.global_type.clone()));
+        Ok(spaces)
+    }
+
+    fn validate(&self, spaces: &IndexSpaces) -> Result<()> {
+        let IndexSpaces {
+            funcs,
+            globals,
+            table_count,
+            memory_count,
+        } = spaces;
+        let (table_count, memory_count) = (*table_count, *memory_count);
+        let imported_globals = globals.len() - self.globals.len();
+
+        if memory_count > 1 {
+            bail!("multiple memories");
+        }
+
+        for global in &self.globals {
+            let value_type = match global.init {
+                ConstExpr::I32Const(_) => ValueType::I32,
+                ConstExpr::I64Const(_) => ValueType::I64,
+                ConstExpr::GlobalGet(idx) => {
+                    // only imported globals are initialized before the module's own globals
+                    if idx as usize >= imported_globals {
+                        bail!("unknown global {}", idx);
+                    }
+                    globals[idx as usize].value_type.clone()
+                }
+            };
+            if value_type != global.global_type.value_type {
+                bail!("type mismatch in global initializer");
+            }
+        }
+
+        let mut names = HashSet::new();
+        for export in &self.exports {
+            if !names.insert(&export.name) {
+                bail!("duplicate export name {}", export.name);
+            }
+            match export.desc {
+                ExportDesc::Func(idx) if idx as usize >= funcs.len() => {
+                    bail!("unknown function {}", idx)
+                }
+                ExportDesc::Table(idx) if idx as usize >= table_count => {
+                    bail!("unknown table {}", idx)
+                }
+                ExportDesc::Memory(idx) if idx as usize >= memory_count => {
+                    bail!("unknown memory {}", idx)
+                }
+                ExportDesc::Global(idx) if idx as usize >= globals.len() => {
+                    bail!("unknown global {}", idx)
+                }
+                _ => {}
+            }
+        }
+
+        for element in &self.elements {
+            if element.table_index as usize >= table_count {
+                bail!("unknown table {}", element.table_index);
+            }
+            if let Some(idx) = element
+                .init
+                .iter()
+                .find(|idx| **idx as usize >= funcs.len())
+            {
+                bail!("unknown function {}", idx);
+            }
+        }
+
+        for data in &self.datas {
+            if data.memory_index as usize >= memory_count {
+                bail!("unknown memory {}", data.memory_index);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_func<I: Borrow<Instruction>>(
+        &self,
+        spaces: &IndexSpaces,
+        func_type: &FuncType,
+        locals: &[ValueType],
+        body: impl IntoIterator<Item = Result<I>>,
+    ) -> Result<()> {
+        let IndexSpaces {
+            funcs,
+            globals,
+            table_count,
+            memory_count,
+        } = spaces;
+        let (table_count, memory_count) = (*table_count, *memory_count);
+        let locals: Vec<_> = func_type.params.iter().chain(locals).collect();
+        let mut stack = TypeStack::new(&self.func_types, &func_type.results);
+        for inst in body {
+            let inst = inst?;
+            let inst = inst.borrow();
+            if stack.controls.is_empty() {
+                bail!("instructions after the end of the function");
+            }
+            match inst {
+                Instruction::Unreachable => stack.set_unreachable(),
+                Instruction::Block(block) | Instruction::Loop(block) => {
+                    let (params, results) = stack.block_types(&block.block_type)?;
+                    stack.pop_all(&params)?;
+                    let kind = match inst {
+                        Instruction::Loop(_) => BlockKind::Loop,
+                        _ => BlockKind::Block,
+                    };
+                    stack.push_control(kind, params, results);
+                }
+                Instruction::If(block) => {
+                    let (params, results) = stack.block_types(&block.block_type)?;
+                    stack.pop_expect(&ValueType::I32)?;
+                    stack.pop_all(&params)?;
+                    stack.push_control(BlockKind::If, params, results);
+                }
+                Instruction::Else => {
+                    if stack.controls.last().map(|control| &control.kind) != Some(&BlockKind::If) {
+                        bail!("else outside of if");
+                    }
+                    let control = stack.pop_control()?;
+                    stack.push_control(BlockKind::Else, control.params, control.results);
+                }
+                Instruction::End => {
+                    let control = stack.pop_control()?;
+                    // without an else the parameters are the results when the condition is false
+                    if control.kind == BlockKind::If && control.params != control.results {
+                        bail!(
+                            "type mismatch: expected {:?}, got {:?}",
+                            control.results,
+                            control.params
+                        );
+                    }
+                    stack.push_all(&control.results);
+                }
+                Instruction::Br(label) => {
+                    let types = stack.label_types(*label)?;
+                    stack.pop_all(&types)?;
+                    stack.set_unreachable();
+                }
+                Instruction::BrIf(label) => {
+                    let types = stack.label_types(*label)?;
+                    stack.pop_expect(&ValueType::I32)?;
+                    stack.pop_all(&types)?;
+                    stack.push_all(&types);
+                }
+                Instruction::Return => {
+                    stack.pop_all(&func_type.results)?;
+                    stack.set_unreachable();
+                }
+                Instruction::LocalGet(idx) | Instruction::LocalSet(idx) => {
+                    let Some(value_type) = locals.get(*idx as usize) else {
+                        bail!("unknown local {}", idx);
+                    };
+                    match inst {
+                        Instruction::LocalGet(_) => stack.push(value_type),
+                        _ => stack.pop_expect(value_type)?,
+                    }
+                }
+                Instruction::GlobalGet(idx) | Instruction::GlobalSet(idx) => {
+                    let Some(GlobalType {
+                        value_type,
+                        mutable,
+                    }) = globals.get(*idx as usize)
+                    else {
+                        bail!("unknown global {}", idx);
+                    };
+                    match inst {
+                        Instruction::GlobalGet(_) => stack.push(value_type),
+                        _ if !mutable => bail!("global is immutable"),
+                        _ => stack.pop_expect(value_type)?,
+                    }
+                }
+                Instruction::I32Store { .. } => {
+                    if memory_count == 0 {
+                        bail!("unknown memory 0");
+                    }
+                    stack.pop_expect(&ValueType::I32)?;
+                    stack.pop_expect(&ValueType::I32)?;
+                }
+                Instruction::I32Const(_) => stack.push(&ValueType::I32),
+                Instruction::I64Const(_) => stack.push(&ValueType::I64),
+                Instruction::I32Lts | Instruction::I32Add | Instruction::I32Sub => {
+                    stack.pop_expect(&ValueType::I32)?;
+                    stack.pop_expect(&ValueType::I32)?;
+                    stack.push(&ValueType::I32);
+                }
+                Instruction::Call(idx) => {
+                    let Some(callee) = funcs.get(*idx as usize) else {
+                        bail!("unknown function {}", idx);
+                    };
+                    stack.pop_all(&callee.params)?;
+                    stack.push_all(&callee.results);
+                }
+                Instruction::CallIndirect {
+                    type_idx,
+                    table_idx,
+                } => {
+                    let Some(callee) = self.func_types.get(*type_idx as usize) else {
+                        bail!("unknown type {}", type_idx);
+                    };
+                    if *table_idx as usize >= table_count {
+                        bail!("unknown table {}", table_idx);
+                    }
+                    stack.pop_expect(&ValueType::I32)?;
+                    stack.pop_all(&callee.params)?;
+                    stack.push_all(&callee.results);
+                }
+            }
+        }
+        if !stack.controls.is_empty() {
+            bail!("missing end of function");
+        }
+
+        Ok(())
+    }
+
+    // the ops of a deserialized function are not produced by the compiler, so everything
+    // the interpreter and native code index with is checked against the module
+    fn verify_func(&self, spaces: &IndexSpaces, func_type: &FuncType, func: &Func) -> Result<()> {
+        let reg_count = func.reg_count;
+        let locals = func_type.params.len() + func.locals.len();
+        if reg_count < locals {
+            bail!("{} registers cannot hold {} locals", reg_count, locals);
+        }
+        if func.costs.len() != func.ops.len() {
+            bail!("{} costs for {} ops", func.costs.len(), func.ops.len());
+        }
+        // execution stops at a return, so it cannot run past the last op
+        if !matches!(
+            func.ops.last(),
+            Some(Op::Return { .. } | Op::Jump { .. } | Op::Unreachable)
+        ) {
+            bail!("function does not end with a return");
+        }
+
+        let regs = |start: Reg, count: usize| -> Result<()> {
+            if start as usize + count > reg_count {
+                bail!("register {} out of range", start as usize + count - 1);
+            }
+            Ok(())
+        };
+        let reg = |reg: Reg| regs(reg, 1);
+        let target = |target: u32| -> Result<()> {
+            match func.ops.get(target as usize) {
+                None => bail!("jump target {} out of range", target),
+                // the results of a call are taken right after it
+                Some(Op::TakeResults { .. }) => bail!("jump target {} takes results", target),
+                Some(_) => Ok(()),
+            }
+        };
+        let global = |idx: u32| -> Result<&GlobalType> {
+            match spaces.globals.get(idx as usize) {
+                Some(global) => Ok(global),
+                None => bail!("unknown global {}", idx),
+            }
+        };
+
+        // the number of results the previous op left on the value stack
+        let mut pending = 0;
+        for op in &func.ops {
+            let results = std::mem::take(&mut pending);
+            match op {
+                Op::TakeResults { .. } => {}
+                _ if results > 0 => bail!("results of a call are not taken"),
+                _ => {}
+            }
+            match op {
+                Op::Unreachable | Op::Nop => {}
+                Op::Copy { dst, src } => {
+                    reg(*dst)?;
+                    reg(*src)?;
+                }
+                Op::Const { dst, .. } => reg(*dst)?,
+                Op::GlobalGet { dst, idx } => {
+                    reg(*dst)?;
+                    global(*idx)?;
+                }
+                Op::GlobalSet { idx, src } => {
+                    reg(*src)?;
+                    if !global(*idx)?.mutable {
+                        bail!("global is immutable");
+                    }
+                }
+                Op::I32Store { addr, value, .. } => {
+                    reg(*addr)?;
+                    reg(*value)?;
+                    if spaces.memory_count == 0 {
+                        bail!("unknown memory 0");
+                    }
+                }
+                Op::I32Add { dst, lhs, rhs }
+                | Op::I32Sub { dst, lhs, rhs }
+                | Op::I32Lts { dst, lhs, rhs } => {
+                    reg(*dst)?;
+                    reg(*lhs)?;
+                    reg(*rhs)?;
+                }
+                Op::I32AddImm { dst, lhs, .. }
+                | Op::I32SubImm { dst, lhs, .. }
+                | Op::I32LtsImm { dst, lhs, .. } => {
+                    reg(*dst)?;
+                    reg(*lhs)?;
+                }
+                Op::Jump { target: to } => target(*to)?,
+                Op::JumpIfZero { cond, target: to } | Op::JumpIfNonZero { cond, target: to } => {
+                    reg(*cond)?;
+                    target(*to)?;
+                }
+                Op::JumpIfLts {
+                    lhs,
+                    rhs,
+                    target: to,
+                }
+                | Op::JumpIfNotLts {
+                    lhs,
+                    rhs,
+                    target: to,
+                } => {
+                    reg(*lhs)?;
+                    reg(*rhs)?;
+                    target(*to)?;
+                }
+                Op::JumpIfLtsImm {
+                    lhs, target: to, ..
+                }
+                | Op::JumpIfNotLtsImm {
+                    lhs, target: to, ..
+                } => {
+                    reg(*lhs)?;
+                    target(*to)?;
+                }
+                Op::Call { func, args } => {
+                    let Some(callee) = spaces.funcs.get(*func as usize) else {
+                        bail!("unknown function {}", func);
+                    };
+                    regs(*args, callee.params.len())?;
+                    pending = callee.results.len();
+                }
+                Op::CallIndirect {
+                    type_idx,
+                    table_idx,
+                    elem,
+                    args,
+                } => {
+                    let Some(callee) = self.func_types.get(*type_idx as usize) else {
+                        bail!("unknown type {}", type_idx);
+                    };
+                    if *table_idx as usize >= spaces.table_count {
+                        bail!("unknown table {}", table_idx);
+                    }
+                    reg(*elem)?;
+                    regs(*args, callee.params.len())?;
+                    pending = callee.results.len();
+                }
+                Op::TakeResults { dst, count } => {
+                    if *count as usize != results || results == 0 {
+                        bail!("taking {} results of a call returning {}", count, results);
+                    }
+                    regs(*dst, results)?;
+                }
+                Op::Return { src } => regs(*src, func_type.results.len())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockKind {
+    Block,
+    Loop,
+    If,
+    Else,
+}
+
+struct Control {
+    kind: BlockKind,
+    params: Vec<ValueType>,
+    results: Vec<ValueType>,
+    // the operand stack height at the start of the block
+    height: usize,
+    // values popped below an unconditional branch or unreachable may have any type
+    unreachable: bool,
+}
+
+// the operand types of a function body and the blocks around them, as the spec validates
+// it, the function body is the outermost block
+struct TypeStack<'a> {
+    func_types: &'a [FuncType],
+    // None is a value of any type, only found after unreachable code
+    operands: Vec<Option<ValueType>>,
+    controls: Vec<Control>,
+}
+
+impl<'a> TypeStack<'a> {
+    fn new(func_types: &'a [FuncType], results: &[ValueType]) -> Self {
+        let mut stack = Self {
+            func_types,
+            operands: vec![],
+            controls: vec![],
+        };
+        stack.push_control(BlockKind::Block, vec![], results.to_vec());
+        stack
+    }
+
+    fn push(&mut self, value_type: &ValueType) {
+        self.operands.push(Some(value_type.clone()));
+    }
+
+    fn push_all(&mut self, value_types: &[ValueType]) {
+        self.operands.extend(value_types.iter().cloned().map(Some));
+    }
+
+    fn pop(&mut self) -> Result<Option<ValueType>> {
+        let Some(control) = self.controls.last() else {
+            bail!("instructions after the end of the function");
+        };
+        if self.operands.len() == control.height {
+            if control.unreachable {
+                return Ok(None);
+            }
+            bail!("not found value in the stack");
+        }
+        Ok(self.operands.pop().flatten())
+    }
+
+    fn pop_expect(&mut self, expected: &ValueType) -> Result<()> {
+        if let Some(actual) = self.pop()?
+            && actual != *expected
+        {
+            bail!("type mismatch: expected {:?}, got {:?}", expected, actual);
+        }
+        Ok(())
+    }
+
+    fn pop_all(&mut self, expected: &[ValueType]) -> Result<()> {
+        for value_type in expected.iter().rev() {
+            self.pop_expect(value_type)?;
+        }
+        Ok(())
+    }
+
+    fn push_control(&mut self, kind: BlockKind, params: Vec<ValueType>, results: Vec<ValueType>) {
+        self.controls.push(Control {
+            kind,
+            params: params.clone(),
+            results,
+            height: self.operands.len(),
+            unreachable: false,
+        });
+        self.push_all(&params);
+    }
+
+    // the block must leave exactly its results
+    fn pop_control(&mut self) -> Result<Control> {
+        let Some(control) = self.controls.last() else {
+            bail!("instructions after the end of the function");
+        };
+        let results = control.results.clone();
+        self.pop_all(&results)?;
+        let Some(control) = self.controls.pop() else {
+            bail!("instructions after the end of the function");
+        };
+        if self.operands.len() != control.height {
+            bail!(
+                "type mismatch: {} values left in the block",
+                self.operands.len() - control.height
+            );
+        }
+        Ok(control)
+    }
+
+    fn set_unreachable(&mut self) {
+        if let Some(control) = self.controls.last_mut() {
+            self.operands.truncate(control.height);
+            control.unreachable = true;
+        }
+    }
+
+    // a branch to a loop starts it again, any other branch leaves the block
+    fn label_types(&self, label: u32) -> Result<Vec<ValueType>> {
+        let Some(control) = self
+            .controls
+            .len()
+            .checked_sub(label as usize + 1)
+            .map(|idx| &self.controls[idx])
+        else {
+            bail!("unknown label {}", label);
+        };
+        Ok(match control.kind {
+            BlockKind::Loop => control.params.clone(),
+            _ => control.results.clone(),
+        })
+    }
+
+    fn block_types(&self, block_type: &BlockType) -> Result<(Vec<ValueType>, Vec<ValueType>)> {
+        match block_type {
+            BlockType::Void => Ok((vec![], vec![])),
+            BlockType::Value(results) => Ok((vec![], results.clone())),
+            BlockType::FuncType(idx) => match self.func_types.get(*idx as usize) {
+                Some(func_type) => Ok((func_type.params.clone(), func_type.results.clone())),
+                None => bail!("unknown type {}", idx),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Module;
+    use crate::execution::{config::Config, linker::Linker, runtime::Runtime, value::Value};
+    use anyhow::Result;
+    use std::sync::{atomic::Ordering, Arc};
+
+    #[test]
+    fn module_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Module>();
+    }
+
+    #[test]
+    fn validate() -> Result<()> {
+        let tests = vec![
+            ("(module (func (call 1)))", "unknown function 1"),
+            (
+                "(module (func (local.set 0 (i32.const 1))))",
+                "unknown local 0",
+            ),
+            ("(module (func (br 1)))", "unknown label 1"),
+            (
+                "(module (global i32 (i32.const 0)) (func (global.set 0 (i32.const 1))))",
+                "global is immutable",
+            ),
+            (
+                "(module (func (i32.store (i32.const 0) (i32.const 0))))",
+                "unknown memory 0",
+            ),
+            (
+                "(module (func (call_indirect (i32.const 0))))",
+                "unknown table 0",
+            ),
+            (
+                "(module (func $f) (export \"f\" (func $f)) (export \"f\" (func $f)))",
+                "duplicate export name f",
+            ),
+        ];
+
+        for (wat, want) in tests {
+            let wasm = wat::parse_str(wat)?;
+            let result = Module::new_with_config(wasm, Config::new().lazy(false));
+            assert_eq!(
+                result.err().map(|e| e.to_string()),
+                Some(format!("failed to validate module: {want}")),
+                "{wat}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reject_type_mismatch() -> Result<()> {
+        let tests = vec![
+            (
+                "(module (func (result i32) (i32.add (i64.const 1) (i32.const 2))))",
+                "type mismatch: expected I32, got I64",
+            ),
+            (
+                "(module (global (mut i64) (i64.const 0)) (func (global.set 0 (i32.const 1))))",
+                "type mismatch: expected I64, got I32",
+            ),
+            (
+                "(module (func (result i32) i64.const 1))",
+                "type mismatch: expected I32, got I64",
+            ),
+            (
+                "(module (func (result i32) (block (result i64) (i64.const 1) (br 0))))",
+                "type mismatch: expected I32, got I64",
+            ),
+            (
+                "(module (func (result i32) (if (result i32) (i32.const 1) (then (i32.const 1)))))",
+                "type mismatch: expected [I32], got []",
+            ),
+            (
+                "(module (func (block (i32.const 1))))",
+                "type mismatch: 1 values left in the block",
+            ),
+            (
+                "(module (func (result i32) (i32.add (i32.const 1))))",
+                "not found value in the stack",
+            ),
+        ];
+
+        for (wat, want) in tests {
+            let wasm = wat::parse_str(wat)?;
+            let result = Module::new_with_config(wasm, Config::new().lazy(false));
+            assert_eq!(
+                result.err().map(|e| e.to_string()),
+                Some(format!("failed to validate module: {want}")),
+                "{wat}"
+            );
+        }
+
+        // values below unreachable code may have any type
+        let valid = vec![
+            "(module (func (result i32) unreachable))",
+            "(module (func (result i32) (block (br 1 (i32.const 1))) (i32.const 2)))",
+            "(module (func (result i64) (loop (result i64) (i64.const 1) (return))))",
+            "(module (func (param i32) (result i32) (if (result i32) (local.get 0) (then (i32.const 1)) (else (i32.const 2)))))",
+        ];
+        for wat in valid {
+            Module::new_with_config(wat::parse_str(wat)?, Config::new().lazy(false))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compile_lazily() -> Result<()> {
+        let wat = r#"
+            (module
+              (func (export "ok") (result i32) (i32.const 1))
+              (func (export "unused") (result i32) (i32.const 2)))
+        "#;
+        let wasm = wat::parse_str(wat)?;
+        #[cfg_attr(not(feature = "jit"), allow(unused_mut))]
+        let mut config = Config::new();
+        #[cfg(feature = "jit")]
+        config.jit(false);
+        let module = Module::new_with_config(&wasm, &config)?;
+        assert!(module
+            .inner
+            .funcs
+            .iter()
+            .all(|(_, code)| code.get().is_none()));
+
+        let mut runtime = Runtime::new(());
+        Linker::new().instantiate(&mut runtime, &module)?;
+        assert_eq!(runtime.call("ok", vec![])?, vec![Value::I32(1)]);
+        assert!(module.inner.funcs[0].1.get().is_some());
+        assert!(module.inner.funcs[1].1.get().is_none());
+
+        // bodies are still validated up front
+        let invalid = r#"(module (func (export "invalid") (local.set 0 (i32.const 1))))"#;
+        let result = Module::new_with_config(wat::parse_str(invalid)?, &config);
+        assert_eq!(
+            result.err().map(|e| e.to_string()),
+            Some("failed to validate module: unknown local 0".to_string())
+        );
+        let invalid = r#"(module (func (export "invalid") (result i32) (i64.const 1)))"#;
+        assert!(Module::new_with_config(wat::parse_str(invalid)?, &config).is_err());
+
+        // a function failing to lower on its first call does not poison the runtime
+        let wat = r#"
+            (module
+              (type $t (func))
+              (table 1 funcref)
+              (func (export "answer") (result i32) (i32.const 42))
+              (func (export "call") (call_indirect (type $t) (i32.const 0))))
+        "#;
+        let mut module = Module::new_with_config(wat::parse_str(wat)?, &config)?;
+        Arc::get_mut(&mut module.inner)
+            .expect("unique module")
+            .func_types
+            .clear();
+        let mut runtime = Runtime::new(());
+        runtime.set_poison_on_trap(true);
+        Linker::new().instantiate(&mut runtime, &module)?;
+        assert_eq!(
+            runtime.call("call", vec![]).err().map(|e| e.to_string()),
+            Some(
+                "failed to execute instructions: failed to compile function 1: unknown type 0"
+                    .to_string()
+            )
+        );
+        assert!(!runtime.is_poisoned());
+        assert!(runtime.call_stack.is_empty());
+        assert_eq!(runtime.call("answer", vec![])?, vec![Value::I32(42)]);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_on_first_call() -> Result<()> {
+        let wat = r#"
+            (module
+              (func (export "ok") (result i32) (i32.const 1))
+              (func (export "unused") (result i32) (i32.const 2)))
+        "#;
+        #[cfg_attr(not(feature = "jit"), allow(unused_mut))]
+        let mut config = Config::new();
+        #[cfg(feature = "jit")]
+        config.jit(false);
+        let module = Module::new_with_config(wat::parse_str(wat)?, &config)?;
+        let decoded = || {
+            let lazy = module.inner.lazy.as_ref().expect("lazily compiled");
+            lazy.decoded.load(Ordering::Relaxed)
+        };
+        // validating the bodies does not decode them
+        assert_eq!(decoded(), 0);
+
+        let mut runtime = Runtime::new(());
+        Linker::new().instantiate(&mut runtime, &module)?;
+        for _ in 0..2 {
+            assert_eq!(runtime.call("ok", vec![])?, vec![Value::I32(1)]);
+        }
+        // the uncalled body is never decoded
+        assert_eq!(decoded(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,458 @@
+use std::{
+    mem,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use super::{
+    linker::Linker,
+    memory::LinearMemory,
+    module::Module,
+    runtime::{Frame, Runtime},
+    store::{MemoryInst, TableInst},
+};
+use crate::binary::types::Limits;
+use anyhow::{bail, Result};
+
+// the limits of every instance slot, a pool allocates all of its slots up front so these
+// bound the memory it uses
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    pub(crate) slots: usize,
+    pub(crate) memories: usize,
+    pub(crate) memory_pages: u32,
+    pub(crate) tables: usize,
+    pub(crate) table_elements: u32,
+    pub(crate) call_depth: usize,
+    pub(crate) stack_size: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            slots: 16,
+            memories: 1,
+            memory_pages: 160,
+            tables: 1,
+            table_elements: 10_000,
+            call_depth: 10_000,
+            stack_size: 16_384,
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the number of runtimes that can be handed out at the same time
+    pub fn slots(&mut self, slots: usize) -> &mut Self {
+        self.slots = slots;
+        self
+    }
+
+    // memories defined by all the modules instantiated in one slot
+    pub fn memories(&mut self, memories: usize) -> &mut Self {
+        self.memories = memories;
+        self
+    }
+
+    // the largest size of each memory, a smaller maximum of the module is kept, on linux
+    // every memory reserves 8GiB of address space regardless
+    pub fn memory_pages(&mut self, memory_pages: u32) -> &mut Self {
+        self.memory_pages = memory_pages;
+        self
+    }
+
+    // tables defined by all the modules instantiated in one slot
+    pub fn tables(&mut self, tables: usize) -> &mut Self {
+        self.tables = tables;
+        self
+    }
+
+    // the largest size of each table
+    pub fn table_elements(&mut self, table_elements: u32) -> &mut Self {
+        self.table_elements = table_elements;
+        self
+    }
+
+    // the deepest call stack of a runtime, deeper calls trap
+    pub fn call_depth(&mut self, call_depth: usize) -> &mut Self {
+        self.call_depth = call_depth;
+        self
+    }
+
+    // the values preallocated for the value stack of each runtime, it still grows past
+    // them when a call needs more
+    pub fn stack_size(&mut self, stack_size: usize) -> &mut Self {
+        self.stack_size = stack_size;
+        self
+    }
+}
+
+// the memories, tables and stacks of one runtime, kept by the store while it is handed out
+pub(crate) struct Slot {
+    memories: Vec<LinearMemory>,
+    tables: Vec<Vec<Option<usize>>>,
+    stack: Vec<u64>,
+    call_stack: Vec<Frame>,
+    memory_pages: u32,
+    table_elements: u32,
+    stack_size: usize,
+    call_depth: usize,
+}
+
+impl Slot {
+    fn new(config: &PoolConfig) -> Result<Self> {
+        let memories = (0..config.memories)
+            .map(|_| reserve_memory(config.memory_pages))
+            .collect::<Result<_>>()?;
+        let tables = (0..config.tables)
+            .map(|_| Vec::with_capacity(config.table_elements as usize))
+            .collect();
+        Ok(Self {
+            memories,
+            tables,
+            stack: Vec::with_capacity(config.stack_size),
+            call_stack: Vec::with_capacity(config.call_depth),
+            memory_pages: config.memory_pages,
+            table_elements: config.table_elements,
+            stack_size: config.stack_size,
+            call_depth: config.call_depth,
+        })
+    }
+
+    // an empty memory, the store resizes it or maps an image into it
+    pub(crate) fn memory(&mut self, limits: &Limits) -> Result<MemoryInst> {
+        if limits.min > self.memory_pages {
+            bail!(
+                "memory of {} pages exceeds the pool limit of {} pages",
+                limits.min,
+                self.memory_pages
+            );
+        }
+        let Some(data) = self.memories.pop() else {
+            bail!("no memory left in the instance slot");
+        };
+        let max = limits.max.unwrap_or(self.memory_pages);
+        Ok(MemoryInst {
+            data,
+            max: Some(max.min(self.memory_pages)),
+        })
+    }
+
+    pub(crate) fn table(&mut self, limits: &Limits) -> Result<TableInst> {
+        if limits.min > self.table_elements {
+            bail!(
+                "table of {} elements exceeds the pool limit of {} elements",
+                limits.min,
+                self.table_elements
+            );
+        }
+        let Some(mut elem) = self.tables.pop() else {
+            bail!("no table left in the instance slot");
+        };
+        elem.resize(limits.min as usize, None);
+        let max = limits.max.unwrap_or(self.table_elements);
+        Ok(TableInst {
+            elem,
+            max: Some(max.min(self.table_elements)),
+        })
+    }
+
+    // shrinking a mapped memory to nothing drops only the pages that were committed,
+    // a vector keeps its capacity and zeroes the bytes again when it grows
+    pub(crate) fn release(
+        &mut self,
+        memories: Vec<MemoryInst>,
+        tables: Vec<TableInst>,
+    ) -> Result<()> {
+        for mut memory in memories {
+            memory.data.resize(0)?;
+            self.memories.push(memory.data);
+        }
+        for mut table in tables {
+            table.elem.clear();
+            self.tables.push(table.elem);
+        }
+        Ok(())
+    }
+
+    fn reclaim<T>(&mut self, runtime: &mut Runtime<T>) -> Result<()> {
+        self.release(
+            mem::take(&mut runtime.store.memories),
+            mem::take(&mut runtime.store.tables),
+        )?;
+        // a restored snapshot replaces the stacks, so the capacity is topped up again
+        self.stack = mem::take(&mut runtime.stack);
+        self.stack.clear();
+        self.stack.reserve(self.stack_size);
+        self.call_stack = mem::take(&mut runtime.call_stack);
+        self.call_stack.clear();
+        self.call_stack.reserve(self.call_depth);
+        Ok(())
+    }
+}
+
+// on linux a mapped memory only commits the pages it touches, but each one reserves 8GiB
+// of address space for its guard region whatever memory_pages is, so the slots and
+// memories of a pool are bounded by the address space more than by memory_pages
+#[cfg(target_os = "linux")]
+fn reserve_memory(_pages: u32) -> Result<LinearMemory> {
+    LinearMemory::mmap(0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reserve_memory(pages: u32) -> Result<LinearMemory> {
+    let len = pages as usize * super::store::PAGE_SIZE as usize;
+    Ok(LinearMemory::from(Vec::with_capacity(len)))
+}
+
+struct Slots {
+    config: PoolConfig,
+    free: Mutex<Vec<Slot>>,
+}
+
+impl Slots {
+    fn free(&self) -> MutexGuard<'_, Vec<Slot>> {
+        // slots are only pushed and popped, a panic elsewhere leaves the list intact
+        self.free
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// hands out runtimes whose memories, tables and stacks come from preallocated slots, a
+// dropped runtime is reset and its slot reused
+#[derive(Clone)]
+pub struct InstancePool {
+    slots: Arc<Slots>,
+}
+
+impl InstancePool {
+    pub fn new(config: &PoolConfig) -> Result<Self> {
+        let free = (0..config.slots)
+            .map(|_| Slot::new(config))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            slots: Arc::new(Slots {
+                config: config.clone(),
+                free: Mutex::new(free),
+            }),
+        })
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.slots.config
+    }
+
+    // the number of slots that are not handed out
+    pub fn available(&self) -> usize {
+        self.slots.free().len()
+    }
+
+    // an empty runtime, modules instantiated into it take their memories and tables from
+    // its slot
+    pub fn runtime<T>(&self, data: T) -> Result<PooledRuntime<T>> {
+        let Some(mut slot) = self.slots.free().pop() else {
+            bail!("all {} instance slots are in use", self.slots.config.slots);
+        };
+        let mut runtime = Runtime::new(data);
+        runtime.stack = mem::take(&mut slot.stack);
+        runtime.call_stack = mem::take(&mut slot.call_stack);
+        runtime.max_call_depth = Some(self.slots.config.call_depth);
+        runtime.store.slot = Some(slot);
+        Ok(PooledRuntime {
+            runtime,
+            slots: self.slots.clone(),
+        })
+    }
+
+    pub fn instantiate<T>(
+        &self,
+        linker: &Linker<T>,
+        module: &Module,
+        data: T,
+    ) -> Result<PooledRuntime<T>> {
+        let mut runtime = self.runtime(data)?;
+        linker.instantiate(&mut runtime, module)?;
+        Ok(runtime)
+    }
+}
+
+// a runtime that gives its slot back to the pool when dropped
+pub struct PooledRuntime<T = ()> {
+    runtime: Runtime<T>,
+    slots: Arc<Slots>,
+}
+
+impl<T> Deref for PooledRuntime<T> {
+    type Target = Runtime<T>;
+
+    fn deref(&self) -> &Runtime<T> {
+        &self.runtime
+    }
+}
+
+impl<T> DerefMut for PooledRuntime<T> {
+    fn deref_mut(&mut self) -> &mut Runtime<T> {
+        &mut self.runtime
+    }
+}
+
+impl<T> Drop for PooledRuntime<T> {
+    fn drop(&mut self) {
+        let Some(mut slot) = self.runtime.store.slot.take() else {
+            return;
+        };
+        // a slot that cannot be reset is not reused
+        if slot.reclaim(&mut self.runtime).is_ok() {
+            self.slots.free().push(slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InstancePool, PoolConfig};
+    use crate::execution::{linker::Linker, module::Module, runtime::Runtime, value::Value};
+    use anyhow::Result;
+
+    #[test]
+    fn reuse_slots() -> Result<()> {
+        let module = Module::new(wat::parse_str(
+            r#"(module (memory (export "memory") 1) (data (i32.const 0) "hello"))"#,
+        )?)?;
+        let pool = InstancePool::new(PoolConfig::new().slots(2))?;
+        let linker = Linker::new();
+
+        let mut first = pool.instantiate(&linker, &module, ())?;
+        let memory = first.get_memory("memory")?;
+        memory.write(&mut first, 0, b"dirty")?;
+        memory.write(&mut first, 60000, b"dirty")?;
+        memory.grow(&mut first, 1)?;
+        let second = pool.instantiate(&linker, &module, ())?;
+        assert_eq!(pool.available(), 0);
+        assert_eq!(
+            pool.instantiate(&linker, &module, ())
+                .err()
+                .map(|e| e.to_string()),
+            Some("all 2 instance slots are in use".to_string())
+        );
+
+        drop(first);
+        drop(second);
+        assert_eq!(pool.available(), 2);
+        for _ in 0..2 {
+            let runtime = pool.instantiate(&linker, &module, ())?;
+            let memory = runtime.get_memory("memory")?;
+            assert_eq!(memory.size(&runtime), 1);
+            assert_eq!(&memory.data(&runtime)[0..5], b"hello");
+            assert!(memory.data(&runtime)[5..].iter().all(|byte| *byte == 0));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn limits() -> Result<()> {
+        let pool = InstancePool::new(
+            PoolConfig::new()
+                .slots(1)
+                .memory_pages(2)
+                .table_elements(4)
+                .call_depth(100),
+        )?;
+        let linker = Linker::new();
+        let instantiate = |wat: &str| -> Result<String> {
+            let module = Module::new(wat::parse_str(wat)?)?;
+            let result = pool.instantiate(&linker, &module, ());
+            Ok(result.err().map(|e| e.to_string()).unwrap_or_default())
+        };
+
+        assert_eq!(
+            instantiate("(module (memory 3))")?,
+            "memory of 3 pages exceeds the pool limit of 2 pages"
+        );
+        assert_eq!(
+            instantiate("(module (table 5 funcref))")?,
+            "table of 5 elements exceeds the pool limit of 4 elements"
+        );
+        let module = Module::new(wat::parse_str("(module (memory 1))")?)?;
+        let mut runtime = pool.runtime(())?;
+        linker.instantiate(&mut runtime, &module)?;
+        assert_eq!(
+            linker
+                .instantiate(&mut runtime, &module)
+                .err()
+                .map(|e| e.to_string()),
+            Some("no memory left in the instance slot".to_string())
+        );
+        drop(runtime);
+        assert_eq!(pool.available(), 1);
+
+        let wasm = wat::parse_str(
+            r#"(module
+                 (memory (export "memory") 1)
+                 (func $f (export "recurse") call $f))"#,
+        )?;
+        let mut runtime = pool.instantiate(&linker, &Module::new(wasm)?, ())?;
+        let memory = runtime.get_memory("memory")?;
+        assert_eq!(
+            memory.grow(&mut runtime, 2).unwrap_err().to_string(),
+            "memory cannot grow beyond 2 pages"
+        );
+        assert_eq!(
+            runtime.call("recurse", vec![]).unwrap_err().to_string(),
+            "failed to execute instructions: call stack exhausted"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn preallocate_stacks() -> Result<()> {
+        let pool = InstancePool::new(PoolConfig::new().slots(1).stack_size(1000))?;
+        let wasm = wat::parse_file("src/fixtures/fib.wat")?;
+        let module = Module::new(wasm)?;
+        let linker = Linker::new();
+
+        let mut runtime = pool.instantiate(&linker, &module, ())?;
+        assert!(runtime.stack.capacity() >= 1000);
+        runtime.call("fib", vec![Value::I32(10)])?;
+        // a snapshot with stacks swaps in vectors of its own
+        let snapshot = runtime.snapshot_with_stacks();
+        runtime.restore(&snapshot)?;
+        drop(runtime);
+
+        let runtime = pool.instantiate(&linker, &module, ())?;
+        assert!(runtime.stack.capacity() >= 1000);
+        assert!(runtime.call_stack.capacity() >= PoolConfig::new().call_depth);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_instantiation_keeps_slot() -> Result<()> {
+        let pool = InstancePool::new(PoolConfig::new().slots(1).memory_pages(2))?;
+        let linker = Linker::new();
+        let mut runtime = pool.runtime(())?;
+
+        // the data segment is checked once the memory is taken from the slot
+        let wasm = wat::parse_str(r#"(module (memory 1) (data (i32.const 70000) "x"))"#)?;
+        assert!(linker
+            .instantiate(&mut runtime, &Module::new(wasm)?)
+            .is_err());
+        let wasm = wat::parse_str(r#"(module (memory (export "memory") 1))"#)?;
+        let module = Module::new(wasm)?;
+        linker.instantiate(&mut runtime, &module)?;
+
+        // a snapshot of an unpooled runtime does not lift the limit of the slot
+        let snapshot = Runtime::instantiate(wat::parse_str("(module (memory 1))")?)?.snapshot();
+        runtime.restore(&snapshot)?;
+        let memory = runtime.get_memory("memory")?;
+        assert_eq!(
+            memory.grow(&mut runtime, 2).unwrap_err().to_string(),
+            "memory cannot grow beyond 2 pages"
+        );
+        Ok(())
+    }
+}
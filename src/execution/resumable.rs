@@ -0,0 +1,173 @@
+use std::sync::{Arc, Weak};
+
+use super::{runtime::Runtime, value::Value};
+use crate::binary::types::ValueType;
+use anyhow::{bail, Result};
+
+pub enum ResumableCall {
+    Finished(Vec<Value>),
+    Suspended(ResumableInvocation),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Suspension {
+    // the guest called a resumable host function, resume with its results
+    HostCall {
+        module: String,
+        name: String,
+        args: Vec<Value>,
+    },
+    // resume with no results after adding fuel
+    OutOfFuel,
+}
+
+// an invocation whose frames and values stay on the runtime's stacks until it is resumed
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SuspendedCall {
+    pub suspension: Suspension,
+    pub results: Vec<ValueType>,
+    pub base: usize,
+    pub sp: usize,
+    // the invoked function, its results are typed once it finishes
+    pub func: usize,
+    // the heights of the stacks when it was suspended, calls made in the meantime must
+    // have returned before it can be resumed
+    pub call_height: usize,
+    pub stack_height: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct Suspended {
+    pub call: SuspendedCall,
+    pub generation: u64,
+    // dead once the invocation is dropped, or when restored from a snapshot
+    pub handle: Weak<()>,
+}
+
+// a handle to the innermost suspended invocation of one runtime, it is invalidated as soon
+// as that invocation is resumed, so it cannot be cloned
+#[derive(Debug)]
+pub struct ResumableInvocation {
+    store: u64,
+    generation: u64,
+    suspension: Suspension,
+    _handle: Arc<()>,
+}
+
+impl ResumableInvocation {
+    pub fn suspension(&self) -> &Suspension {
+        &self.suspension
+    }
+
+    pub fn resume<T>(self, runtime: &mut Runtime<T>, values: Vec<Value>) -> Result<ResumableCall> {
+        if self.store != runtime.store.id {
+            bail!("invocation belongs to another runtime");
+        }
+        runtime.release_suspended();
+        let Some(suspended) = runtime.suspended.last() else {
+            bail!("invocation is no longer suspended");
+        };
+        if suspended.generation != self.generation || !suspended.call.is_idle(runtime) {
+            bail!("invocation is no longer suspended");
+        }
+        runtime.check_poisoned()?;
+
+        let Some(Suspended { call, .. }) = runtime.suspended.pop() else {
+            bail!("invocation is no longer suspended");
+        };
+        let types: Vec<ValueType> = values.iter().map(Value::value_type).collect();
+        if types != call.results {
+            // the embedder passed the wrong results, the guest did not trap, so the
+            // invocation is dropped without poisoning the runtime
+            runtime.call_stack.truncate(call.base);
+            runtime.stack.truncate(call.sp);
+            bail!(
+                "type mismatch in results of resumed call: expected {:?}, got {:?}",
+                call.results,
+                types
+            );
+        }
+        runtime
+            .stack
+            .extend(values.iter().map(|value| value.to_bits()));
+        runtime.run_resumable(call.base, call.sp, call.func)
+    }
+}
+
+impl SuspendedCall {
+    // no call made after it was suspended is still running
+    fn is_idle<T>(&self, runtime: &Runtime<T>) -> bool {
+        runtime.call_stack.len() == self.call_height && runtime.stack.len() == self.stack_height
+    }
+}
+
+impl<T> Runtime<T> {
+    pub(crate) fn suspend_invocation(
+        &mut self,
+        suspension: Suspension,
+        results: Vec<ValueType>,
+        base: usize,
+        sp: usize,
+        func: usize,
+    ) -> ResumableInvocation {
+        let call = SuspendedCall {
+            suspension,
+            results,
+            base,
+            sp,
+            func,
+            call_height: self.call_stack.len(),
+            stack_height: self.stack.len(),
+        };
+        self.suspended.push(Suspended {
+            call,
+            generation: 0,
+            handle: Weak::new(),
+        });
+        self.claim_suspended()
+            .expect("invocation was just suspended")
+    }
+
+    // a handle to the innermost suspended invocation when it has none, e.g. after a
+    // snapshot taken while it was suspended is restored
+    pub fn take_suspended(&mut self) -> Option<ResumableInvocation> {
+        let suspended = self.suspended.last()?;
+        if suspended.handle.strong_count() > 0 || !suspended.call.is_idle(self) {
+            return None;
+        }
+        self.claim_suspended()
+    }
+
+    fn claim_suspended(&mut self) -> Option<ResumableInvocation> {
+        let handle = Arc::new(());
+        self.generation += 1;
+        let generation = self.generation;
+        let suspended = self.suspended.last_mut()?;
+        suspended.generation = generation;
+        suspended.handle = Arc::downgrade(&handle);
+        Some(ResumableInvocation {
+            store: self.store.id,
+            generation,
+            suspension: suspended.call.suspension.clone(),
+            _handle: handle,
+        })
+    }
+
+    // drops the frames of suspended invocations whose handles are gone, only once nothing
+    // runs on top of them
+    pub(crate) fn release_suspended(&mut self) {
+        while let Some(Suspended { call, handle, .. }) = self.suspended.last() {
+            if self.call_stack.len() < call.call_height || self.stack.len() < call.stack_height {
+                // its frames were already unwound
+                self.suspended.pop();
+                continue;
+            }
+            if !call.is_idle(self) || handle.strong_count() > 0 {
+                break;
+            }
+            self.call_stack.truncate(call.base);
+            self.stack.truncate(call.sp);
+            self.suspended.pop();
+        }
+    }
+}
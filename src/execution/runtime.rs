@@ -1,468 +1,2499 @@
-use std::mem::size_of;
+use std::{
+    mem::size_of,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
 
 use super::{
-    import::Import,
-    store::{ExternalFuncInst, FuncInst, InternalFuncInst, Store},
-    value::{LabelKind, Value},
+    caller::{Caller, Extern, Global, Memory, Table},
+    compile::{Op, Reg},
+    func::{Func, IntoFunc, TypedFunc, WasmParams, WasmResults},
+    import::{HostFunc, Import, ImportFunc},
+    instance::Instance,
+    linker::Linker,
+    module::{LowerError, Module},
+    resumable::{ResumableCall, Suspended, Suspension},
+    store::{self, ExternalFuncInst, FuncInst, Store},
+    value::Value,
     wasi::WasiSnapshotPreview1,
 };
-use crate::{
-    binary::{
-        instruction::Instruction,
-        module::Module,
-        types::{ExportDesc, ValueType},
-    },
-    execution::value::Label,
-};
+use crate::binary::types::{FuncType, ImportDesc, ValueType};
 use anyhow::{anyhow, bail, Result};
 
+#[cfg(feature = "jit")]
+use super::jit;
+
 #[derive(Default)]
 pub struct Frame {
     pub pc: isize,
     pub sp: usize,
-    pub insts: Vec<Instruction>,
+    pub code: Arc<store::Func>,
     pub arity: usize,
-    pub labels: Vec<Label>,
-    pub locals: Vec<Value>,
+    // the locals followed by the operand slots of the compiled code, as raw bits
+    pub regs: Vec<u64>,
+    pub module: usize,
+    pub func: usize,
+    // fuel already charged for the next op when the previous charge ran out
+    pub prepaid: u32,
 }
 
-#[derive(Default)]
-pub struct Runtime {
-    pub store: Store,
-    pub stack: Vec<Value>,
+// unwinds an invocation that did not finish, also when its future is dropped
+struct UnwindGuard<'a, T> {
+    runtime: Option<&'a mut Runtime<T>>,
+    base: usize,
+    sp: usize,
+}
+
+impl<'a, T> UnwindGuard<'a, T> {
+    fn new(runtime: &'a mut Runtime<T>) -> Self {
+        let base = runtime.call_stack.len();
+        let sp = runtime.stack.len();
+        Self {
+            runtime: Some(runtime),
+            base,
+            sp,
+        }
+    }
+
+    fn disarm(mut self) -> &'a mut Runtime<T> {
+        self.runtime.take().expect("guard is armed")
+    }
+}
+
+impl<T> Deref for UnwindGuard<'_, T> {
+    type Target = Runtime<T>;
+
+    fn deref(&self) -> &Runtime<T> {
+        self.runtime.as_deref().expect("guard is armed")
+    }
+}
+
+impl<T> DerefMut for UnwindGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Runtime<T> {
+        self.runtime.as_deref_mut().expect("guard is armed")
+    }
+}
+
+impl<T> Drop for UnwindGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.unwind(self.base, self.sp);
+        }
+    }
+}
+
+// a call from the guest to an async or resumable host function, the interpreter stops
+// until the results are available
+pub(crate) struct PendingCall<T> {
+    pub func: ExternalFuncInst<T>,
+    pub args: Vec<Value>,
+}
+
+pub(crate) enum Interrupt<T> {
+    HostCall(PendingCall<T>),
+    OutOfFuel,
+}
+
+pub struct Runtime<T = ()> {
+    pub store: Store<T>,
+    // untyped slots, the types are known from the validated code
+    pub stack: Vec<u64>,
     pub call_stack: Vec<Frame>,
-    pub import: Import,
-    pub wasi: Option<WasiSnapshotPreview1>,
+    // host functions for modules instantiated without a linker, looked up on every call
+    pub import: Import<T>,
+    // remaining number of instructions, unlimited if None
+    pub fuel: Option<u64>,
+    // refuse further calls after a trap until a snapshot is restored
+    poison_on_trap: bool,
+    pub(crate) poisoned: bool,
+    // resumable invocations waiting for their results, the innermost last
+    pub(crate) suspended: Vec<Suspended>,
+    pub(crate) generation: u64,
+    // deeper calls trap, only set for pooled runtimes
+    pub(crate) max_call_depth: Option<usize>,
 }
 
 impl Runtime {
     pub fn instantiate(wasm: impl AsRef<[u8]>) -> Result<Self> {
-        let module = Module::new(wasm.as_ref())?;
-        let store = Store::new(module)?;
-        Ok(Self {
-            store,
-            ..Default::default()
-        })
+        Self::instantiate_with_data(wasm, ())
     }
+}
 
+impl Runtime<WasiSnapshotPreview1> {
     pub fn instantiate_with_wasi(
         wasm: impl AsRef<[u8]>,
         wasi: WasiSnapshotPreview1,
     ) -> Result<Self> {
-        let module = Module::new(wasm.as_ref())?;
-        let store = Store::new(module)?;
-        Ok(Self {
-            store,
-            wasi: Some(wasi),
-            ..Default::default()
-        })
+        let mut runtime = Self::new(wasi);
+        let mut linker = Linker::new();
+        WasiSnapshotPreview1::add_to_linker(&mut linker, |wasi| wasi)?;
+        linker.instantiate(&mut runtime, &Module::new(wasm)?)?;
+        Ok(runtime)
+    }
+}
+
+impl<T> Runtime<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            store: Store::with_data(data),
+            stack: vec![],
+            call_stack: vec![],
+            import: Import::default(),
+            fuel: None,
+            poison_on_trap: false,
+            poisoned: false,
+            suspended: vec![],
+            generation: 0,
+            max_call_depth: None,
+        }
+    }
+
+    // imported functions are resolved by name in the import map when they are called, so
+    // they can be added with add_import after instantiation
+    pub fn instantiate_with_data(wasm: impl AsRef<[u8]>, data: T) -> Result<Self> {
+        Self::instantiate_module(&Module::new(wasm)?, data)
+    }
+
+    pub(crate) fn instantiate_module(module: &Module, data: T) -> Result<Self> {
+        let mut runtime = Self::new(data);
+        let mut imports = vec![];
+        for import in module.imports() {
+            let ImportDesc::Func(type_idx) = import.desc else {
+                bail!("unknown import: {}.{}", import.module, import.field);
+            };
+            let Some(func_type) = module.inner.func_types.get(type_idx as usize) else {
+                bail!("not found func type in type_section");
+            };
+            let func = runtime.store.alloc_host_func(
+                &import.module,
+                &import.field,
+                func_type.clone(),
+                HostFunc::Sync(import_by_name(&import.module, &import.field)),
+            );
+            imports.push(Extern::Func(func));
+        }
+        runtime.store.instantiate(module, imports)?;
+        Ok(runtime)
     }
 
-    pub fn add_import(
+    pub fn add_import<Params, Results>(
         &mut self,
         module_name: impl Into<String>,
         func_name: impl Into<String>,
-        func: impl FnMut(&mut Store, Vec<Value>) -> Result<Option<Value>> + 'static,
+        func: impl IntoFunc<T, Params, Results>,
     ) -> Result<()> {
-        let import = self.import.entry(module_name.into()).or_default();
-        import.insert(func_name.into(), Box::new(func));
+        let (module_name, func_name) = (module_name.into(), func_name.into());
+        let (func_type, func) = func.into_func();
+
+        // check against the type declared by the module's import section
+        for func_inst in &self.store.funcs {
+            if let FuncInst::External(import) = func_inst
+                && import.module == module_name
+                && import.func == func_name
+                && import.func_type != func_type
+            {
+                bail!(
+                    "type mismatch for import {}.{}: expected {:?}, got {:?}",
+                    module_name,
+                    func_name,
+                    import.func_type,
+                    func_type
+                );
+            }
+        }
+
+        let import = self.import.entry(module_name).or_default();
+        import.insert(func_name, func);
         Ok(())
     }
 
-    pub fn call(&mut self, name: impl Into<String>, args: Vec<Value>) -> Result<Option<Value>> {
-        let idx = match self
-            .store
-            .module
-            .exports
-            .get(&name.into())
-            .ok_or(anyhow!("not found export function"))?
-            .desc
-        {
-            ExportDesc::Func(idx) => idx as usize,
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+    }
+
+    // the stacks are always unwound after a trap, but memory, tables and globals keep
+    // the writes made before it, so an embedder may prefer to stop using the runtime
+    pub fn set_poison_on_trap(&mut self, poison_on_trap: bool) {
+        self.poison_on_trap = poison_on_trap;
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    pub(crate) fn check_poisoned(&self) -> Result<()> {
+        if self.poisoned {
+            bail!("runtime is poisoned by an earlier trap");
+        }
+        Ok(())
+    }
+
+    pub fn data(&self) -> &T {
+        &self.store.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut T {
+        &mut self.store.data
+    }
+
+    // the most recently instantiated module is used for lookups by name
+    pub fn instance(&self) -> Result<Instance> {
+        let Some(idx) = self.store.instances.len().checked_sub(1) else {
+            bail!("not found instance");
         };
+        Ok(Instance { idx })
+    }
+
+    pub fn func_type(&self, name: impl AsRef<str>) -> Result<&FuncType> {
+        let func = self.get_func(name)?;
+        func.func_type(self)
+    }
+
+    pub fn call(&mut self, name: impl AsRef<str>, args: Vec<Value>) -> Result<Vec<Value>> {
+        self.instance()?.call(self, name, args)
+    }
+
+    pub async fn call_async(
+        &mut self,
+        name: impl AsRef<str>,
+        args: Vec<Value>,
+    ) -> Result<Vec<Value>> {
+        self.instance()?.call_async(self, name, args).await
+    }
+
+    pub fn call_resumable(
+        &mut self,
+        name: impl AsRef<str>,
+        args: Vec<Value>,
+    ) -> Result<ResumableCall> {
+        self.instance()?.call_resumable(self, name, args)
+    }
+
+    pub fn get_export(&self, name: impl AsRef<str>) -> Option<Extern> {
+        self.instance().ok()?.get_export(self, name)
+    }
+
+    pub fn get_func(&self, name: impl AsRef<str>) -> Result<Func> {
+        self.instance()?.get_func(self, name)
+    }
+
+    pub fn get_table(&self, name: impl AsRef<str>) -> Result<Table> {
+        self.instance()?.get_table(self, name)
+    }
+
+    pub fn get_memory(&self, name: impl AsRef<str>) -> Result<Memory> {
+        self.instance()?.get_memory(self, name)
+    }
+
+    pub fn get_global(&self, name: impl AsRef<str>) -> Result<Global> {
+        self.instance()?.get_global(self, name)
+    }
+
+    pub fn get_typed_func<Params, Results>(
+        &self,
+        name: impl AsRef<str>,
+    ) -> Result<TypedFunc<Params, Results>>
+    where
+        Params: WasmParams,
+        Results: WasmResults,
+    {
+        self.instance()?.get_typed_func(self, name)
+    }
+
+    pub(crate) fn invoke(&mut self, idx: usize, args: Vec<Value>) -> Result<Vec<Value>> {
+        self.release_suspended();
+        self.check_poisoned()?;
         let Some(func_inst) = self.store.funcs.get(idx) else {
             bail!("not found func")
         };
-        for arg in args {
-            self.stack.push(arg);
-        }
         match func_inst {
-            FuncInst::Internal(func) => self.invoke_internal(func.clone()),
-            FuncInst::External(func) => self.invoke_external(func.clone()),
+            FuncInst::Internal(_) => {
+                self.stack.extend(args.iter().map(|arg| arg.to_bits()));
+                self.invoke_internal(idx)
+            }
+            FuncInst::External(func) => {
+                // an exported host function fails like one called from wasm
+                let func = func.clone();
+                let base = self.call_stack.len();
+                let sp = self.stack.len();
+                self.stack.extend(args.iter().map(|arg| arg.to_bits()));
+                self.invoke_external(func)
+                    .inspect_err(|_| self.unwind(base, sp))
+            }
         }
     }
 
-    fn push_frame(&mut self, func: &InternalFuncInst) {
-        let bottom = self.stack.len() - func.func_type.params.len();
-        let mut locals = self.stack.split_off(bottom);
-
-        for local in func.code.locals.iter() {
-            match local {
-                ValueType::I32 => locals.push(Value::I32(0)),
-                ValueType::I64 => locals.push(Value::I64(0)),
-            }
+    pub(crate) fn push_frame(&mut self, idx: usize) -> Result<()> {
+        let Some(FuncInst::Internal(func)) = self.store.funcs.get(idx) else {
+            bail!("not found func");
+        };
+        if self
+            .max_call_depth
+            .is_some_and(|max| self.call_stack.len() >= max)
+        {
+            bail!("call stack exhausted");
         }
+        let code = func.code()?;
+        let bottom = stack_bottom(&self.stack, func.func_type.params.len())?;
+        let mut regs = self.stack.split_off(bottom);
+        // zero bits are the zero value of every type, so locals need no initializer
+        regs.resize(code.reg_count, 0);
 
         let arity = func.func_type.results.len();
 
         let frame = Frame {
             pc: -1,
             sp: self.stack.len(),
-            insts: func.code.body.clone(),
+            code,
             arity,
-            locals,
-            labels: vec![],
+            regs,
+            module: func.module,
+            func: idx,
+            prepaid: 0,
         };
 
         self.call_stack.push(frame);
+        Ok(())
     }
 
-    fn invoke_internal(&mut self, func: InternalFuncInst) -> Result<Option<Value>> {
-        let arity = func.func_type.results.len();
+    fn invoke_internal(&mut self, idx: usize) -> Result<Vec<Value>> {
+        let func_type = self.store.funcs[idx].func_type();
 
-        self.push_frame(&func);
+        let base = self.call_stack.len();
+        let sp = stack_bottom(&self.stack, func_type.params.len())?;
 
-        if let Err(e) = self.execute() {
-            self.cleanup();
+        // native code does not count fuel, so metered calls are always interpreted
+        #[cfg(feature = "jit")]
+        let result = if self.fuel.is_none() && self.has_native(idx) {
+            let depth = self.max_call_depth.map_or(jit::MAX_DEPTH, |max| {
+                jit::MAX_DEPTH.min(max.saturating_sub(self.call_stack.len()) as u64)
+            });
+            self.invoke_native(idx, depth)
+        } else {
+            self.interpret(idx, base)
+        };
+        #[cfg(not(feature = "jit"))]
+        let result = self.interpret(idx, base);
+        if let Err(e) = result {
+            self.abort(base, sp, &e);
             bail!("failed to execute instructions: {}", e)
         };
 
-        if arity > 0 {
-            let Some(value) = self.stack.pop() else {
-                bail!("not found return value")
+        let results = self.store.funcs[idx].func_type().results.clone();
+        take_values(&mut self.stack, &results)
+    }
+
+    // runs a function to completion, it may not call async or resumable host functions
+    pub(crate) fn interpret(&mut self, idx: usize, base: usize) -> Result<()> {
+        self.push_frame(idx)?;
+        match self.execute(base)? {
+            None => Ok(()),
+            Some(Interrupt::HostCall(PendingCall { func, .. })) => {
+                Err(unsupported_host_call(&func))
+            }
+            Some(Interrupt::OutOfFuel) => Err(anyhow!("all fuel consumed")),
+        }
+    }
+
+    fn invoke_external(&mut self, func: ExternalFuncInst<T>) -> Result<Vec<Value>> {
+        let args = take_values(&mut self.stack, &func.func_type.params)?;
+
+        let instance = self.caller_instance();
+        match func.host {
+            HostFunc::Sync(host) => host(Caller::new(self, instance), args),
+            HostFunc::Async(_) | HostFunc::Resumable => Err(unsupported_host_call(&func)),
+        }
+    }
+
+    pub(crate) async fn invoke_async(
+        &mut self,
+        idx: usize,
+        args: Vec<Value>,
+    ) -> Result<Vec<Value>> {
+        self.release_suspended();
+        self.check_poisoned()?;
+        let Some(func_inst) = self.store.funcs.get(idx) else {
+            bail!("not found func")
+        };
+        let external = match func_inst {
+            FuncInst::Internal(_) => None,
+            FuncInst::External(func) => Some(func.clone()),
+        };
+
+        // dropping the future while a host future is pending abandons the invocation, the
+        // guard unwinds it unless it runs to completion
+        let mut guard = UnwindGuard::new(self);
+        if let Some(func) = external {
+            let result = match func.host {
+                HostFunc::Sync(host) => host(Caller::new(&mut guard, None), args),
+                HostFunc::Async(host) => host(Caller::new(&mut guard, None), args).await,
+                HostFunc::Resumable => Err(unsupported_host_call(&func)),
             };
-            return Ok(Some(value));
+            if result.is_ok() {
+                guard.disarm();
+            }
+            return result;
         }
-        Ok(None)
+        let base = guard.base;
+        guard.stack.extend(args.iter().map(|arg| arg.to_bits()));
+        guard.push_frame(idx)?;
+
+        // the guest state lives in the call stack, so execution stops at every async host
+        // call and continues from the same frame once the host future has resolved
+        loop {
+            let PendingCall { func, args } = match guard.execute(base) {
+                Ok(None) => break,
+                Ok(Some(Interrupt::HostCall(pending))) => pending,
+                Ok(Some(Interrupt::OutOfFuel)) => {
+                    bail!("failed to execute instructions: all fuel consumed")
+                }
+                Err(e) => {
+                    let sp = guard.sp;
+                    guard.disarm().abort(base, sp, &e);
+                    bail!("failed to execute instructions: {}", e)
+                }
+            };
+            let instance = guard.caller_instance();
+            let result = match func.host {
+                HostFunc::Sync(host) => host(Caller::new(&mut guard, instance), args),
+                HostFunc::Async(host) => host(Caller::new(&mut guard, instance), args).await,
+                HostFunc::Resumable => Err(unsupported_host_call(&func)),
+            };
+            let result = result.and_then(|values| {
+                push_results(&mut guard.stack, &func.func_type.results, &values)
+            });
+            if let Err(e) = result {
+                bail!("failed to execute instructions: {}", e)
+            }
+        }
+
+        let runtime = guard.disarm();
+        let results = runtime.store.funcs[idx].func_type().results.clone();
+        take_values(&mut runtime.stack, &results)
     }
 
-    fn invoke_external(&mut self, func: ExternalFuncInst) -> Result<Option<Value>> {
-        let args = self
-            .stack
-            .split_off(self.stack.len() - func.func_type.params.len());
+    pub(crate) fn invoke_resumable(
+        &mut self,
+        idx: usize,
+        args: Vec<Value>,
+    ) -> Result<ResumableCall> {
+        self.release_suspended();
+        self.check_poisoned()?;
+        if self.store.funcs.get(idx).is_none() {
+            bail!("not found func")
+        }
 
-        if func.module == "wasi_snapshot_preview1" {
-            if let Some(wasi) = &mut self.wasi {
-                return wasi.invoke(&mut self.store, &func.func, args);
+        let base = self.call_stack.len();
+        let sp = self.stack.len();
+        self.stack.extend(args.iter().map(|arg| arg.to_bits()));
+        match self.call_func(idx) {
+            Ok(None) => self.run_resumable(base, sp, idx),
+            Ok(Some(pending)) => self.suspend(Interrupt::HostCall(pending), base, sp, idx),
+            Err(e) => {
+                self.abort(base, sp, &e);
+                Err(e)
             }
         }
+    }
 
-        let module = self
-            .import
-            .get_mut(&func.module)
-            .ok_or(anyhow!("not found module"))?;
-        let import_func = module
-            .get_mut(&func.func)
-            .ok_or(anyhow!("not found function"))?;
-        import_func(&mut self.store, args)
+    pub(crate) fn run_resumable(
+        &mut self,
+        base: usize,
+        sp: usize,
+        idx: usize,
+    ) -> Result<ResumableCall> {
+        match self.execute(base) {
+            Ok(None) => {
+                let results = self.store.funcs[idx].func_type().results.clone();
+                Ok(ResumableCall::Finished(take_values(
+                    &mut self.stack,
+                    &results,
+                )?))
+            }
+            Ok(Some(interrupt)) => self.suspend(interrupt, base, sp, idx),
+            Err(e) => {
+                self.abort(base, sp, &e);
+                bail!("failed to execute instructions: {}", e)
+            }
+        }
     }
 
-    fn execute(&mut self) -> Result<()> {
-        loop {
-            let Some(frame) = self.call_stack.last_mut() else {
-                break;
-            };
+    fn suspend(
+        &mut self,
+        interrupt: Interrupt<T>,
+        base: usize,
+        sp: usize,
+        idx: usize,
+    ) -> Result<ResumableCall> {
+        let (suspension, results) = match interrupt {
+            Interrupt::HostCall(PendingCall { func, args }) => {
+                if !matches!(func.host, HostFunc::Resumable) {
+                    self.unwind(base, sp);
+                    bail!(
+                        "failed to execute instructions: {}",
+                        unsupported_host_call(&func)
+                    );
+                }
+                let suspension = Suspension::HostCall {
+                    module: func.module,
+                    name: func.func,
+                    args,
+                };
+                (suspension, func.func_type.results)
+            }
+            Interrupt::OutOfFuel => (Suspension::OutOfFuel, vec![]),
+        };
+        let invocation = self.suspend_invocation(suspension, results, base, sp, idx);
+        Ok(ResumableCall::Suspended(invocation))
+    }
 
-            frame.pc += 1;
+    // only unwind this invocation, the frames below belong to the wasm code
+    // that called the host function calling back into wasm
+    pub(crate) fn unwind(&mut self, base: usize, sp: usize) {
+        // the invocation is abandoned half way, whatever it already wrote stays
+        self.poisoned |= self.poison_on_trap;
+        self.call_stack.truncate(base);
+        self.stack.truncate(sp);
+    }
+
+    // a function that failed to lower never ran, so only a trap poisons the runtime
+    fn abort(&mut self, base: usize, sp: usize, e: &anyhow::Error) {
+        if e.is::<LowerError>() {
+            self.call_stack.truncate(base);
+            self.stack.truncate(sp);
+        } else {
+            self.unwind(base, sp);
+        }
+    }
+
+    fn caller_instance(&self) -> Option<Instance> {
+        self.call_stack
+            .last()
+            .map(|frame| Instance { idx: frame.module })
+    }
 
-            let Some(inst) = frame.insts.get(frame.pc as usize) else {
+    pub(crate) fn execute(&mut self, base: usize) -> Result<Option<Interrupt<T>>> {
+        while self.call_stack.len() > base {
+            let Some(frame) = self.call_stack.last_mut() else {
+                bail!("not found frame");
+            };
+            let pc = (frame.pc + 1) as usize;
+            let Some(op) = frame.code.ops.get(pc) else {
                 break;
             };
 
-            match inst {
-                Instruction::If(block) => {
-                    let cond = self
-                        .stack
-                        .pop()
-                        .ok_or(anyhow!("not found value in the stack"))?;
-
-                    let next_pc = get_end_address(&frame.insts, frame.pc as usize)?;
-                    if cond == Value::I32(0) {
-                        frame.pc = next_pc as isize
-                    }
+            // checked before the pc moves, so the op runs once fuel is added
+            if let Some(fuel) = self.fuel.as_mut() {
+                let cost = u64::from(frame.code.costs[pc] - frame.prepaid);
+                if *fuel < cost {
+                    frame.prepaid += *fuel as u32;
+                    *fuel = 0;
+                    return Ok(Some(Interrupt::OutOfFuel));
+                }
+                *fuel -= cost;
+                frame.prepaid = 0;
+            }
+            frame.pc += 1;
 
-                    let label = Label {
-                        kind: LabelKind::If,
-                        pc: next_pc,
-                        sp: self.stack.len(),
-                        arity: block.block_type.result_count(),
+            let module = &self.store.instances[frame.module];
+            let regs = &mut frame.regs;
+            match op {
+                Op::Unreachable => bail!("unreachable"),
+                Op::Nop => {}
+                Op::Copy { dst, src } => regs[*dst as usize] = regs[*src as usize],
+                Op::Const { dst, value } => regs[*dst as usize] = *value,
+                Op::GlobalGet { dst, idx } => {
+                    let Some(addr) = module.global_addrs.get(*idx as usize) else {
+                        bail!("not found global");
                     };
-                    frame.labels.push(label);
+                    regs[*dst as usize] = self.store.globals[*addr].value.to_bits();
                 }
-                Instruction::End => match frame.labels.pop() {
-                    Some(label) => {
-                        let Label { pc, sp, arity, .. } = label;
-                        frame.pc = pc as isize;
-                        stack_unwind(&mut self.stack, sp, arity)?;
-                    }
-                    None => {
-                        let frame = self
-                            .call_stack
-                            .pop()
-                            .ok_or(anyhow!("not found value in th stack"))?;
-                        let Frame { sp, arity, .. } = frame;
-                        stack_unwind(&mut self.stack, sp, arity)?;
-                    }
-                },
-                Instruction::Return => {
-                    let Some(frame) = self.call_stack.pop() else {
-                        bail!("not found frame");
+                Op::GlobalSet { idx, src } => {
+                    let Some(addr) = module.global_addrs.get(*idx as usize) else {
+                        bail!("not found global");
                     };
-                    let Frame { sp, arity, .. } = frame;
-                    stack_unwind(&mut self.stack, sp, arity)?;
+                    let global = &mut self.store.globals[*addr];
+                    let value_type = global.value.value_type();
+                    global.value = Value::from_bits(regs[*src as usize], &value_type);
                 }
-                Instruction::LocalGet(idx) => {
-                    let Some(value) = frame.locals.get(*idx as usize) else {
-                        bail!("not found local");
+                Op::I32Store {
+                    addr,
+                    value,
+                    offset,
+                } => {
+                    let value = get_i32(regs, *value);
+                    let addr = get_i32(regs, *addr) as u32 as usize;
+                    let at = addr + *offset as usize;
+                    let end = at + size_of::<i32>();
+                    let Some(addr) = module.mem_addrs.first() else {
+                        bail!("not found memory");
                     };
-                    self.stack.push(*value);
-                }
-                Instruction::LocalSet(idx) => {
-                    let Some(value) = self.stack.pop() else {
-                        bail!("not found value in the stack");
+                    let memory = &mut self.store.memories[*addr];
+                    let Some(dst) = memory.data.get_mut(at..end) else {
+                        bail!("out of bounds memory access");
                     };
-                    let idx = *idx as usize;
-                    frame.locals[idx] = value;
+                    dst.copy_from_slice(&value.to_le_bytes());
                 }
-                Instruction::I32Store { align: _, offset } => {
-                    let (Some(value), Some(addr)) = (self.stack.pop(), self.stack.pop()) else {
-                        bail!("not found any value in the stack");
-                    };
-                    let addr = Into::<i32>::into(addr) as usize;
-                    let offset = (*offset) as usize;
-                    let at = addr + offset;
-                    let end = at + size_of::<i32>();
-                    let memory = self
-                        .store
-                        .memories
-                        .get_mut(0)
-                        .ok_or(anyhow!("not found memory"))?;
-                    let value: i32 = value.into();
-                    memory.data[at..end].copy_from_slice(&value.to_le_bytes());
+                Op::I32Add { dst, lhs, rhs } => {
+                    let right = get_i32(regs, *rhs);
+                    let left = get_i32(regs, *lhs);
+                    regs[*dst as usize] = left.wrapping_add(right) as u32 as u64;
                 }
-                Instruction::I32Const(value) => self.stack.push(Value::I32(*value)),
-                Instruction::I32Add => {
-                    let (Some(right), Some(left)) = (self.stack.pop(), self.stack.pop()) else {
-                        bail!("not found any value in the stack");
-                    };
-                    let result = left + right;
-                    self.stack.push(result);
+                Op::I32Sub { dst, lhs, rhs } => {
+                    let right = get_i32(regs, *rhs);
+                    let left = get_i32(regs, *lhs);
+                    regs[*dst as usize] = left.wrapping_sub(right) as u32 as u64;
                 }
-                Instruction::I32Sub => {
-                    let (Some(right), Some(left)) = (self.stack.pop(), self.stack.pop()) else {
-                        bail!("not found any value in the stack");
-                    };
-                    let result = left - right;
-                    self.stack.push(result);
+                Op::I32Lts { dst, lhs, rhs } => {
+                    let right = get_i32(regs, *rhs);
+                    let left = get_i32(regs, *lhs);
+                    regs[*dst as usize] = u64::from(left < right);
+                }
+                Op::I32AddImm { dst, lhs, imm } => {
+                    regs[*dst as usize] = get_i32(regs, *lhs).wrapping_add(*imm) as u32 as u64;
+                }
+                Op::I32SubImm { dst, lhs, imm } => {
+                    regs[*dst as usize] = get_i32(regs, *lhs).wrapping_sub(*imm) as u32 as u64;
+                }
+                Op::I32LtsImm { dst, lhs, imm } => {
+                    regs[*dst as usize] = u64::from(get_i32(regs, *lhs) < *imm);
+                }
+                Op::Jump { target } => frame.pc = *target as isize - 1,
+                Op::JumpIfZero { cond, target } => {
+                    if get_i32(regs, *cond) == 0 {
+                        frame.pc = *target as isize - 1;
+                    }
+                }
+                Op::JumpIfNonZero { cond, target } => {
+                    if get_i32(regs, *cond) != 0 {
+                        frame.pc = *target as isize - 1;
+                    }
+                }
+                Op::JumpIfLts { lhs, rhs, target } => {
+                    if get_i32(regs, *lhs) < get_i32(regs, *rhs) {
+                        frame.pc = *target as isize - 1;
+                    }
+                }
+                Op::JumpIfNotLts { lhs, rhs, target } => {
+                    if get_i32(regs, *lhs) >= get_i32(regs, *rhs) {
+                        frame.pc = *target as isize - 1;
+                    }
+                }
+                Op::JumpIfLtsImm { lhs, imm, target } => {
+                    if get_i32(regs, *lhs) < *imm {
+                        frame.pc = *target as isize - 1;
+                    }
+                }
+                Op::JumpIfNotLtsImm { lhs, imm, target } => {
+                    if get_i32(regs, *lhs) >= *imm {
+                        frame.pc = *target as isize - 1;
+                    }
                 }
-                Instruction::I32Lts => {
-                    let (Some(right), Some(left)) = (self.stack.pop(), self.stack.pop()) else {
-                        bail!("not found any value in the stack");
+                Op::Call { func, args } => {
+                    let Some(addr) = module.func_addrs.get(*func as usize) else {
+                        bail!("not found func");
                     };
-                    let result = left < right;
-                    self.stack.push(result.into());
+                    let addr = *addr;
+                    let params = self.store.funcs[addr].func_type().params.len();
+                    push_args(&mut self.stack, regs, *args, params)?;
+                    if let Some(pending) = self.call_func(addr)? {
+                        return Ok(Some(Interrupt::HostCall(pending)));
+                    }
                 }
-                Instruction::Call(idx) => {
-                    let Some(func) = self.store.funcs.get(*idx as usize) else {
+                Op::CallIndirect {
+                    type_idx,
+                    table_idx,
+                    elem,
+                    args,
+                } => {
+                    let (type_idx, table_idx) = (*type_idx as usize, *table_idx as usize);
+                    let elem_idx = get_i32(regs, *elem) as u32 as usize;
+                    let Some(addr) = module.table_addrs.get(table_idx) else {
+                        bail!("not found table");
+                    };
+                    let table = &self.store.tables[*addr];
+                    let Some(elem) = table.elem.get(elem_idx) else {
+                        bail!("undefined element");
+                    };
+                    let Some(idx) = *elem else {
+                        bail!("uninitialized element");
+                    };
+                    let Some(func) = self.store.funcs.get(idx) else {
                         bail!("not found func");
                     };
-                    let func_inst = func.clone();
-                    match func_inst {
-                        FuncInst::Internal(func) => self.push_frame(&func),
-                        FuncInst::External(func) => {
-                            if let Some(value) = self.invoke_external(func)? {
-                                self.stack.push(value);
-                            }
-                        }
+                    let func_type = func.func_type();
+                    if module.func_types.get(type_idx) != Some(func_type) {
+                        bail!("indirect call type mismatch");
+                    }
+                    push_args(&mut self.stack, regs, *args, func_type.params.len())?;
+                    if let Some(pending) = self.call_func(idx)? {
+                        return Ok(Some(Interrupt::HostCall(pending)));
                     }
                 }
+                Op::TakeResults { dst, count } => {
+                    let bottom = stack_bottom(&self.stack, *count as usize)?;
+                    let dst = *dst as usize;
+                    for (offset, value) in self.stack.drain(bottom..).enumerate() {
+                        regs[dst + offset] = value;
+                    }
+                }
+                Op::Return { src } => {
+                    let src = *src as usize;
+                    let Some(frame) = self.call_stack.pop() else {
+                        bail!("not found frame");
+                    };
+                    let Some(values) = frame.regs.get(src..src + frame.arity) else {
+                        bail!("not found return value");
+                    };
+                    self.stack.truncate(frame.sp);
+                    self.stack.extend_from_slice(values);
+                }
             }
         }
-        Ok(())
-    }
-
-    fn cleanup(&mut self) {
-        self.stack = vec![];
-        self.call_stack = vec![];
+        Ok(None)
     }
-}
 
-pub fn get_end_address(insts: &[Instruction], pc: usize) -> Result<usize> {
-    let mut pc = pc;
-    let mut depth = 0;
-    loop {
-        pc += 1;
-        let inst = insts.get(pc).ok_or(anyhow!("not found instructions"))?;
-        match inst {
-            Instruction::If(_) => {
-                depth += 1;
-            }
-            Instruction::End => {
-                if depth == 0 {
-                    return Ok(pc);
-                } else {
-                    depth -= 1;
+    fn call_func(&mut self, idx: usize) -> Result<Option<PendingCall<T>>> {
+        match self.store.funcs.get(idx) {
+            None => bail!("not found func"),
+            Some(FuncInst::Internal(_)) => self.push_frame(idx)?,
+            Some(FuncInst::External(func)) => {
+                let func = func.clone();
+                if let HostFunc::Async(_) | HostFunc::Resumable = func.host {
+                    let args = take_values(&mut self.stack, &func.func_type.params)?;
+                    return Ok(Some(PendingCall { func, args }));
                 }
-            }
-            _ => {
-                // do nothing
+                let values = self.invoke_external(func.clone())?;
+                push_results(&mut self.stack, &func.func_type.results, &values)?;
             }
         }
+        Ok(None)
+    }
+}
+
+pub(crate) fn unsupported_host_call<T>(func: &ExternalFuncInst<T>) -> anyhow::Error {
+    match func.host {
+        HostFunc::Async(_) => anyhow!(
+            "cannot call async host function {}.{} synchronously",
+            func.module,
+            func.func
+        ),
+        _ => anyhow!(
+            "cannot call resumable host function {}.{} outside of call_resumable",
+            func.module,
+            func.func
+        ),
     }
 }
 
-pub fn stack_unwind(stack: &mut Vec<Value>, sp: usize, arity: usize) -> Result<()> {
-    if arity > 0 {
-        let Some(value) = stack.pop() else {
-            bail!("not found return value");
+fn import_by_name<T>(module: &str, func: &str) -> ImportFunc<T> {
+    let (module, func) = (module.to_string(), func.to_string());
+    Arc::new(move |caller: Caller<'_, T>, args: Vec<Value>| {
+        let Some(import) = caller
+            .import
+            .get(&module)
+            .and_then(|import| import.get(&func))
+        else {
+            bail!("not found import: {}.{}", module, func);
         };
-        stack.drain(sp..);
-        stack.push(value);
-    } else {
-        stack.drain(sp..);
+        let import = import.clone();
+        import(caller, args)
+    })
+}
+
+pub(crate) fn check_args(name: &str, func_type: &FuncType, args: &[Value]) -> Result<()> {
+    if args.len() != func_type.params.len() {
+        bail!(
+            "invalid number of arguments for {}: expected {}, got {}",
+            name,
+            func_type.params.len(),
+            args.len()
+        );
     }
+    for (i, (arg, param)) in args.iter().zip(func_type.params.iter()).enumerate() {
+        if arg.value_type() != *param {
+            bail!(
+                "type mismatch in argument {} of {}: expected {:?}, got {:?}",
+                i,
+                name,
+                param,
+                arg.value_type()
+            );
+        }
+    }
+    Ok(())
+}
+
+// called for every call, so the error is only built when it is returned
+pub(crate) fn stack_bottom(stack: &[u64], count: usize) -> Result<usize> {
+    let Some(bottom) = stack.len().checked_sub(count) else {
+        bail!("not found value in the stack");
+    };
+    Ok(bottom)
+}
+
+// the compiler has checked the type, an i32 is kept in the low bits
+fn get_i32(regs: &[u64], reg: Reg) -> i32 {
+    regs[reg as usize] as u32 as i32
+}
+
+// values only get their types back where they leave the interpreter
+pub(crate) fn take_values(stack: &mut Vec<u64>, types: &[ValueType]) -> Result<Vec<Value>> {
+    let bottom = stack_bottom(stack, types.len())?;
+    let values = stack
+        .drain(bottom..)
+        .zip(types)
+        .map(|(bits, value_type)| Value::from_bits(bits, value_type))
+        .collect();
+    Ok(values)
+}
+
+// host functions are not validated, so their results are checked before they are untyped
+pub(crate) fn push_results(
+    stack: &mut Vec<u64>,
+    types: &[ValueType],
+    values: &[Value],
+) -> Result<()> {
+    let actual: Vec<ValueType> = values.iter().map(Value::value_type).collect();
+    if actual != types {
+        bail!(
+            "type mismatch in results of host function: expected {:?}, got {:?}",
+            types,
+            actual
+        );
+    }
+    stack.extend(values.iter().map(|value| value.to_bits()));
+    Ok(())
+}
+
+fn push_args(stack: &mut Vec<u64>, regs: &[u64], args: Reg, count: usize) -> Result<()> {
+    let args = args as usize;
+    let Some(values) = regs.get(args..args + count) else {
+        bail!("not found value in the stack");
+    };
+    stack.extend_from_slice(values);
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::Runtime;
-    use crate::execution::value::Value;
+    use crate::{
+        binary::types::{FuncType, ValueType},
+        execution::{
+            caller::Caller,
+            config::Config,
+            instance::Instance,
+            linker::Linker,
+            module::Module,
+            resumable::{ResumableCall, ResumableInvocation, Suspension},
+            store::{self, FuncInst},
+            value::Value,
+            wasi::WasiSnapshotPreview1,
+        },
+    };
     use anyhow::Result;
+    use std::{
+        collections::HashMap,
+        future::Future,
+        pin::{pin, Pin},
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+        task::{Context, Poll, Wake, Waker},
+    };
 
-    #[test]
-    fn execute_i32_add() -> Result<()> {
-        let wasm = wat::parse_file("src/fixtures/func_add.wat")?;
-        let mut runtime = Runtime::instantiate(wasm)?;
-        let tests = vec![(2, 3, 5), (10, 5, 15), (1, 1, 2)];
-
-        for (left, right, want) in tests {
-            let args = vec![Value::I32(left), Value::I32(right)];
-            let result = runtime.call("add", args)?;
-            assert_eq!(result, Some(Value::I32(want)));
+    // every test runs on the interpreter, and also on native code with the jit feature
+    fn on_backends(test: impl Fn(&Config) -> Result<()>) -> Result<()> {
+        #[cfg(feature = "jit")]
+        {
+            test(Config::new().jit(true))?;
+            test(Config::new().jit(false))
         }
-        Ok(())
+        #[cfg(not(feature = "jit"))]
+        test(&Config::new())
     }
 
-    #[test]
-    fn not_found_export_function() -> Result<()> {
-        let wasm = wat::parse_file("src/fixtures/func_add.wat")?;
-        let mut runtime = Runtime::instantiate(wasm)?;
-        let result = runtime.call("fooooo", vec![]);
-        assert!(result.is_err());
-        Ok(())
+    fn instantiate(wasm: impl AsRef<[u8]>, config: &Config) -> Result<Runtime> {
+        instantiate_with_data(wasm, (), config)
     }
 
-    #[test]
-    fn func_call() -> Result<()> {
-        let wasm = wat::parse_file("src/fixtures/func_call.wat")?;
-        let mut runtime = Runtime::instantiate(wasm)?;
-        let tests = vec![(2, 4), (10, 20), (1, 2)];
-
-        for (arg, want) in tests {
-            let args = vec![Value::I32(arg)];
-            let result = runtime.call("call_doubler", args)?;
-            assert_eq!(result, Some(Value::I32(want)));
-        }
-        Ok(())
+    fn instantiate_with_data<T>(
+        wasm: impl AsRef<[u8]>,
+        data: T,
+        config: &Config,
+    ) -> Result<Runtime<T>> {
+        Runtime::instantiate_module(&Module::new_with_config(wasm, config)?, data)
     }
 
+    #[cfg(feature = "jit")]
     #[test]
-    fn call_imported_func() -> Result<()> {
-        let wasm = wat::parse_file("src/fixtures/import.wat")?;
-        let mut runtime = Runtime::instantiate(wasm)?;
-        runtime.add_import("env", "add", |_, args| {
-            let arg = args[0];
-            Ok(Some(arg + arg))
+    fn tests_run_on_both_backends() -> Result<()> {
+        let native = Mutex::new(vec![]);
+        on_backends(|config| {
+            let runtime = instantiate(wat::parse_file("src/fixtures/fib.wat")?, config)?;
+            let idx = runtime.get_func("fib")?.idx;
+            native.lock().unwrap().push(runtime.has_native(idx));
+            Ok(())
         })?;
-        let tests = vec![(2, 4), (10, 20), (1, 2)];
-
-        for (arg, want) in tests {
-            let args = vec![Value::I32(arg)];
-            let result = runtime.call("call_add", args)?;
-            assert_eq!(result, Some(Value::I32(want)));
-        }
+        assert_eq!(native.into_inner().unwrap(), vec![true, false]);
         Ok(())
     }
 
     #[test]
-    fn not_found_imported_func() -> Result<()> {
-        let wasm = wat::parse_file("src/fixtures/import.wat")?;
-        let mut runtime = Runtime::instantiate(wasm)?;
-        runtime.add_import("env", "fooooo", |_, _| Ok(None))?;
-        let result = runtime.call("call_add", vec![Value::I32(1)]);
-        assert!(result.is_err());
-        Ok(())
+    fn execute_i32_add() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/func_add.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let tests = vec![(2, 3, 5), (10, 5, 15), (1, 1, 2)];
+
+            for (left, right, want) in tests {
+                let args = vec![Value::I32(left), Value::I32(right)];
+                let result = runtime.call("add", args)?;
+                assert_eq!(result, vec![Value::I32(want)]);
+            }
+            Ok(())
+        })
     }
 
     #[test]
-    fn i32_const() -> Result<()> {
-        let wasm = wat::parse_file("src/fixtures/i32_const.wat")?;
-        let mut runtime = Runtime::instantiate(wasm)?;
-        let result = runtime.call("i32_const", vec![])?;
-        assert_eq!(result, Some(Value::I32(42)));
-        Ok(())
+    fn not_found_export_function() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/func_add.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let result = runtime.call("fooooo", vec![]);
+            assert!(result.is_err());
+            Ok(())
+        })
     }
 
     #[test]
-    fn local_set() -> Result<()> {
-        let wasm = wat::parse_file("src/fixtures/local_set.wat")?;
-        let mut runtime = Runtime::instantiate(wasm)?;
-        let result = runtime.call("local_set", vec![])?;
-        assert_eq!(result, Some(Value::I32(42)));
-        Ok(())
+    fn func_call() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/func_call.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let tests = vec![(2, 4), (10, 20), (1, 2)];
+
+            for (arg, want) in tests {
+                let args = vec![Value::I32(arg)];
+                let result = runtime.call("call_doubler", args)?;
+                assert_eq!(result, vec![Value::I32(want)]);
+            }
+            Ok(())
+        })
     }
 
     #[test]
-    fn i32_store() -> Result<()> {
-        let wasm = wat::parse_file("src/fixtures/i32_store.wat")?;
-        let mut runtime = Runtime::instantiate(wasm)?;
-        runtime.call("i32_store", vec![])?;
-        let memory = &runtime.store.memories[0].data;
-        assert_eq!(memory[0], 42);
-        Ok(())
+    fn call_imported_func() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            linker.func("env", "add", |arg: i32| arg + arg)?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+            let tests = vec![(2, 4), (10, 20), (1, 2)];
+
+            for (arg, want) in tests {
+                let args = vec![Value::I32(arg)];
+                let result = runtime.call("call_add", args)?;
+                assert_eq!(result, vec![Value::I32(want)]);
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn call_imported_func_by_name() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            runtime.add_import("env", "add", |arg: i32| arg + arg)?;
+            let tests = vec![(2, 4), (10, 20), (1, 2)];
+
+            for (arg, want) in tests {
+                let args = vec![Value::I32(arg)];
+                let result = runtime.call("call_add", args)?;
+                assert_eq!(result, vec![Value::I32(want)]);
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn not_found_imported_func() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            linker.func("env", "fooooo", || {})?;
+            let result = linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?);
+            assert_eq!(result.unwrap_err().to_string(), "unknown import: env.add");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn not_found_imported_func_by_name() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            runtime.add_import("env", "fooooo", || {})?;
+            let result = runtime.call("call_add", vec![Value::I32(1)]);
+            assert!(result.is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn i32_const() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/i32_const.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let result = runtime.call("i32_const", vec![])?;
+            assert_eq!(result, vec![Value::I32(42)]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn local_set() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/local_set.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let result = runtime.call("local_set", vec![])?;
+            assert_eq!(result, vec![Value::I32(42)]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn copy_locals() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/local_set.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let result = runtime.call("copy_locals", vec![Value::I32(7)])?;
+            assert_eq!(result, vec![Value::I32(7)]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn i32_store() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/i32_store.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            runtime.call("i32_store", vec![])?;
+            let memory = &runtime.store.memories[0].data;
+            assert_eq!(memory[0], 42);
+            Ok(())
+        })
     }
 
     #[test]
     fn i32_sub() -> Result<()> {
-        let wasm = wat::parse_file("src/fixtures/func_sub.wat")?;
-        let mut runtime = Runtime::instantiate(wasm)?;
-        let result = runtime.call("sub", vec![Value::I32(10), Value::I32(5)])?;
-        assert_eq!(result, Some(Value::I32(5)));
-        Ok(())
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/func_sub.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let result = runtime.call("sub", vec![Value::I32(10), Value::I32(5)])?;
+            assert_eq!(result, vec![Value::I32(5)]);
+            Ok(())
+        })
     }
 
     #[test]
     fn i32_lts() -> Result<()> {
-        let wasm = wat::parse_file("src/fixtures/func_lts.wat")?;
-        let mut runtime = Runtime::instantiate(wasm)?;
-        let result = runtime.call("lts", vec![Value::I32(10), Value::I32(5)])?;
-        assert_eq!(result, Some(Value::I32(0)));
-        Ok(())
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/func_lts.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let result = runtime.call("lts", vec![Value::I32(10), Value::I32(5)])?;
+            assert_eq!(result, vec![Value::I32(0)]);
+            Ok(())
+        })
     }
 
     #[test]
     fn fib() -> Result<()> {
-        let wasm = wat::parse_file("src/fixtures/fib.wat")?;
-        let mut runtime = Runtime::instantiate(wasm)?;
-        let tests = vec![
-            (1, 1),
-            (2, 2),
-            (3, 3),
-            (4, 5),
-            (5, 8),
-            (6, 13),
-            (7, 21),
-            (8, 34),
-            (9, 55),
-            (10, 89),
-        ];
-
-        for (arg, want) in tests {
-            let args = vec![Value::I32(arg)];
-            let result = runtime.call("fib", args)?;
-            assert_eq!(result, Some(Value::I32(want)));
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/fib.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let tests = vec![
+                (1, 1),
+                (2, 2),
+                (3, 3),
+                (4, 5),
+                (5, 8),
+                (6, 13),
+                (7, 21),
+                (8, 34),
+                (9, 55),
+                (10, 89),
+            ];
+
+            for (arg, want) in tests {
+                let args = vec![Value::I32(arg)];
+                let result = runtime.call("fib", args)?;
+                assert_eq!(result, vec![Value::I32(want)]);
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn multi_value() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/multi_value.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let tests = vec![
+                ("swap", vec![1, 2], vec![2, 1]),
+                ("call_swap", vec![1, 2], vec![2, 1]),
+                ("block_param", vec![10, 3], vec![7]),
+                ("br_results", vec![], vec![3, 4]),
+                ("if_else", vec![1], vec![1, 2]),
+                ("if_else", vec![0], vec![3, 4]),
+                ("loop_sum", vec![3], vec![6]),
+                ("loop_sum", vec![10], vec![55]),
+                ("return_results", vec![], vec![2, 3]),
+            ];
+
+            for (name, args, want) in tests {
+                let args = args.into_iter().map(Value::I32).collect();
+                let want: Vec<Value> = want.into_iter().map(Value::I32).collect();
+                let result = runtime.call(name, args)?;
+                assert_eq!(result, want, "{name}");
+                assert!(runtime.stack.is_empty());
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn func_type() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/func_add.wat")?;
+            let runtime = instantiate(wasm, config)?;
+            let func_type = runtime.func_type("add")?;
+            assert_eq!(
+                func_type,
+                &FuncType {
+                    params: vec![ValueType::I32, ValueType::I32],
+                    results: vec![ValueType::I32],
+                }
+            );
+            assert!(runtime.func_type("fooooo").is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn invalid_args() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/func_add.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+
+            let result = runtime.call("add", vec![Value::I32(1)]);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "invalid number of arguments for add: expected 2, got 1"
+            );
+
+            let result = runtime.call("add", vec![Value::I32(1), Value::I64(2)]);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "type mismatch in argument 1 of add: expected I32, got I64"
+            );
+
+            assert!(runtime.stack.is_empty());
+            assert_eq!(
+                runtime.call("add", vec![Value::I32(1), Value::I32(2)])?,
+                vec![Value::I32(3)]
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn typed_func() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/func_add.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let add = runtime.get_typed_func::<(i32, i32), i32>("add")?;
+            assert_eq!(add.call(&mut runtime, (2, 3))?, 5);
+            assert_eq!(add.call(&mut runtime, (10, 5))?, 15);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn typed_func_multi_value() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/func_reverse.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let reverse =
+                runtime.get_typed_func::<(i32, i64, f32, f64), (f64, f32, i64, i32)>("reverse")?;
+            let result = reverse.call(&mut runtime, (1, 2, 3.5, 4.5))?;
+            assert_eq!(result, (4.5, 3.5, 2, 1));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn typed_func_type_mismatch() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/func_add.wat")?;
+            let runtime = instantiate(wasm, config)?;
+            assert!(runtime.get_typed_func::<(i32, i32), i64>("add").is_err());
+            assert!(runtime.get_typed_func::<i32, i32>("add").is_err());
+            assert!(runtime.get_typed_func::<(i32, i32), ()>("add").is_err());
+            assert!(runtime.get_typed_func::<(i32, i32), i32>("fooooo").is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn func_of_another_runtime() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/func_add.wat")?;
+            let runtime = instantiate(&wasm, config)?;
+            let mut other = instantiate(&wasm, config)?;
+            let add = runtime.get_typed_func::<(i32, i32), i32>("add")?;
+            let func = runtime.get_func("add")?;
+            let message = "func 0 belongs to another runtime";
+            assert_eq!(
+                add.call(&mut other, (2, 3)).unwrap_err().to_string(),
+                message
+            );
+            assert_eq!(func.func_type(&other).unwrap_err().to_string(), message);
+            let args = vec![Value::I32(2), Value::I32(3)];
+            assert_eq!(
+                func.call(&mut other, args).unwrap_err().to_string(),
+                message
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn stateful_imported_func() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import_typed.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            let stored = Arc::new(Mutex::new(vec![]));
+            let values = stored.clone();
+            linker
+                .func("env", "add", |a: i32, b: i64| a as i64 + b)?
+                .func("env", "pair", |a: i32| (a, a))?
+                .func("env", "store", move |value: i32| {
+                    values.lock().unwrap().push(value);
+                })?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+
+            runtime.call("call_store", vec![Value::I32(1)])?;
+            runtime.call("call_store", vec![Value::I32(2)])?;
+            assert_eq!(*stored.lock().unwrap(), vec![1, 2]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn call_typed_imported_func() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import_typed.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            linker
+                .func("env", "add", |a: i32, b: i64| a as i64 + b)?
+                .func("env", "pair", |a: i32| (a, a * 2))?
+                .func("env", "store", |mut caller: Caller<'_, ()>, value: i32| {
+                    caller.store.memories[0].data[0..4].copy_from_slice(&value.to_le_bytes());
+                })?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+
+            let result = runtime.call("call_add", vec![Value::I32(1), Value::I64(2)])?;
+            assert_eq!(result, vec![Value::I64(3)]);
+
+            let result = runtime.call("call_pair", vec![Value::I32(3)])?;
+            assert_eq!(result, vec![Value::I32(3), Value::I32(6)]);
+
+            runtime.call("call_store", vec![Value::I32(42)])?;
+            assert_eq!(&runtime.store.memories[0].data[0..4], &42i32.to_le_bytes());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn call_typed_imported_func_by_name() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import_typed.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            runtime.add_import("env", "add", |a: i32, b: i64| a as i64 + b)?;
+            runtime.add_import("env", "pair", |a: i32| (a, a * 2))?;
+            runtime.add_import("env", "store", |mut caller: Caller<'_, ()>, value: i32| {
+                caller.store.memories[0].data[0..4].copy_from_slice(&value.to_le_bytes());
+            })?;
+
+            let result = runtime.call("call_add", vec![Value::I32(1), Value::I64(2)])?;
+            assert_eq!(result, vec![Value::I64(3)]);
+
+            let result = runtime.call("call_pair", vec![Value::I32(3)])?;
+            assert_eq!(result, vec![Value::I32(3), Value::I32(6)]);
+
+            runtime.call("call_store", vec![Value::I32(42)])?;
+            assert_eq!(&runtime.store.memories[0].data[0..4], &42i32.to_le_bytes());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn imported_func_error() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import_typed.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            linker
+                .func("env", "add", |a: i32, b: i64| a as i64 + b)?
+                .func("env", "pair", |_: i32| -> Result<(i32, i32)> {
+                    anyhow::bail!("failed to pair")
+                })?
+                .func("env", "store", |_: i32| {})?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+            let result = runtime.call("call_pair", vec![Value::I32(3)]);
+            assert!(result.is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn imported_func_error_by_name() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import_typed.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            runtime.add_import("env", "pair", |_: i32| -> Result<(i32, i32)> {
+                anyhow::bail!("failed to pair")
+            })?;
+            let result = runtime.call("call_pair", vec![Value::I32(3)]);
+            assert!(result.is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn imported_func_type_mismatch() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import_typed.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            linker
+                .func("env", "add", |a: i32| a)?
+                .func("env", "pair", |a: i32| (a, a))?
+                .func("env", "store", |_: i32| {})?;
+            let result = linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "type mismatch for import env.add: expected FuncType { params: [I32, I64], results: [I64] }, got FuncType { params: [I32], results: [I32] }"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn imported_func_type_mismatch_by_name() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import_typed.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let result = runtime.add_import("env", "add", |a: i32| a);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "type mismatch for import env.add: expected FuncType { params: [I32, I64], results: [I64] }, got FuncType { params: [I32], results: [I32] }"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn caller_exports() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/caller.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            linker.func(
+                "env",
+                "greet",
+                |mut caller: Caller<'_, ()>, ptr: i32, len: i32| -> Result<i32> {
+                    let memory = caller.get_memory("memory")?;
+                    let mut name = vec![0; len as usize];
+                    memory.read(&caller, ptr as usize, &mut name)?;
+
+                    let greeting = [b"hello, ".as_slice(), &name].concat();
+                    let malloc = caller.get_typed_func::<i32, i32>("malloc")?;
+                    let ptr = malloc.call(&mut caller, greeting.len() as i32)?;
+                    memory.write(&mut caller, ptr as usize, &greeting)?;
+                    Ok(ptr)
+                },
+            )?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+
+            let result = runtime.call("call_greet", vec![])?;
+            assert_eq!(result, vec![Value::I32(1024)]);
+            let memory = runtime.get_memory("memory")?;
+            assert_eq!(&memory.data(&runtime)[1024..1035], b"hello, wasm");
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn caller_exports_by_name() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/caller.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            runtime.add_import(
+                "env",
+                "greet",
+                |mut caller: Caller<'_, ()>, ptr: i32, len: i32| -> Result<i32> {
+                    let memory = caller.get_memory("memory")?;
+                    let mut name = vec![0; len as usize];
+                    memory.read(&caller, ptr as usize, &mut name)?;
+
+                    let greeting = [b"hello, ".as_slice(), &name].concat();
+                    let malloc = caller.get_typed_func::<i32, i32>("malloc")?;
+                    let ptr = malloc.call(&mut caller, greeting.len() as i32)?;
+                    memory.write(&mut caller, ptr as usize, &greeting)?;
+                    Ok(ptr)
+                },
+            )?;
+
+            let result = runtime.call("call_greet", vec![])?;
+            assert_eq!(result, vec![Value::I32(1024)]);
+            let memory = runtime.get_memory("memory")?;
+            assert_eq!(&memory.data(&runtime)[1024..1035], b"hello, wasm");
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn memory_out_of_bounds() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/caller.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            linker.func("env", "greet", |ptr: i32, _: i32| ptr)?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+            let memory = runtime.get_memory("memory")?;
+            let mut buf = [0; 4];
+            assert!(memory.read(&runtime, 65534, &mut buf).is_err());
+            assert!(memory.write(&mut runtime, usize::MAX, &buf).is_err());
+            assert!(runtime.get_memory("malloc").is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn embedder_data() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import.wat")?;
+            let mut runtime = Runtime::new(0u32);
+            let mut linker = Linker::new();
+            linker.func("env", "add", |mut caller: Caller<'_, u32>, arg: i32| {
+                *caller.data_mut() += 1;
+                arg + arg
+            })?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+
+            for arg in 0..3 {
+                runtime.call("call_add", vec![Value::I32(arg)])?;
+            }
+            assert_eq!(*runtime.data(), 3);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn embedder_data_by_name() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import.wat")?;
+            let mut runtime = instantiate_with_data(wasm, 0u32, config)?;
+            runtime.add_import("env", "add", |mut caller: Caller<'_, u32>, arg: i32| {
+                *caller.data_mut() += 1;
+                arg + arg
+            })?;
+
+            for arg in 0..3 {
+                runtime.call("call_add", vec![Value::I32(arg)])?;
+            }
+            assert_eq!(*runtime.data(), 3);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn wasi_as_embedder_data() -> Result<()> {
+        on_backends(|config| {
+            struct Host {
+                wasi: WasiSnapshotPreview1,
+            }
+
+            let wasm = wat::parse_file("src/fixtures/wasi_random_get.wat")?;
+            let host = Host {
+                wasi: WasiSnapshotPreview1::default(),
+            };
+            let mut runtime = Runtime::new(host);
+            let mut linker = Linker::new();
+            WasiSnapshotPreview1::add_to_linker(&mut linker, |host: &mut Host| &mut host.wasi)?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+
+            let result = runtime.call("_start", vec![])?;
+            assert_eq!(result, vec![Value::I32(0)]);
+            let memory = runtime.get_memory("memory")?;
+            assert!(memory.data(&runtime)[0..32].iter().any(|byte| *byte != 0));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn wasi_as_embedder_data_by_name() -> Result<()> {
+        on_backends(|config| {
+            struct Host {
+                wasi: WasiSnapshotPreview1,
+            }
+
+            let wasm = wat::parse_file("src/fixtures/wasi_random_get.wat")?;
+            let host = Host {
+                wasi: WasiSnapshotPreview1::default(),
+            };
+            let mut runtime = instantiate_with_data(wasm, host, config)?;
+            WasiSnapshotPreview1::add_to_runtime(&mut runtime, |host| &mut host.wasi)?;
+
+            let result = runtime.call("_start", vec![])?;
+            assert_eq!(result, vec![Value::I32(0)]);
+            let memory = runtime.get_memory("memory")?;
+            assert!(memory.data(&runtime)[0..32].iter().any(|byte| *byte != 0));
+            Ok(())
+        })
+    }
+
+    fn apply(mut caller: Caller<'_, ()>, idx: i32, arg: i32) -> Result<i32> {
+        let table = caller.get_table("table")?;
+        let Some(func) = table.get(&caller, idx as u32) else {
+            anyhow::bail!("not found func");
+        };
+        let func = func.typed::<_, i32, i32>(&caller)?;
+        func.call(&mut caller, arg)
+    }
+
+    fn instantiate_reentrant(config: &Config) -> Result<Runtime> {
+        let wasm = wat::parse_file("src/fixtures/reentrant.wat")?;
+        let mut runtime = Runtime::new(());
+        let mut linker = Linker::new();
+        linker.func("env", "apply", apply)?.func(
+            "env",
+            "try_apply",
+            |caller: Caller<'_, ()>, idx: i32, arg: i32| apply(caller, idx, arg).unwrap_or(-1),
+        )?;
+        linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+        Ok(runtime)
+    }
+
+    #[test]
+    fn reentrant_call() -> Result<()> {
+        on_backends(|config| {
+            let mut runtime = instantiate_reentrant(config)?;
+
+            let result = runtime.call("apply", vec![Value::I32(0), Value::I32(21)])?;
+            assert_eq!(result, vec![Value::I32(42)]);
+
+            // wasm -> host -> wasm -> host -> wasm
+            let result = runtime.call("apply", vec![Value::I32(2), Value::I32(3)])?;
+            assert_eq!(result, vec![Value::I32(12)]);
+
+            // the trap is handled by the host function and the outer frame continues
+            let result = runtime.call("try_apply", vec![Value::I32(1), Value::I32(3)])?;
+            assert_eq!(result, vec![Value::I32(99)]);
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn reentrant_call_by_name() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/reentrant.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            runtime.add_import("env", "apply", apply)?;
+            runtime.add_import(
+                "env",
+                "try_apply",
+                |caller: Caller<'_, ()>, idx: i32, arg: i32| apply(caller, idx, arg).unwrap_or(-1),
+            )?;
+
+            let result = runtime.call("apply", vec![Value::I32(0), Value::I32(21)])?;
+            assert_eq!(result, vec![Value::I32(42)]);
+
+            // wasm -> host -> wasm -> host -> wasm
+            let result = runtime.call("apply", vec![Value::I32(2), Value::I32(3)])?;
+            assert_eq!(result, vec![Value::I32(12)]);
+
+            // the trap is handled by the host function and the outer frame continues
+            let result = runtime.call("try_apply", vec![Value::I32(1), Value::I32(3)])?;
+            assert_eq!(result, vec![Value::I32(99)]);
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn reentrant_call_trap() -> Result<()> {
+        on_backends(|config| {
+            let mut runtime = instantiate_reentrant(config)?;
+
+            let result = runtime.call("apply", vec![Value::I32(1), Value::I32(3)]);
+            assert!(result.unwrap_err().to_string().ends_with("unreachable"));
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+
+            let result = runtime.call("apply", vec![Value::I32(0), Value::I32(3)])?;
+            assert_eq!(result, vec![Value::I32(6)]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn reentrant_call_trap_by_name() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/reentrant.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            runtime.add_import("env", "apply", apply)?;
+
+            let result = runtime.call("apply", vec![Value::I32(1), Value::I32(3)]);
+            assert!(result.unwrap_err().to_string().ends_with("unreachable"));
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+
+            let result = runtime.call("apply", vec![Value::I32(0), Value::I32(3)])?;
+            assert_eq!(result, vec![Value::I32(6)]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn call_indirect() -> Result<()> {
+        on_backends(|config| {
+            let mut runtime = instantiate_reentrant(config)?;
+
+            let result = runtime.call("call_indirect", vec![Value::I32(0), Value::I32(5)])?;
+            assert_eq!(result, vec![Value::I32(10)]);
+
+            let result = runtime.call("call_indirect", vec![Value::I32(3), Value::I32(5)]);
+            assert!(result
+                .unwrap_err()
+                .to_string()
+                .ends_with("undefined element"));
+
+            let result = runtime.call("call_indirect", vec![Value::I32(1), Value::I32(5)]);
+            assert!(result.unwrap_err().to_string().ends_with("unreachable"));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn link_instances() -> Result<()> {
+        on_backends(|config| {
+            let provider_module = Module::new_with_config(
+                wat::parse_file("src/fixtures/link_provider.wat")?,
+                config,
+            )?;
+            let consumer_module = Module::new_with_config(
+                wat::parse_file("src/fixtures/link_consumer.wat")?,
+                config,
+            )?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            let provider = linker.instantiate(&mut runtime, &provider_module)?;
+            linker.instance(&runtime, "provider", provider)?;
+            let consumer = linker.instantiate(&mut runtime, &consumer_module)?;
+
+            // shared function
+            let result = consumer.call(&mut runtime, "call_double", vec![Value::I32(4)])?;
+            assert_eq!(result, vec![Value::I32(8)]);
+
+            // shared memory
+            consumer.call(&mut runtime, "store", vec![Value::I32(42)])?;
+            let memory = provider.get_memory(&runtime, "memory")?;
+            assert_eq!(&memory.data(&runtime)[0..4], &42i32.to_le_bytes());
+
+            // shared table, initialized by both instances
+            let table = provider.get_table(&runtime, "table")?;
+            let triple = table
+                .get(&runtime, 1)
+                .unwrap()
+                .typed::<_, i32, i32>(&runtime)?;
+            assert_eq!(triple.call(&mut runtime, 5)?, 15);
+            let result = consumer.call(
+                &mut runtime,
+                "call_indirect",
+                vec![Value::I32(0), Value::I32(5)],
+            )?;
+            assert_eq!(result, vec![Value::I32(10)]);
+
+            // shared global
+            assert_eq!(
+                provider.call(&mut runtime, "incr", vec![])?,
+                vec![Value::I32(1)]
+            );
+            consumer.call(&mut runtime, "add_counter", vec![Value::I32(10)])?;
+            assert_eq!(
+                provider.call(&mut runtime, "incr", vec![])?,
+                vec![Value::I32(12)]
+            );
+            let counter = provider.get_global(&runtime, "counter")?;
+            assert_eq!(counter.get(&runtime), Value::I32(12));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn link_incompatible_import() -> Result<()> {
+        on_backends(|config| {
+            let provider_module = Module::new_with_config(
+                wat::parse_file("src/fixtures/link_provider.wat")?,
+                config,
+            )?;
+            let consumer_module = Module::new_with_config(
+                wat::parse_file("src/fixtures/link_consumer.wat")?,
+                config,
+            )?;
+            let mut runtime = Runtime::new(());
+            let provider = Linker::new().instantiate(&mut runtime, &provider_module)?;
+            let exports: HashMap<_, _> = provider.exports(&runtime).into_iter().collect();
+            let export = |name: &str| exports[name];
+
+            let mut linker = Linker::new();
+            linker
+                .define("provider", "double", export("incr"))?
+                .define("provider", "memory", export("memory"))?
+                .define("provider", "table", export("table"))?
+                .define("provider", "counter", export("counter"))?;
+            assert!(linker
+                .define("provider", "memory", export("memory"))
+                .is_err());
+            let result = linker.instantiate(&mut runtime, &consumer_module);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "type mismatch for import provider.double: expected FuncType { params: [I32], results: [I32] }, got FuncType { params: [], results: [I32] }"
+            );
+
+            let mut linker = Linker::new();
+            linker
+                .define("provider", "double", export("double"))?
+                .define("provider", "memory", export("memory"))?
+                .define("provider", "table", export("table"))?
+                .define("provider", "counter", export("answer"))?;
+            let result = linker.instantiate(&mut runtime, &consumer_module);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "incompatible import type for provider.counter"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn global() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/link_provider.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            let counter = runtime.get_global("counter")?;
+            counter.set(&mut runtime, Value::I32(41))?;
+            assert_eq!(runtime.call("incr", vec![])?, vec![Value::I32(42)]);
+            assert!(counter.set(&mut runtime, Value::I64(0)).is_err());
+
+            let answer = runtime.get_global("answer")?;
+            assert_eq!(answer.get(&runtime), Value::I64(42));
+            assert_eq!(
+                answer
+                    .set(&mut runtime, Value::I64(0))
+                    .unwrap_err()
+                    .to_string(),
+                "cannot set immutable global"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn instantiate_module_many_times() -> Result<()> {
+        on_backends(|config| {
+            let module = Module::new_with_config(
+                wat::parse_file("src/fixtures/link_provider.wat")?,
+                config,
+            )?;
+
+            let mut runtime = Runtime::new(());
+            let first = Linker::new().instantiate(&mut runtime, &module)?;
+            let second = Linker::new().instantiate(&mut runtime, &module)?;
+            first.call(&mut runtime, "incr", vec![])?;
+            assert_eq!(
+                first.call(&mut runtime, "incr", vec![])?,
+                vec![Value::I32(2)]
+            );
+            assert_eq!(
+                second.call(&mut runtime, "incr", vec![])?,
+                vec![Value::I32(1)]
+            );
+
+            // each thread instantiates the shared module into its own runtime
+            let handles: Vec<_> = (0..4)
+                .map(|i| {
+                    let module = module.clone();
+                    std::thread::spawn(move || -> Result<Vec<Value>> {
+                        let mut runtime = Runtime::new(());
+                        let instance = Linker::new().instantiate(&mut runtime, &module)?;
+                        instance.call(&mut runtime, "double", vec![Value::I32(i)])
+                    })
+                })
+                .collect();
+            for (i, handle) in handles.into_iter().enumerate() {
+                let result = handle.join().unwrap()?;
+                assert_eq!(result, vec![Value::I32(i as i32 * 2)]);
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn runtime_is_send() {
+        fn assert_send<T: Send>() {}
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send::<Runtime>();
+        assert_send::<Runtime<WasiSnapshotPreview1>>();
+        assert_send_sync::<Linker>();
+        assert_send_sync::<Linker<WasiSnapshotPreview1>>();
+    }
+
+    #[test]
+    fn move_runtime_across_threads() -> Result<()> {
+        on_backends(|config| {
+            let calls = Arc::new(AtomicU32::new(0));
+            let wasm = wat::parse_file("src/fixtures/import.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            let counter = calls.clone();
+            linker.func("env", "add", move |arg: i32| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                arg + arg
+            })?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+
+            let handle = std::thread::spawn(move || runtime.call("call_add", vec![Value::I32(21)]));
+            let result = handle.join().unwrap()?;
+            assert_eq!(result, vec![Value::I32(42)]);
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+            Ok(())
+        })
+    }
+
+    // a minimal executor returning the output and how many times the future was polled
+    fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+        struct Unpark(std::thread::Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
         }
-        Ok(())
+
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return (output, polls),
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    struct NoopWake;
+
+    impl Wake for NoopWake {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    // pending on the first poll, like a host future waiting for I/O
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn call_async_imported_func() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            linker.func_async("env", "add", |_: Caller<'_, ()>, arg: i32| {
+                Box::new(async move {
+                    YieldNow(false).await;
+                    arg + arg
+                })
+            })?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+
+            // the future can be spawned on a multi-threaded executor
+            fn assert_send<F: Send>(future: F) -> F {
+                future
+            }
+            let future = assert_send(runtime.call_async("call_add", vec![Value::I32(21)]));
+            let (result, polls) = block_on(future);
+            assert_eq!(result?, vec![Value::I32(42)]);
+            assert_eq!(polls, 2);
+
+            let result = runtime.call("call_add", vec![Value::I32(21)]);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "failed to execute instructions: cannot call async host function env.add synchronously"
+            );
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn call_async_caller_exports() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/caller.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            linker.func_async(
+                "env",
+                "greet",
+                |mut caller: Caller<'_, ()>, (ptr, len): (i32, i32)| {
+                    Box::new(async move {
+                        YieldNow(false).await;
+                        let memory = caller.get_memory("memory")?;
+                        let mut name = vec![0; len as usize];
+                        memory.read(&caller, ptr as usize, &mut name)?;
+
+                        let greeting = [b"hello, ".as_slice(), &name].concat();
+                        let malloc = caller.get_typed_func::<i32, i32>("malloc")?;
+                        let ptr = malloc.call(&mut caller, greeting.len() as i32)?;
+                        memory.write(&mut caller, ptr as usize, &greeting)?;
+                        Ok(ptr)
+                    })
+                },
+            )?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+
+            let (result, _) = block_on(runtime.call_async("call_greet", vec![]));
+            assert_eq!(result?, vec![Value::I32(1024)]);
+            let memory = runtime.get_memory("memory")?;
+            assert_eq!(&memory.data(&runtime)[1024..1035], b"hello, wasm");
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn call_async_imported_func_error() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            linker.func_async("env", "add", |_: Caller<'_, ()>, _: i32| {
+                Box::new(async move {
+                    YieldNow(false).await;
+                    anyhow::bail!("connection refused") as Result<i32>
+                })
+            })?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+
+            let (result, _) = block_on(runtime.call_async("call_add", vec![Value::I32(1)]));
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "failed to execute instructions: connection refused"
+            );
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn drop_pending_call_async() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            linker.func_async("env", "add", |_: Caller<'_, ()>, arg: i32| {
+                Box::new(async move {
+                    YieldNow(false).await;
+                    arg + arg
+                })
+            })?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+
+            {
+                let waker = Waker::from(Arc::new(NoopWake));
+                let mut cx = Context::from_waker(&waker);
+                let mut future = pin!(runtime.call_async("call_add", vec![Value::I32(1)]));
+                assert!(future.as_mut().poll(&mut cx).is_pending());
+            }
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+
+            let (result, _) = block_on(runtime.call_async("call_add", vec![Value::I32(21)]));
+            assert_eq!(result?, vec![Value::I32(42)]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn call_resumable_host_func() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/import.wat")?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            let func_type = FuncType {
+                params: vec![ValueType::I32],
+                results: vec![ValueType::I32],
+            };
+            linker.resumable_func("env", "add", func_type)?;
+            linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+
+            let ResumableCall::Suspended(invocation) =
+                runtime.call_resumable("call_add", vec![Value::I32(21)])?
+            else {
+                panic!("expected the call to be suspended");
+            };
+            assert_eq!(
+                invocation.suspension(),
+                &Suspension::HostCall {
+                    module: "env".into(),
+                    name: "add".into(),
+                    args: vec![Value::I32(21)],
+                }
+            );
+            assert_eq!(runtime.call_stack.len(), 1);
+
+            let ResumableCall::Finished(result) =
+                invocation.resume(&mut runtime, vec![Value::I32(42)])?
+            else {
+                panic!("expected the call to finish");
+            };
+            assert_eq!(result, vec![Value::I32(42)]);
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+
+            let ResumableCall::Suspended(invocation) =
+                runtime.call_resumable("call_add", vec![Value::I32(1)])?
+            else {
+                panic!("expected the call to be suspended");
+            };
+            assert!(invocation
+                .resume(&mut runtime, vec![Value::I64(2)])
+                .is_err());
+
+            let result = runtime.call("call_add", vec![Value::I32(1)]);
+            assert!(result.unwrap_err().to_string().ends_with(
+                "cannot call resumable host function env.add outside of call_resumable"
+            ));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn resume_stale_invocation() -> Result<()> {
+        on_backends(|config| {
+            let module =
+                Module::new_with_config(wat::parse_file("src/fixtures/import.wat")?, config)?;
+            let mut linker = Linker::new();
+            let func_type = FuncType {
+                params: vec![ValueType::I32],
+                results: vec![ValueType::I32],
+            };
+            linker.resumable_func("env", "add", func_type)?;
+            let mut runtime = Runtime::new(());
+            runtime.set_poison_on_trap(true);
+            linker.instantiate(&mut runtime, &module)?;
+            let mut other = Runtime::new(());
+            linker.instantiate(&mut other, &module)?;
+
+            let suspend = |runtime: &mut Runtime, arg: i32| -> Result<ResumableInvocation> {
+                match runtime.call_resumable("call_add", vec![Value::I32(arg)])? {
+                    ResumableCall::Suspended(invocation) => Ok(invocation),
+                    ResumableCall::Finished(_) => panic!("expected the call to be suspended"),
+                }
+            };
+            let error = |result: Result<ResumableCall>| result.err().map(|e| e.to_string());
+
+            let invocation = suspend(&mut runtime, 1)?;
+            assert_eq!(
+                error(invocation.resume(&mut other, vec![Value::I32(2)])),
+                Some("invocation belongs to another runtime".to_string())
+            );
+
+            // only the innermost invocation can be resumed
+            let outer = suspend(&mut runtime, 1)?;
+            let inner = suspend(&mut runtime, 2)?;
+            assert_eq!(
+                error(outer.resume(&mut runtime, vec![Value::I32(2)])),
+                Some("invocation is no longer suspended".to_string())
+            );
+            let ResumableCall::Finished(result) =
+                inner.resume(&mut runtime, vec![Value::I32(4)])?
+            else {
+                panic!("expected the call to finish");
+            };
+            assert_eq!(result, vec![Value::I32(4)]);
+            // the frames of the dropped outer invocation stay until the next call
+            assert_eq!(runtime.call_stack.len(), 1);
+
+            // the wrong results drop the invocation without poisoning the runtime
+            let invocation = suspend(&mut runtime, 1)?;
+            assert_eq!(runtime.call_stack.len(), 1);
+            assert_eq!(
+                error(invocation.resume(&mut runtime, vec![Value::I64(2)])),
+                Some(
+                    "type mismatch in results of resumed call: expected [I32], got [I64]"
+                        .to_string()
+                )
+            );
+            assert!(!runtime.is_poisoned());
+            assert!(runtime.call_stack.is_empty());
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.suspended.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn frames_share_function_code() -> Result<()> {
+        on_backends(|config| {
+            let module =
+                Module::new_with_config(wat::parse_file("src/fixtures/import.wat")?, config)?;
+            let mut runtime = Runtime::new(());
+            let mut linker = Linker::new();
+            let func_type = FuncType {
+                params: vec![ValueType::I32],
+                results: vec![ValueType::I32],
+            };
+            linker.resumable_func("env", "add", func_type)?;
+            let first = linker.instantiate(&mut runtime, &module)?;
+            let second = linker.instantiate(&mut runtime, &module)?;
+
+            let code = |runtime: &Runtime, instance: Instance| -> Result<Arc<store::Func>> {
+                let func = instance.get_func(runtime, "call_add")?;
+                let FuncInst::Internal(func) = &runtime.store.funcs[func.idx] else {
+                    panic!("expected an internal function");
+                };
+                func.code()
+            };
+            assert!(Arc::ptr_eq(
+                &code(&runtime, first)?,
+                &code(&runtime, second)?
+            ));
+
+            let ResumableCall::Suspended(_) =
+                runtime.call_resumable("call_add", vec![Value::I32(1)])?
+            else {
+                panic!("expected the call to be suspended");
+            };
+            assert!(Arc::ptr_eq(
+                &runtime.call_stack[0].code,
+                &code(&runtime, second)?
+            ));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn call_resumable_out_of_fuel() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/fib.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            runtime.set_fuel(10);
+
+            let mut suspensions = 0;
+            let mut call = runtime.call_resumable("fib", vec![Value::I32(10)])?;
+            let result = loop {
+                match call {
+                    ResumableCall::Finished(result) => break result,
+                    ResumableCall::Suspended(invocation) => {
+                        assert_eq!(invocation.suspension(), &Suspension::OutOfFuel);
+                        assert_eq!(runtime.fuel(), Some(0));
+                        suspensions += 1;
+                        runtime.set_fuel(10);
+                        call = invocation.resume(&mut runtime, vec![])?;
+                    }
+                }
+            };
+            assert_eq!(result, vec![Value::I32(89)]);
+            assert!(suspensions > 1);
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+
+            runtime.set_fuel(10);
+            let result = runtime.call("fib", vec![Value::I32(10)]);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "failed to execute instructions: all fuel consumed"
+            );
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn fuel_counts_wasm_instructions() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/fib.wat")?;
+            let mut runtime = instantiate(wasm, config)?;
+            runtime.set_fuel(10_000);
+            runtime.call("fib", vec![Value::I32(10)])?;
+            assert_eq!(runtime.fuel(), Some(10_000 - 1854));
+
+            // ops standing for several instructions are paid for across suspensions
+            runtime.set_fuel(1);
+            let mut given = 1;
+            let mut call = runtime.call_resumable("fib", vec![Value::I32(10)])?;
+            let result = loop {
+                match call {
+                    ResumableCall::Finished(result) => break result,
+                    ResumableCall::Suspended(invocation) => {
+                        runtime.set_fuel(1);
+                        given += 1;
+                        call = invocation.resume(&mut runtime, vec![])?;
+                    }
+                }
+            };
+            assert_eq!(result, vec![Value::I32(89)]);
+            assert_eq!(given - runtime.fuel().unwrap_or(0), 1854);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn fused_and_unfused_agree() -> Result<()> {
+        on_backends(|config| {
+            let tests = vec![
+                ("fib.wat", "fib", vec![Value::I32(10)]),
+                ("fib.wat", "fib", vec![Value::I32(1)]),
+                ("multi_value.wat", "loop_sum", vec![Value::I32(10)]),
+                (
+                    "multi_value.wat",
+                    "block_param",
+                    vec![Value::I32(5), Value::I32(3)],
+                ),
+                ("multi_value.wat", "if_else", vec![Value::I32(0)]),
+            ];
+            for (file, name, args) in tests {
+                let wasm = wat::parse_file(format!("src/fixtures/{file}"))?;
+                let mut outcomes = vec![];
+                for fuse in [true, false] {
+                    let module = Module::new_with_config(&wasm, config.clone().fuse(fuse))?;
+                    let mut runtime = Runtime::new(());
+                    Linker::new().instantiate(&mut runtime, &module)?;
+                    runtime.set_fuel(10_000);
+                    let result = runtime.call(name, args.clone())?;
+                    outcomes.push((result, runtime.fuel()));
+                }
+                assert_eq!(outcomes[0], outcomes[1], "{name}");
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn interleave_guests() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_file("src/fixtures/fib.wat")?;
+            let mut guests = vec![];
+            for arg in [5, 10, 8] {
+                let mut runtime = instantiate(&wasm, config)?;
+                runtime.set_fuel(5);
+                let call = runtime.call_resumable("fib", vec![Value::I32(arg)])?;
+                guests.push((runtime, call));
+            }
+
+            // round-robin over the guests, giving each a time slice of fuel
+            let mut results = vec![None; guests.len()];
+            while results.iter().any(Option::is_none) {
+                for (i, (runtime, call)) in guests.iter_mut().enumerate() {
+                    let current = std::mem::replace(call, ResumableCall::Finished(vec![]));
+                    *call = match current {
+                        ResumableCall::Finished(result) => {
+                            results[i].get_or_insert(result);
+                            continue;
+                        }
+                        ResumableCall::Suspended(invocation) => {
+                            runtime.set_fuel(5);
+                            invocation.resume(runtime, vec![])?
+                        }
+                    };
+                }
+            }
+            let results: Vec<_> = results.into_iter().flatten().collect();
+            assert_eq!(
+                results,
+                vec![
+                    vec![Value::I32(8)],
+                    vec![Value::I32(89)],
+                    vec![Value::I32(34)]
+                ]
+            );
+            Ok(())
+        })
+    }
+
+    fn instantiate_trap(config: &Config) -> Result<Runtime> {
+        let wasm = wat::parse_file("src/fixtures/trap.wat")?;
+        let mut runtime = Runtime::new(());
+        let mut linker = Linker::new();
+        linker.func("env", "fail", |_: Caller<'_, ()>| -> Result<()> {
+            anyhow::bail!("host failure")
+        })?;
+        linker.instantiate(&mut runtime, &Module::new_with_config(wasm, config)?)?;
+        Ok(runtime)
+    }
+
+    #[test]
+    fn consistent_state_after_trap() -> Result<()> {
+        on_backends(|config| {
+            let mut runtime = instantiate_trap(config)?;
+            let tests = vec![
+                ("store_then_trap", vec![], "unreachable"),
+                (
+                    "store",
+                    vec![Value::I32(65534)],
+                    "out of bounds memory access",
+                ),
+                ("store", vec![Value::I32(-1)], "out of bounds memory access"),
+                ("nested", vec![], "unreachable"),
+                ("undefined_element", vec![], "undefined element"),
+                ("call_fail", vec![], "host failure"),
+            ];
+
+            for (name, args, want) in tests {
+                let result = runtime.call(name, args);
+                assert_eq!(
+                    result.unwrap_err().to_string(),
+                    format!("failed to execute instructions: {want}"),
+                    "{name}"
+                );
+                assert!(runtime.stack.is_empty(), "{name}");
+                assert!(runtime.call_stack.is_empty(), "{name}");
+                assert_eq!(runtime.call("answer", vec![])?, vec![Value::I32(42)]);
+            }
+
+            runtime.set_fuel(100);
+            let result = runtime.call("spin", vec![]);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "failed to execute instructions: all fuel consumed"
+            );
+            assert!(runtime.stack.is_empty());
+            assert!(runtime.call_stack.is_empty());
+            runtime.set_fuel(100);
+            assert_eq!(runtime.call("answer", vec![])?, vec![Value::I32(42)]);
+
+            // writes made before the trap are not rolled back
+            let memory = runtime.get_memory("memory")?;
+            assert_eq!(&memory.data(&runtime)[0..4], &42i32.to_le_bytes());
+            assert!(!runtime.is_poisoned());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn poison_on_trap() -> Result<()> {
+        on_backends(|config| {
+            let mut runtime = instantiate_trap(config)?;
+            runtime.set_poison_on_trap(true);
+            let snapshot = runtime.snapshot();
+
+            assert_eq!(runtime.call("answer", vec![])?, vec![Value::I32(42)]);
+            assert!(runtime.call("store_then_trap", vec![]).is_err());
+            assert!(runtime.is_poisoned());
+
+            let result = runtime.call("answer", vec![]);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "runtime is poisoned by an earlier trap"
+            );
+            assert!(runtime.call_resumable("answer", vec![]).is_err());
+            let (result, _) = block_on(runtime.call_async("answer", vec![]));
+            assert!(result.is_err());
+
+            runtime.restore(&snapshot)?;
+            assert!(!runtime.is_poisoned());
+            let memory = runtime.get_memory("memory")?;
+            assert_eq!(&memory.data(&runtime)[0..4], &[0; 4]);
+            assert_eq!(runtime.call("answer", vec![])?, vec![Value::I32(42)]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn poison_on_exported_host_func_error() -> Result<()> {
+        on_backends(|config| {
+            let wasm = wat::parse_str(
+                r#"(module
+                     (import "env" "fail" (func $fail))
+                     (import "env" "fail_async" (func $fail_async))
+                     (export "fail" (func $fail))
+                     (export "fail_async" (func $fail_async)))"#,
+            )?;
+            let module = Module::new_with_config(wasm, config)?;
+            let mut linker = Linker::new();
+            linker.func("env", "fail", |_: Caller<'_, ()>| -> Result<()> {
+                anyhow::bail!("host failure")
+            })?;
+            linker.func_async("env", "fail_async", |_: Caller<'_, ()>, ()| {
+                Box::new(async move {
+                    YieldNow(false).await;
+                    anyhow::bail!("host failure") as Result<()>
+                })
+            })?;
+
+            let mut runtime = Runtime::new(());
+            runtime.set_poison_on_trap(true);
+            linker.instantiate(&mut runtime, &module)?;
+            let result = runtime.call("fail", vec![]);
+            assert_eq!(result.unwrap_err().to_string(), "host failure");
+            assert!(runtime.is_poisoned());
+
+            let mut runtime = Runtime::new(());
+            runtime.set_poison_on_trap(true);
+            linker.instantiate(&mut runtime, &module)?;
+            let (result, _) = block_on(runtime.call_async("fail_async", vec![]));
+            assert_eq!(result.unwrap_err().to_string(), "host failure");
+            assert!(runtime.is_poisoned());
+            assert!(runtime.stack.is_empty());
+            Ok(())
+        })
     }
 }
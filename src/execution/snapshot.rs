@@ -0,0 +1,741 @@
+use std::sync::Weak;
+
+use super::{
+    artifact::{decode_str, decode_value_type, decode_vec, write_str, write_types},
+    resumable::{Suspended, SuspendedCall, Suspension},
+    runtime::{Frame, Runtime},
+    store::{FuncInst, TableInst, MAX_PAGES, PAGE_SIZE},
+    value::Value,
+};
+use anyhow::{bail, Result};
+use nom::{
+    bytes::complete::{tag, take},
+    error::{Error, ErrorKind},
+    multi::count,
+    number::complete::{le_f32, le_f64, le_i32, le_i64, le_u32, le_u64, le_u8},
+    IResult,
+};
+
+const MAGIC: &[u8] = b"\0tws";
+const VERSION: u32 = 4;
+
+// zero runs shorter than this are kept inside a memory segment
+const MAX_ZERO_RUN: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    memories: Vec<MemorySnapshot>,
+    tables: Vec<TableInst>,
+    globals: Vec<Value>,
+    stacks: Option<Stacks>,
+}
+
+// only the runs of non-zero bytes are kept, so a decoded snapshot allocates no more than
+// its input until it is restored
+#[derive(Debug, Clone, PartialEq)]
+struct MemorySnapshot {
+    max: Option<u32>,
+    len: usize,
+    segments: Vec<(usize, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Stacks {
+    stack: Vec<u64>,
+    frames: Vec<FrameSnapshot>,
+    suspended: Vec<SuspendedCall>,
+}
+
+// the instructions are not copied, they are taken from the function on restore
+#[derive(Debug, Clone, PartialEq)]
+struct FrameSnapshot {
+    func: usize,
+    pc: isize,
+    sp: usize,
+    arity: usize,
+    regs: Vec<u64>,
+    prepaid: u32,
+}
+
+impl<T> Runtime<T> {
+    // should be taken at a quiescent point, i.e. when no call is running
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            memories: self
+                .store
+                .memories
+                .iter()
+                .map(|memory| MemorySnapshot {
+                    max: memory.max,
+                    len: memory.data.len(),
+                    segments: data_segments(&memory.data)
+                        .into_iter()
+                        .map(|(offset, bytes)| (offset, bytes.to_vec()))
+                        .collect(),
+                })
+                .collect(),
+            tables: self.store.tables.clone(),
+            globals: self
+                .store
+                .globals
+                .iter()
+                .map(|global| global.value)
+                .collect(),
+            stacks: None,
+        }
+    }
+
+    // also captures the frames of suspended resumable invocations
+    pub fn snapshot_with_stacks(&self) -> Snapshot {
+        let frames = self
+            .call_stack
+            .iter()
+            .map(|frame| FrameSnapshot {
+                func: frame.func,
+                pc: frame.pc,
+                sp: frame.sp,
+                arity: frame.arity,
+                regs: frame.regs.clone(),
+                prepaid: frame.prepaid,
+            })
+            .collect();
+        Snapshot {
+            stacks: Some(Stacks {
+                stack: self.stack.clone(),
+                frames,
+                suspended: self
+                    .suspended
+                    .iter()
+                    .map(|suspended| suspended.call.clone())
+                    .collect(),
+            }),
+            ..self.snapshot()
+        }
+    }
+
+    // everything is checked before the runtime is touched, a snapshot that does not fit
+    // leaves it as it was
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let store = &self.store;
+        let matches = snapshot.memories.len() == store.memories.len()
+            && snapshot.tables.len() == store.tables.len()
+            && snapshot.globals.len() == store.globals.len()
+            && snapshot
+                .globals
+                .iter()
+                .zip(store.globals.iter())
+                .all(|(value, global)| value.value_type() == global.value.value_type());
+        if !matches {
+            bail!("snapshot does not match the runtime");
+        }
+        for (memory, saved) in store.memories.iter().zip(&snapshot.memories) {
+            // bounded by the runtime's own limit, the snapshot may be crafted
+            let pages = saved.len / PAGE_SIZE as usize;
+            let max = memory.max.unwrap_or(MAX_PAGES).min(MAX_PAGES) as usize;
+            if !saved.len.is_multiple_of(PAGE_SIZE as usize) || pages > max {
+                bail!("snapshot does not match the runtime");
+            }
+        }
+        for (table, saved) in store.tables.iter().zip(&snapshot.tables) {
+            let in_bounds = table.max.is_none_or(|max| saved.elem.len() <= max as usize)
+                && saved
+                    .elem
+                    .iter()
+                    .flatten()
+                    .all(|idx| *idx < store.funcs.len());
+            if !in_bounds {
+                bail!("snapshot does not match the runtime");
+            }
+        }
+
+        let mut call_stack = vec![];
+        if let Some(stacks) = &snapshot.stacks {
+            let mut sp = 0;
+            for frame in &stacks.frames {
+                let Some(FuncInst::Internal(func)) = store.funcs.get(frame.func) else {
+                    bail!("snapshot does not match the runtime");
+                };
+                let code = func.code()?;
+                // the frame continues at pc + 1, which is charged minus what was prepaid
+                let next = usize::try_from(frame.pc + 1).ok();
+                let prepaid_fits = match next.and_then(|pc| code.costs.get(pc)) {
+                    Some(cost) => frame.prepaid <= *cost,
+                    None => frame.prepaid == 0,
+                };
+                let fits = next.is_some_and(|pc| pc <= code.ops.len())
+                    && prepaid_fits
+                    && frame.regs.len() == code.reg_count
+                    && frame.arity == func.func_type.results.len()
+                    && sp <= frame.sp
+                    && frame.sp <= stacks.stack.len();
+                if !fits {
+                    bail!("snapshot does not match the runtime");
+                }
+                sp = frame.sp;
+                call_stack.push(Frame {
+                    pc: frame.pc,
+                    sp: frame.sp,
+                    code,
+                    arity: frame.arity,
+                    regs: frame.regs.clone(),
+                    prepaid: frame.prepaid,
+                    module: func.module,
+                    func: frame.func,
+                });
+            }
+
+            let mut height = 0;
+            for call in &stacks.suspended {
+                let fits = matches!(store.funcs.get(call.func), Some(FuncInst::Internal(_)))
+                    && height <= call.base
+                    && call.base <= call.call_height
+                    && call.call_height <= call_stack.len()
+                    && call.sp <= call.stack_height
+                    && call.stack_height <= stacks.stack.len();
+                if !fits {
+                    bail!("snapshot does not match the runtime");
+                }
+                height = call.call_height;
+            }
+        }
+
+        // memories are resized first, the only step that can fail, growing ones before
+        // shrinking ones so a failure can be undone without losing bytes
+        let mut sizes: Vec<_> = self
+            .store
+            .memories
+            .iter()
+            .zip(&snapshot.memories)
+            .enumerate()
+            .map(|(idx, (memory, saved))| (idx, memory.data.len(), saved.len))
+            .collect();
+        sizes.sort_by_key(|(_, len, saved)| *saved < *len);
+        for (done, (idx, _, saved)) in sizes.iter().enumerate() {
+            if let Err(e) = self.store.memories[*idx].data.resize(*saved) {
+                for (idx, len, saved) in &sizes[..done] {
+                    if saved > len {
+                        let _ = self.store.memories[*idx].data.resize(*len);
+                    }
+                }
+                return Err(e);
+            }
+        }
+
+        // copied into the memories in place, which keeps mapped memories at their address,
+        // memories and tables keep the limits of the runtime, not those of the snapshot
+        for (memory, saved) in self.store.memories.iter_mut().zip(&snapshot.memories) {
+            memory.data.fill(0);
+            for (offset, bytes) in &saved.segments {
+                memory.data[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+            }
+        }
+        for (table, saved) in self.store.tables.iter_mut().zip(&snapshot.tables) {
+            table.elem.clone_from(&saved.elem);
+        }
+        for (global, value) in self.store.globals.iter_mut().zip(&snapshot.globals) {
+            global.value = *value;
+        }
+        if let Some(stacks) = &snapshot.stacks {
+            self.stack = stacks.stack.clone();
+            self.call_stack = call_stack;
+            // handles to the restored invocations are given out by take_suspended
+            self.suspended = stacks
+                .suspended
+                .iter()
+                .map(|call| Suspended {
+                    call: call.clone(),
+                    generation: 0,
+                    handle: Weak::new(),
+                })
+                .collect();
+        }
+        self.poisoned = false;
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend(VERSION.to_le_bytes());
+
+        write_u32(&mut buf, self.memories.len());
+        for memory in &self.memories {
+            write_max(&mut buf, memory.max);
+            write_u32(&mut buf, memory.len);
+            write_u32(&mut buf, memory.segments.len());
+            for (offset, bytes) in &memory.segments {
+                write_u32(&mut buf, *offset);
+                write_u32(&mut buf, bytes.len());
+                buf.extend(bytes);
+            }
+        }
+
+        write_u32(&mut buf, self.tables.len());
+        for table in &self.tables {
+            write_max(&mut buf, table.max);
+            write_u32(&mut buf, table.elem.len());
+            for elem in &table.elem {
+                // 0 is an uninitialized element
+                write_u32(&mut buf, elem.map_or(0, |idx| idx + 1));
+            }
+        }
+
+        write_values(&mut buf, &self.globals);
+
+        match &self.stacks {
+            None => buf.push(0),
+            Some(stacks) => {
+                buf.push(1);
+                write_slots(&mut buf, &stacks.stack);
+                write_u32(&mut buf, stacks.frames.len());
+                for frame in &stacks.frames {
+                    write_u32(&mut buf, frame.func);
+                    buf.extend((frame.pc as i64).to_le_bytes());
+                    write_u32(&mut buf, frame.sp);
+                    write_u32(&mut buf, frame.arity);
+                    write_slots(&mut buf, &frame.regs);
+                    buf.extend(frame.prepaid.to_le_bytes());
+                }
+                write_u32(&mut buf, stacks.suspended.len());
+                for call in &stacks.suspended {
+                    match &call.suspension {
+                        Suspension::HostCall { module, name, args } => {
+                            buf.push(0);
+                            write_str(&mut buf, module);
+                            write_str(&mut buf, name);
+                            write_values(&mut buf, args);
+                        }
+                        Suspension::OutOfFuel => buf.push(1),
+                    }
+                    write_types(&mut buf, &call.results);
+                    write_u32(&mut buf, call.base);
+                    write_u32(&mut buf, call.sp);
+                    write_u32(&mut buf, call.func);
+                    write_u32(&mut buf, call.call_height);
+                    write_u32(&mut buf, call.stack_height);
+                }
+            }
+        }
+        buf
+    }
+
+    pub fn decode(input: &[u8]) -> Result<Self> {
+        match decode_snapshot(input) {
+            Ok(([], snapshot)) => Ok(snapshot),
+            Ok(_) => bail!("failed to parse snapshot: trailing bytes"),
+            Err(e) => bail!("failed to parse snapshot: {}", e),
+        }
+    }
+}
+
+pub(super) fn write_u32(buf: &mut Vec<u8>, value: usize) {
+    buf.extend((value as u32).to_le_bytes());
+}
+
+pub(super) fn write_max(buf: &mut Vec<u8>, max: Option<u32>) {
+    match max {
+        None => buf.push(0),
+        Some(max) => {
+            buf.push(1);
+            buf.extend(max.to_le_bytes());
+        }
+    }
+}
+
+// the stacks hold untyped bits, only the frames' code knows their types
+fn write_slots(buf: &mut Vec<u8>, slots: &[u64]) {
+    write_u32(buf, slots.len());
+    for slot in slots {
+        buf.extend(slot.to_le_bytes());
+    }
+}
+
+fn write_values(buf: &mut Vec<u8>, values: &[Value]) {
+    write_u32(buf, values.len());
+    for value in values {
+        match value {
+            Value::I32(value) => {
+                buf.push(0x7F);
+                buf.extend(value.to_le_bytes());
+            }
+            Value::I64(value) => {
+                buf.push(0x7E);
+                buf.extend(value.to_le_bytes());
+            }
+            Value::F32(value) => {
+                buf.push(0x7D);
+                buf.extend(value.to_le_bytes());
+            }
+            Value::F64(value) => {
+                buf.push(0x7C);
+                buf.extend(value.to_le_bytes());
+            }
+        }
+    }
+}
+
+// linear memory is mostly zeros, so only the runs of non-zero bytes are stored
+fn data_segments(data: &[u8]) -> Vec<(usize, &[u8])> {
+    let mut segments = vec![];
+    let mut pc = 0;
+    while pc < data.len() {
+        if data[pc] == 0 {
+            pc += 1;
+            continue;
+        }
+        let start = pc;
+        let mut end = pc;
+        let mut zeros = 0;
+        while pc < data.len() && zeros < MAX_ZERO_RUN {
+            if data[pc] == 0 {
+                zeros += 1;
+            } else {
+                zeros = 0;
+                end = pc + 1;
+            }
+            pc += 1;
+        }
+        segments.push((start, &data[start..end]));
+        pc = end;
+    }
+    segments
+}
+
+fn decode_snapshot(input: &[u8]) -> IResult<&[u8], Snapshot> {
+    let (input, _) = tag(MAGIC)(input)?;
+    let (input, _) = tag(&VERSION.to_le_bytes()[..])(input)?;
+
+    let (input, memory_count) = le_u32(input)?;
+    let (input, memories) = count(decode_memory, memory_count as usize)(input)?;
+
+    let (input, table_count) = le_u32(input)?;
+    let (input, tables) = count(decode_table, table_count as usize)(input)?;
+
+    let (input, globals) = decode_values(input)?;
+
+    let (input, has_stacks) = le_u8(input)?;
+    let (input, stacks) = match has_stacks {
+        0 => (input, None),
+        _ => {
+            let (input, stack) = decode_slots(input)?;
+            let (input, frame_count) = le_u32(input)?;
+            let (input, frames) = count(decode_frame, frame_count as usize)(input)?;
+            let (input, suspended) = decode_vec(input, decode_suspended)?;
+            let stacks = Stacks {
+                stack,
+                frames,
+                suspended,
+            };
+            (input, Some(stacks))
+        }
+    };
+
+    Ok((
+        input,
+        Snapshot {
+            memories,
+            tables,
+            globals,
+            stacks,
+        },
+    ))
+}
+
+pub(super) fn decode_max(input: &[u8]) -> IResult<&[u8], Option<u32>> {
+    let (input, has_max) = le_u8(input)?;
+    match has_max {
+        0 => Ok((input, None)),
+        _ => {
+            let (input, max) = le_u32(input)?;
+            Ok((input, Some(max)))
+        }
+    }
+}
+
+// the segments are checked against the length, which is only allocated on restore
+fn decode_memory(input: &[u8]) -> IResult<&[u8], MemorySnapshot> {
+    let (input, max) = decode_max(input)?;
+    let (input, len) = le_u32(input)?;
+    let (mut input, segment_count) = le_u32(input)?;
+
+    let mut segments = vec![];
+    let mut end = 0;
+    for _ in 0..segment_count {
+        let (rest, offset) = le_u32(input)?;
+        let (rest, size) = le_u32(rest)?;
+        let (rest, bytes) = take(size)(rest)?;
+        // in order and without overlaps, as they are encoded
+        if offset < end || offset as u64 + size as u64 > len as u64 {
+            return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+        }
+        end = offset + size;
+        segments.push((offset as usize, bytes.to_vec()));
+        input = rest;
+    }
+    let memory = MemorySnapshot {
+        max,
+        len: len as usize,
+        segments,
+    };
+    Ok((input, memory))
+}
+
+fn decode_table(input: &[u8]) -> IResult<&[u8], TableInst> {
+    let (input, max) = decode_max(input)?;
+    let (input, len) = le_u32(input)?;
+    let (input, elem) = count(le_u32, len as usize)(input)?;
+    let elem = elem
+        .into_iter()
+        .map(|idx| idx.checked_sub(1).map(|idx| idx as usize))
+        .collect();
+    Ok((input, TableInst { elem, max }))
+}
+
+fn decode_value(input: &[u8]) -> IResult<&[u8], Value> {
+    let (rest, value_type) = le_u8(input)?;
+    match value_type {
+        0x7F => le_i32(rest).map(|(rest, value)| (rest, Value::I32(value))),
+        0x7E => le_i64(rest).map(|(rest, value)| (rest, Value::I64(value))),
+        0x7D => le_f32(rest).map(|(rest, value)| (rest, Value::F32(value))),
+        0x7C => le_f64(rest).map(|(rest, value)| (rest, Value::F64(value))),
+        _ => Err(nom::Err::Failure(Error::new(input, ErrorKind::Tag))),
+    }
+}
+
+fn decode_values(input: &[u8]) -> IResult<&[u8], Vec<Value>> {
+    let (input, len) = le_u32(input)?;
+    count(decode_value, len as usize)(input)
+}
+
+fn decode_slots(input: &[u8]) -> IResult<&[u8], Vec<u64>> {
+    let (input, len) = le_u32(input)?;
+    count(le_u64, len as usize)(input)
+}
+
+fn decode_frame(input: &[u8]) -> IResult<&[u8], FrameSnapshot> {
+    let (input, func) = le_u32(input)?;
+    let (input, pc) = le_i64(input)?;
+    let (input, sp) = le_u32(input)?;
+    let (input, arity) = le_u32(input)?;
+    let (input, regs) = decode_slots(input)?;
+    let (input, prepaid) = le_u32(input)?;
+    Ok((
+        input,
+        FrameSnapshot {
+            func: func as usize,
+            pc: pc as isize,
+            sp: sp as usize,
+            arity: arity as usize,
+            regs,
+            prepaid,
+        },
+    ))
+}
+
+fn decode_suspended(input: &[u8]) -> IResult<&[u8], SuspendedCall> {
+    let (rest, kind) = le_u8(input)?;
+    let (input, suspension) = match kind {
+        0 => {
+            let (rest, module) = decode_str(rest)?;
+            let (rest, name) = decode_str(rest)?;
+            let (rest, args) = decode_values(rest)?;
+            (rest, Suspension::HostCall { module, name, args })
+        }
+        1 => (rest, Suspension::OutOfFuel),
+        _ => return Err(nom::Err::Failure(Error::new(input, ErrorKind::Tag))),
+    };
+    let (input, results) = decode_vec(input, decode_value_type)?;
+    let (input, base) = le_u32(input)?;
+    let (input, sp) = le_u32(input)?;
+    let (input, func) = le_u32(input)?;
+    let (input, call_height) = le_u32(input)?;
+    let (input, stack_height) = le_u32(input)?;
+    Ok((
+        input,
+        SuspendedCall {
+            suspension,
+            results,
+            base: base as usize,
+            sp: sp as usize,
+            func: func as usize,
+            call_height: call_height as usize,
+            stack_height: stack_height as usize,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameSnapshot, Snapshot};
+    use crate::execution::{resumable::ResumableCall, runtime::Runtime, value::Value};
+    use anyhow::Result;
+
+    #[test]
+    fn snapshot_and_restore() -> Result<()> {
+        let wasm = wat::parse_file("src/fixtures/link_provider.wat")?;
+        let mut runtime = Runtime::instantiate(wasm)?;
+        let snapshot = runtime.snapshot();
+
+        runtime.call("incr", vec![])?;
+        let memory = runtime.get_memory("memory")?;
+        memory.write(&mut runtime, 100, b"dirty")?;
+
+        runtime.restore(&snapshot)?;
+        assert_eq!(runtime.get_global("counter")?.get(&runtime), Value::I32(0));
+        assert!(memory.data(&runtime).iter().all(|byte| *byte == 0));
+        assert_eq!(runtime.call("incr", vec![])?, vec![Value::I32(1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn encode_and_decode() -> Result<()> {
+        let wasm = wat::parse_file("src/fixtures/link_provider.wat")?;
+        let mut runtime = Runtime::instantiate(wasm)?;
+        runtime.call("incr", vec![])?;
+        let memory = runtime.get_memory("memory")?;
+        memory.write(&mut runtime, 0, b"hello")?;
+        memory.write(&mut runtime, 60000, b"world")?;
+
+        let snapshot = runtime.snapshot();
+        let bytes = snapshot.encode();
+        assert!(bytes.len() < 128);
+        assert_eq!(Snapshot::decode(&bytes)?, snapshot);
+
+        assert!(Snapshot::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Snapshot::decode(b"\0asm").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn restore_mismatch() -> Result<()> {
+        let mut runtime = Runtime::instantiate(wat::parse_file("src/fixtures/link_provider.wat")?)?;
+        let other = Runtime::instantiate(wat::parse_file("src/fixtures/fib.wat")?)?;
+        let result = runtime.restore(&other.snapshot());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "snapshot does not match the runtime"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_suspended_invocation() -> Result<()> {
+        let wasm = wat::parse_file("src/fixtures/fib.wat")?;
+        let mut runtime = Runtime::instantiate(wasm)?;
+        runtime.set_fuel(50);
+        let ResumableCall::Suspended(invocation) =
+            runtime.call_resumable("fib", vec![Value::I32(10)])?
+        else {
+            panic!("expected the call to be suspended");
+        };
+
+        let bytes = runtime.snapshot_with_stacks().encode();
+        let snapshot = Snapshot::decode(&bytes)?;
+
+        let finish = |runtime: &mut Runtime, mut call| -> Result<Vec<Value>> {
+            loop {
+                match call {
+                    ResumableCall::Finished(result) => return Ok(result),
+                    ResumableCall::Suspended(invocation) => {
+                        runtime.set_fuel(50);
+                        call = invocation.resume(runtime, vec![])?;
+                    }
+                }
+            }
+        };
+        let result = finish(&mut runtime, ResumableCall::Suspended(invocation))?;
+        assert_eq!(result, vec![Value::I32(89)]);
+        assert!(runtime.call_stack.is_empty());
+        assert!(runtime.take_suspended().is_none());
+
+        // roll back into the middle of the call and run it to completion again
+        runtime.restore(&snapshot)?;
+        assert!(!runtime.call_stack.is_empty());
+        let invocation = runtime.take_suspended().expect("restored invocation");
+        assert!(runtime.take_suspended().is_none());
+        let result = finish(&mut runtime, ResumableCall::Suspended(invocation))?;
+        assert_eq!(result, vec![Value::I32(89)]);
+        Ok(())
+    }
+
+    #[test]
+    fn restore_invalid_snapshot() -> Result<()> {
+        let wasm = wat::parse_file("src/fixtures/link_provider.wat")?;
+        let mut runtime = Runtime::instantiate(wasm)?;
+        let memory = runtime.get_memory("memory")?;
+        memory.write(&mut runtime, 0, b"hello")?;
+        let snapshot = runtime.snapshot();
+        runtime.call("incr", vec![])?;
+
+        let restore = |runtime: &mut Runtime, edit: &dyn Fn(&mut Snapshot)| -> String {
+            let mut snapshot = snapshot.clone();
+            edit(&mut snapshot);
+            let result = runtime.restore(&snapshot);
+            result.err().map(|e| e.to_string()).unwrap_or_default()
+        };
+        let mismatch = "snapshot does not match the runtime";
+        assert_eq!(restore(&mut runtime, &|s| s.memories[0].len = 3), mismatch);
+        assert_eq!(
+            restore(&mut runtime, &|s| s.tables[0].elem[1] = Some(99)),
+            mismatch
+        );
+        // nothing was restored
+        assert_eq!(runtime.get_global("counter")?.get(&runtime), Value::I32(1));
+        assert_eq!(&memory.data(&runtime)[0..5], b"hello");
+
+        // bounded by the maximum of the runtime's memory, not of the snapshot
+        let mut limited = Runtime::instantiate(wat::parse_str("(module (memory 1 2))")?)?;
+        let mut grown = limited.snapshot();
+        grown.memories[0].len = 3 * 65536;
+        grown.memories[0].max = None;
+        assert_eq!(limited.restore(&grown).unwrap_err().to_string(), mismatch);
+        // nor does the maximum of the snapshot replace the one of the runtime
+        let mut raised = limited.snapshot();
+        raised.memories[0].max = None;
+        limited.restore(&raised)?;
+        assert_eq!(limited.store.memories[0].max, Some(2));
+
+        let tables = r#"(module (table 1 2 funcref) (func) (elem (i32.const 0) 0))"#;
+        let mut limited = Runtime::instantiate(wat::parse_str(tables)?)?;
+        let mut grown = limited.snapshot();
+        grown.tables[0].elem.resize(3, None);
+        grown.tables[0].max = None;
+        assert_eq!(limited.restore(&grown).unwrap_err().to_string(), mismatch);
+        grown.tables[0].elem.truncate(2);
+        limited.restore(&grown)?;
+        assert_eq!(limited.store.tables[0].elem, vec![Some(0), None]);
+        assert_eq!(limited.store.tables[0].max, Some(2));
+
+        // a segment past the end is rejected without allocating the memory
+        let mut truncated = snapshot.clone();
+        truncated.memories[0].len = 0;
+        assert!(Snapshot::decode(&truncated.encode()).is_err());
+
+        let wasm = wat::parse_file("src/fixtures/fib.wat")?;
+        let mut runtime = Runtime::instantiate(wasm)?;
+        runtime.set_fuel(50);
+        let ResumableCall::Suspended(_invocation) =
+            runtime.call_resumable("fib", vec![Value::I32(10)])?
+        else {
+            panic!("expected the call to be suspended");
+        };
+        let snapshot = runtime.snapshot_with_stacks();
+        let frames = runtime.call_stack.len();
+        let edit_frame = |runtime: &mut Runtime, edit: &dyn Fn(&mut FrameSnapshot)| {
+            let mut snapshot = snapshot.clone();
+            let stacks = snapshot.stacks.as_mut().expect("stacks");
+            edit(stacks.frames.last_mut().expect("frame"));
+            runtime.restore(&snapshot).err().map(|e| e.to_string())
+        };
+        let mismatch = Some(mismatch.to_string());
+        assert_eq!(edit_frame(&mut runtime, &|f| f.regs.truncate(1)), mismatch);
+        assert_eq!(edit_frame(&mut runtime, &|f| f.pc = 100_000), mismatch);
+        assert_eq!(edit_frame(&mut runtime, &|f| f.pc = -2), mismatch);
+        assert_eq!(edit_frame(&mut runtime, &|f| f.sp = 100_000), mismatch);
+        assert_eq!(edit_frame(&mut runtime, &|f| f.arity = 2), mismatch);
+        assert_eq!(runtime.call_stack.len(), frames);
+        runtime.restore(&snapshot)?;
+        Ok(())
+    }
+}
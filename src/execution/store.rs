@@ -1,190 +1,523 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
+use super::{
+    caller::{Extern, Global, Memory, Table},
+    compile::Op,
+    func,
+    import::HostFunc,
+    memory::LinearMemory,
+    module::Module,
+    pool::Slot,
+    value::Value,
+};
 use crate::binary::{
     instruction::Instruction,
-    module::Module,
-    types::{ExportDesc, FuncType, ImportDesc, ValueType},
+    types::{ConstExpr, ExportDesc, FuncType, ImportDesc, Limits, ValueType},
 };
 use anyhow::{anyhow, bail, Result};
 
 pub const PAGE_SIZE: u32 = 65536; // 64Ki
+pub const MAX_PAGES: u32 = 65536;
 
-#[derive(Clone)]
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Default)]
 pub struct Func {
     pub locals: Vec<ValueType>,
     pub body: Vec<Instruction>,
+    // the body lowered into register based ops, with the fuel each op is charged
+    pub ops: Vec<Op>,
+    pub costs: Vec<u32>,
+    pub reg_count: usize,
+    #[cfg(feature = "jit")]
+    pub native: Option<super::jit::NativeFunc>,
 }
 
 #[derive(Clone)]
 pub struct InternalFuncInst {
     pub func_type: FuncType,
-    pub code: Func,
+    // the module defining the function, which owns its code
+    pub(crate) source: Module,
+    pub(crate) code_idx: usize,
+    pub module: usize,
 }
 
-#[derive(Debug, Clone)]
-pub struct ExternalFuncInst {
+impl InternalFuncInst {
+    // shared with the module and the frames executing it
+    pub fn code(&self) -> Result<Arc<Func>> {
+        self.source.inner.code(self.code_idx)
+    }
+}
+
+pub struct ExternalFuncInst<T> {
     pub module: String,
     pub func: String,
     pub func_type: FuncType,
+    pub host: HostFunc<T>,
 }
 
-#[derive(Clone)]
-pub enum FuncInst {
+impl<T> Clone for ExternalFuncInst<T> {
+    fn clone(&self) -> Self {
+        Self {
+            module: self.module.clone(),
+            func: self.func.clone(),
+            func_type: self.func_type.clone(),
+            host: self.host.clone(),
+        }
+    }
+}
+
+pub enum FuncInst<T> {
     Internal(InternalFuncInst),
-    External(ExternalFuncInst),
+    External(ExternalFuncInst<T>),
 }
 
-pub struct ExportInst {
-    pub name: String,
-    pub desc: ExportDesc,
+impl<T> Clone for FuncInst<T> {
+    fn clone(&self) -> Self {
+        match self {
+            FuncInst::Internal(func) => FuncInst::Internal(func.clone()),
+            FuncInst::External(func) => FuncInst::External(func.clone()),
+        }
+    }
+}
+
+impl<T> FuncInst<T> {
+    pub fn func_type(&self) -> &FuncType {
+        match self {
+            FuncInst::Internal(func) => &func.func_type,
+            FuncInst::External(func) => &func.func_type,
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct ModuleInst {
-    pub exports: HashMap<String, ExportInst>,
+    pub func_types: Vec<FuncType>,
+    pub func_addrs: Vec<usize>,
+    pub table_addrs: Vec<usize>,
+    pub mem_addrs: Vec<usize>,
+    pub global_addrs: Vec<usize>,
+    pub exports: HashMap<String, Extern>,
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct MemoryInst {
-    pub data: Vec<u8>,
+    pub data: LinearMemory,
     pub max: Option<u32>,
 }
 
-#[derive(Default)]
-pub struct Store {
-    pub funcs: Vec<FuncInst>,
-    pub module: ModuleInst,
+impl MemoryInst {
+    pub fn size(&self) -> u32 {
+        (self.data.len() / PAGE_SIZE as usize) as u32
+    }
+
+    // returns the previous size in pages, like memory.grow
+    pub fn grow(&mut self, delta: u32) -> Result<u32> {
+        let size = self.size();
+        let max = self.max.unwrap_or(MAX_PAGES).min(MAX_PAGES);
+        let Some(pages) = size.checked_add(delta).filter(|pages| *pages <= max) else {
+            bail!("memory cannot grow beyond {} pages", max);
+        };
+        self.data.resize(pages as usize * PAGE_SIZE as usize)?;
+        Ok(size)
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct TableInst {
+    pub elem: Vec<Option<usize>>,
+    pub max: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GlobalInst {
+    pub value: Value,
+    pub mutable: bool,
+}
+
+pub struct Store<T = ()> {
+    pub funcs: Vec<FuncInst<T>>,
+    pub tables: Vec<TableInst>,
     pub memories: Vec<MemoryInst>,
+    pub globals: Vec<GlobalInst>,
+    pub instances: Vec<ModuleInst>,
+    pub data: T,
+    // tells the handles of different stores apart
+    pub(crate) id: u64,
+    // memories and tables come from here when the store belongs to a pooled runtime
+    pub(crate) slot: Option<Slot>,
 }
 
 impl Store {
-    pub fn new(module: Module) -> Result<Self> {
-        let func_type_idxs = match module.function_section {
-            Some(ref idexs) => idexs.clone(),
-            _ => vec![],
-        };
-
-        let mut funcs = vec![];
-        let mut memories = vec![];
+    pub fn new(module: &Module) -> Result<Self> {
+        Self::new_with_data(module, ())
+    }
+}
 
-        if let Some(ref import_section) = module.import_section {
-            for import in import_section {
-                let module_name = import.module.clone();
-                let field = import.field.clone();
-                let func_type = match import.desc {
-                    ImportDesc::Func(type_idx) => {
-                        let Some(ref func_types) = module.type_section else {
-                            bail!("not found type_section")
-                        };
+impl<T> Store<T> {
+    pub fn with_data(data: T) -> Self {
+        Self {
+            funcs: vec![],
+            tables: vec![],
+            memories: vec![],
+            globals: vec![],
+            instances: vec![],
+            data,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            slot: None,
+        }
+    }
 
-                        let Some(func_type) = func_types.get(type_idx as usize) else {
-                            bail!("not found func type in type_section")
-                        };
+    pub fn new_with_data(module: &Module, data: T) -> Result<Self> {
+        let mut store = Self::with_data(data);
+        store.instantiate(module, vec![])?;
+        Ok(store)
+    }
 
-                        func_type.clone()
-                    }
-                };
+    pub fn alloc_host_func(
+        &mut self,
+        module: impl Into<String>,
+        func: impl Into<String>,
+        func_type: FuncType,
+        host: HostFunc<T>,
+    ) -> func::Func {
+        let func = FuncInst::External(ExternalFuncInst {
+            module: module.into(),
+            func: func.into(),
+            func_type,
+            host,
+        });
+        self.funcs.push(func);
+        func::Func {
+            store: self.id,
+            idx: self.funcs.len() - 1,
+        }
+    }
 
-                let func = FuncInst::External(ExternalFuncInst {
-                    module: module_name,
-                    func: field,
-                    func_type,
-                });
-                funcs.push(func);
+    // nothing of a failed instantiation stays in the store, so its instance index, which
+    // the functions it added refer to, is free for the next one
+    pub fn instantiate(&mut self, source: &Module, imports: Vec<Extern>) -> Result<usize> {
+        let lens = (
+            self.funcs.len(),
+            self.tables.len(),
+            self.memories.len(),
+            self.globals.len(),
+        );
+        self.allocate(source, imports).inspect_err(|_| {
+            let (funcs, tables, memories, globals) = lens;
+            self.funcs.truncate(funcs);
+            let tables = self.tables.split_off(tables);
+            let memories = self.memories.split_off(memories);
+            self.globals.truncate(globals);
+            // a slot that cannot reset them keeps fewer for the next instantiation
+            if let Some(slot) = &mut self.slot {
+                let _ = slot.release(memories, tables);
             }
-        }
+        })
+    }
 
-        if let Some(ref code_section) = module.code_section {
-            for (func_body, type_idx) in code_section.iter().zip(func_type_idxs.into_iter()) {
-                let Some(ref func_types) = module.type_section else {
-                    bail!("not found type_section")
-                };
+    fn allocate(&mut self, source: &Module, imports: Vec<Extern>) -> Result<usize> {
+        let module = &source.inner;
+        let mut module_inst = ModuleInst {
+            func_types: module.func_types.clone(),
+            ..Default::default()
+        };
+        let instance_idx = self.instances.len();
 
-                let Some(func_type) = func_types.get(type_idx as usize) else {
-                    bail!("not found func type in type_section")
-                };
+        let import_section = &module.imports;
+        if import_section.len() != imports.len() {
+            bail!(
+                "invalid number of imports: expected {}, got {}",
+                import_section.len(),
+                imports.len()
+            );
+        }
 
-                let mut locals = Vec::with_capacity(func_body.locals.len());
-                for local in func_body.locals.iter() {
-                    for _ in 0..local.type_count {
-                        locals.push(local.value_type.clone());
+        for (import, value) in import_section.iter().zip(imports) {
+            let matched = match (&import.desc, value) {
+                (ImportDesc::Func(type_idx), Extern::Func(func)) => {
+                    if func.store != self.id {
+                        bail!("func {} belongs to another runtime", func.idx);
+                    }
+                    let Some(func_type) = module_inst.func_types.get(*type_idx as usize) else {
+                        bail!("not found func type in type_section")
+                    };
+                    module_inst.func_addrs.push(func.idx);
+                    let actual = self.funcs[func.idx].func_type();
+                    if actual != func_type {
+                        bail!(
+                            "type mismatch for import {}.{}: expected {:?}, got {:?}",
+                            import.module,
+                            import.field,
+                            func_type,
+                            actual
+                        );
                     }
+                    true
                 }
-
-                let func = FuncInst::Internal(InternalFuncInst {
-                    func_type: func_type.clone(),
-                    code: Func {
-                        locals,
-                        body: func_body.code.clone(),
-                    },
-                });
-                funcs.push(func);
+                (ImportDesc::Table(table), Extern::Table(addr)) => {
+                    module_inst.table_addrs.push(addr.idx);
+                    self.tables.get(addr.idx).is_some_and(|table_inst| {
+                        match_limits(&table.limits, table_inst.elem.len() as u32, table_inst.max)
+                    })
+                }
+                (ImportDesc::Memory(memory), Extern::Memory(addr)) => {
+                    module_inst.mem_addrs.push(addr.idx);
+                    self.memories.get(addr.idx).is_some_and(|memory_inst| {
+                        match_limits(&memory.limits, memory_inst.size(), memory_inst.max)
+                    })
+                }
+                (ImportDesc::Global(global_type), Extern::Global(addr)) => {
+                    module_inst.global_addrs.push(addr.idx);
+                    self.globals.get(addr.idx).is_some_and(|global_inst| {
+                        global_inst.value.value_type() == global_type.value_type
+                            && global_inst.mutable == global_type.mutable
+                    })
+                }
+                _ => false,
+            };
+            if !matched {
+                bail!(
+                    "incompatible import type for {}.{}",
+                    import.module,
+                    import.field
+                );
             }
         }
 
-        let mut exports = HashMap::default();
-        if let Some(ref sections) = module.export_section {
-            for export in sections {
-                let name = export.name.clone();
-                let export_inst = ExportInst {
-                    name: name.clone(),
-                    desc: export.desc.clone(),
+        for (code_idx, (func_type, _)) in module.funcs.iter().enumerate() {
+            let func = FuncInst::Internal(InternalFuncInst {
+                func_type: func_type.clone(),
+                source: source.clone(),
+                code_idx,
+                module: instance_idx,
+            });
+            module_inst.func_addrs.push(self.funcs.len());
+            self.funcs.push(func);
+        }
+
+        for table in module.tables.iter() {
+            let table = match &mut self.slot {
+                Some(slot) => slot.table(&table.limits)?,
+                None => TableInst {
+                    elem: vec![None; table.limits.min as usize],
+                    max: table.limits.max,
+                },
+            };
+            module_inst.table_addrs.push(self.tables.len());
+            self.tables.push(table);
+        }
+
+        // a mapped memory starts as a copy-on-write view of the module's memory image
+        #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+        let mut initialized = false;
+        for memory in module.memories.iter() {
+            let min = memory.limits.min as usize * PAGE_SIZE as usize;
+            if let Some(slot) = &mut self.slot {
+                // owned by the store before anything can fail, so a failed instantiation
+                // hands it back to the slot
+                module_inst.mem_addrs.push(self.memories.len());
+                self.memories.push(slot.memory(&memory.limits)?);
+                let Some(memory) = self.memories.last_mut() else {
+                    bail!("not found memory");
                 };
-                exports.insert(name, export_inst);
+                #[cfg(target_os = "linux")]
+                if let Some(image) = module.memory_image()?.filter(|_| memory.data.is_guarded()) {
+                    initialized = true;
+                    memory.data.map_image(image, min)?;
+                }
+                if !initialized {
+                    memory.data.resize(min)?;
+                }
+                continue;
             }
-        };
-        let module_inst = ModuleInst { exports };
-
-        if let Some(ref sections) = module.memory_section {
-            for memory in sections {
-                let min = memory.limits.min * PAGE_SIZE;
-                let memory = MemoryInst {
-                    data: vec![0; min as usize],
-                    max: memory.limits.max,
-                };
-                memories.push(memory);
+            #[cfg(target_os = "linux")]
+            let data = match module.config.mmap_memory {
+                true => match module.memory_image()? {
+                    Some(image) => {
+                        initialized = true;
+                        LinearMemory::mmap_image(image, min)?
+                    }
+                    None => LinearMemory::mmap(min)?,
+                },
+                false => LinearMemory::new(min),
+            };
+            #[cfg(not(target_os = "linux"))]
+            let data = LinearMemory::new(min);
+            let memory = MemoryInst {
+                data,
+                max: memory.limits.max,
+            };
+            module_inst.mem_addrs.push(self.memories.len());
+            self.memories.push(memory);
+        }
+
+        for global in module.globals.iter() {
+            let value = match global.init {
+                ConstExpr::I32Const(value) => Value::I32(value),
+                ConstExpr::I64Const(value) => Value::I64(value),
+                ConstExpr::GlobalGet(idx) => {
+                    let addr = module_inst
+                        .global_addrs
+                        .get(idx as usize)
+                        .ok_or(anyhow!("not found global"))?;
+                    self.globals[*addr].value
+                }
+            };
+            let global = GlobalInst {
+                value,
+                mutable: global.global_type.mutable,
+            };
+            module_inst.global_addrs.push(self.globals.len());
+            self.globals.push(global);
+        }
+
+        for export in module.exports.iter() {
+            let idx = match export.desc {
+                ExportDesc::Func(idx)
+                | ExportDesc::Table(idx)
+                | ExportDesc::Memory(idx)
+                | ExportDesc::Global(idx) => idx as usize,
+            };
+            let addrs = match export.desc {
+                ExportDesc::Func(_) => &module_inst.func_addrs,
+                ExportDesc::Table(_) => &module_inst.table_addrs,
+                ExportDesc::Memory(_) => &module_inst.mem_addrs,
+                ExportDesc::Global(_) => &module_inst.global_addrs,
+            };
+            let Some(&idx) = addrs.get(idx) else {
+                bail!("not found exported item: {}", export.name);
+            };
+            let value = match export.desc {
+                ExportDesc::Func(_) => Extern::Func(func::Func {
+                    store: self.id,
+                    idx,
+                }),
+                ExportDesc::Table(_) => Extern::Table(Table { idx }),
+                ExportDesc::Memory(_) => Extern::Memory(Memory { idx }),
+                ExportDesc::Global(_) => Extern::Global(Global { idx }),
+            };
+            module_inst.exports.insert(export.name.clone(), value);
+        }
+
+        // every segment is checked before any is written, so a failed instantiation leaves
+        // imported tables and memories as they were
+        let mut elements = vec![];
+        for element in module.elements.iter() {
+            let addr = *module_inst
+                .table_addrs
+                .get(element.table_index as usize)
+                .ok_or(anyhow!("not found table"))?;
+            let offset = element.offset as usize;
+            if offset + element.init.len() > self.tables[addr].elem.len() {
+                bail!("elements segment does not fit");
             }
+            let funcs = element
+                .init
+                .iter()
+                .map(|func_idx| {
+                    module_inst
+                        .func_addrs
+                        .get(*func_idx as usize)
+                        .copied()
+                        .ok_or(anyhow!("not found func"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            elements.push((addr, offset, funcs));
         }
 
-        if let Some(ref sections) = module.data_section {
-            for data in sections {
-                let memory = memories
-                    .get_mut(data.memory_index as usize)
+        let mut datas = vec![];
+        if !initialized {
+            for data in module.datas.iter() {
+                let addr = *module_inst
+                    .mem_addrs
+                    .get(data.memory_index as usize)
                     .ok_or(anyhow!("not found memory"))?;
-
                 let offset = data.offset as usize;
-                let init = &data.init;
-
-                if offset + init.len() > memory.data.len() {
+                if offset + data.init.len() > self.memories[addr].data.len() {
                     bail!("data is too large to fit in memory");
                 }
-                memory.data[offset..offset + init.len()].copy_from_slice(init);
+                datas.push((addr, offset, &data.init));
             }
         }
 
-        Ok(Self {
-            funcs,
-            memories,
-            module: module_inst,
-        })
+        for (addr, offset, funcs) in elements {
+            let elem = &mut self.tables[addr].elem[offset..offset + funcs.len()];
+            for (elem, func) in elem.iter_mut().zip(funcs) {
+                *elem = Some(func);
+            }
+        }
+        for (addr, offset, init) in datas {
+            self.memories[addr].data[offset..offset + init.len()].copy_from_slice(init);
+        }
+
+        self.instances.push(module_inst);
+        Ok(instance_idx)
+    }
+}
+
+fn match_limits(limits: &Limits, min: u32, max: Option<u32>) -> bool {
+    if min < limits.min {
+        return false;
+    }
+    match limits.max {
+        Some(limit) => max.is_some_and(|max| max <= limit),
+        None => true,
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::Store;
-    use crate::binary::module::Module;
+    use crate::execution::{linker::Linker, module::Module, runtime::Runtime};
     use anyhow::Result;
 
     #[test]
     fn init_memory() -> Result<()> {
         let wasm = wat::parse_file("src/fixtures/memory.wat")?;
-        let module = Module::new(&wasm)?;
-        let store = Store::new(module)?;
+        let module = Module::new(wasm)?;
+        let store = Store::new(&module)?;
         assert_eq!(store.memories.len(), 1);
         assert_eq!(store.memories[0].data.len(), 65536);
         assert_eq!(&store.memories[0].data[0..5], b"hello");
         assert_eq!(&store.memories[0].data[5..10], b"world");
         Ok(())
     }
+
+    #[test]
+    fn failed_instantiation_leaves_store_unchanged() -> Result<()> {
+        let provider = Module::new(wat::parse_file("src/fixtures/link_provider.wat")?)?;
+        let mut runtime = Runtime::new(());
+        let mut linker = Linker::new();
+        let instance = linker.instantiate(&mut runtime, &provider)?;
+        linker.instance(&runtime, "provider", instance)?;
+        let funcs = runtime.store.funcs.len();
+
+        let wasm = wat::parse_str(
+            r#"(module
+                 (import "provider" "table" (table 2 funcref))
+                 (import "provider" "memory" (memory 1))
+                 (func $f (result i32) i32.const 7)
+                 (elem (i32.const 1) $f)
+                 (data (i32.const 0) "ab")
+                 (data (i32.const 65535) "ab"))"#,
+        )?;
+        let result = linker.instantiate(&mut runtime, &Module::new(wasm)?);
+        assert_eq!(
+            result.err().map(|e| e.to_string()),
+            Some("data is too large to fit in memory".to_string())
+        );
+        assert_eq!(runtime.store.funcs.len(), funcs);
+        assert_eq!(runtime.store.instances.len(), 1);
+        let table = runtime.get_table("table")?;
+        assert!(table.get(&runtime, 1).is_none());
+        assert_eq!(&runtime.store.memories[0].data[0..2], &[0, 0]);
+        Ok(())
+    }
 }
@@ -1,9 +1,42 @@
 use std::cmp::Ordering;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use crate::binary::types::ValueType;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Value {
     I32(i32),
     I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::I32(_) => ValueType::I32,
+            Value::I64(_) => ValueType::I64,
+            Value::F32(_) => ValueType::F32,
+            Value::F64(_) => ValueType::F64,
+        }
+    }
+    // the untyped form kept in the interpreter's registers and value stack
+    pub fn to_bits(self) -> u64 {
+        match self {
+            Value::I32(value) => value as u32 as u64,
+            Value::I64(value) => value as u64,
+            Value::F32(value) => value.to_bits() as u64,
+            Value::F64(value) => value.to_bits(),
+        }
+    }
+
+    pub fn from_bits(bits: u64, value_type: &ValueType) -> Self {
+        match value_type {
+            ValueType::I32 => Value::I32(bits as u32 as i32),
+            ValueType::I64 => Value::I64(bits as i64),
+            ValueType::F32 => Value::F32(f32::from_bits(bits as u32)),
+            ValueType::F64 => Value::F64(f64::from_bits(bits)),
+        }
+    }
 }
 
 impl From<i32> for Value {
@@ -33,12 +66,26 @@ impl From<i64> for Value {
     }
 }
 
+impl From<f32> for Value {
+    fn from(value: f32) -> Self {
+        Value::F32(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::F64(value)
+    }
+}
+
 impl std::ops::Add for Value {
     type Output = Self;
     fn add(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
             (Value::I32(left), Value::I32(right)) => Value::I32(left.wrapping_add(right)),
             (Value::I64(left), Value::I64(right)) => Value::I64(left.wrapping_add(right)),
+            (Value::F32(left), Value::F32(right)) => Value::F32(left + right),
+            (Value::F64(left), Value::F64(right)) => Value::F64(left + right),
             _ => panic!("type mismatch"),
         }
     }
@@ -48,8 +95,10 @@ impl std::ops::Sub for Value {
     type Output = Self;
     fn sub(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Value::I32(left), Value::I32(right)) => Value::I32(left - right),
-            (Value::I64(left), Value::I64(right)) => Value::I64(left - right),
+            (Value::I32(left), Value::I32(right)) => Value::I32(left.wrapping_sub(right)),
+            (Value::I64(left), Value::I64(right)) => Value::I64(left.wrapping_sub(right)),
+            (Value::F32(left), Value::F32(right)) => Value::F32(left - right),
+            (Value::F64(left), Value::F64(right)) => Value::F64(left - right),
             _ => panic!("type mismatch"),
         }
     }
@@ -60,20 +109,9 @@ impl PartialOrd for Value {
         match (self, other) {
             (Value::I32(a), Value::I32(b)) => a.partial_cmp(b),
             (Value::I64(a), Value::I64(b)) => a.partial_cmp(b),
+            (Value::F32(a), Value::F32(b)) => a.partial_cmp(b),
+            (Value::F64(a), Value::F64(b)) => a.partial_cmp(b),
             _ => panic!("type mismatch"),
         }
     }
 }
-
-#[derive(Debug, Clone, PartialEq)]
-pub enum LabelKind {
-    If,
-}
-
-#[derive(Debug, Clone)]
-pub struct Label {
-    pub kind: LabelKind,
-    pub pc: usize,
-    pub sp: usize,
-    pub arity: usize,
-}
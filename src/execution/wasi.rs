@@ -1,5 +1,5 @@
-use super::{store::Store, value::Value};
-use anyhow::Result;
+use super::{caller::Caller, linker::Linker, runtime::Runtime};
+use anyhow::{anyhow, Result};
 use rand::Rng;
 use std::{fs::File, io::prelude::*, os::fd::FromRawFd};
 
@@ -21,81 +21,115 @@ impl WasiSnapshotPreview1 {
         }
     }
 
-    pub fn invoke(
-        &mut self,
-        store: &mut Store,
-        func: &str,
-        args: Vec<Value>,
-    ) -> Result<Option<Value>> {
-        match func {
-            "fd_write" => self.fd_write(store, args),
-            _ => unimplemented!("{}", func),
-        }
+    pub fn add_to_linker<T: 'static>(
+        linker: &mut Linker<T>,
+        get: fn(&mut T) -> &mut WasiSnapshotPreview1,
+    ) -> Result<()> {
+        linker.func("wasi_snapshot_preview1", "fd_write", fd_write(get))?;
+        linker.func("wasi_snapshot_preview1", "random_get", random_get(get))?;
+        Ok(())
     }
 
-    pub fn fd_write(&mut self, store: &mut Store, args: Vec<Value>) -> Result<Option<Value>> {
-        let args: Vec<i32> = args.into_iter().map(Into::into).collect();
+    // for runtimes created with Runtime::instantiate_with_data
+    pub fn add_to_runtime<T: 'static>(
+        runtime: &mut Runtime<T>,
+        get: fn(&mut T) -> &mut WasiSnapshotPreview1,
+    ) -> Result<()> {
+        runtime.add_import("wasi_snapshot_preview1", "fd_write", fd_write(get))?;
+        runtime.add_import("wasi_snapshot_preview1", "random_get", random_get(get))?;
+        Ok(())
+    }
 
-        let fd = args[0];
-        let mut iovs = args[1] as usize;
-        let iovs_len = args[2];
-        let rp = args[3] as usize;
+    pub fn fd_write(
+        &mut self,
+        memory: &mut [u8],
+        fd: i32,
+        iovs: i32,
+        iovs_len: i32,
+        rp: i32,
+    ) -> Result<i32> {
+        let mut iovs = iovs as usize;
+        let rp = rp as usize;
 
         let file = self
             .file_table
             .get_mut(fd as usize)
-            .ok_or(anyhow::anyhow!("not found fd"))?;
-
-        let memory = store
-            .memories
-            .get_mut(0)
-            .ok_or(anyhow::anyhow!("not found memory"))?;
+            .ok_or(anyhow!("not found fd"))?;
 
         let mut nwritten = 0;
 
         for _ in 0..iovs_len {
-            let start = memory_read(&memory.data, iovs)? as usize;
+            let start = memory_read(memory, iovs)? as usize;
             iovs += 4;
 
-            let len: i32 = memory_read(&memory.data, iovs)?;
+            let len: i32 = memory_read(memory, iovs)?;
             iovs += 4;
 
             let end = start + len as usize;
-            nwritten += file.write(&memory.data[start..end])?;
+            let buf = memory
+                .get(start..end)
+                .ok_or(anyhow!("out of bounds memory access"))?;
+            nwritten += file.write(buf)?;
         }
 
-        memory_write(&mut memory.data, rp, &nwritten.to_le_bytes())?;
+        memory_write(memory, rp, &(nwritten as i32).to_le_bytes())?;
 
-        Ok(Some(0.into()))
+        Ok(0)
     }
 
-    pub fn random_get(&mut self, store: &mut Store, args: Vec<Value>) -> Result<Option<Value>> {
-        let args: Vec<i32> = args.into_iter().map(Into::into).collect();
-
-        let buf_ptr = args[0] as usize;
-        let buf_len = args[1] as usize;
+    pub fn random_get(&mut self, memory: &mut [u8], buf_ptr: i32, buf_len: i32) -> Result<i32> {
+        let buf_ptr = buf_ptr as usize;
+        let buf_len = buf_len as usize;
 
-        let memory = store
-            .memories
-            .get_mut(0)
-            .ok_or(anyhow::anyhow!("not found memory"))?;
+        let buf = memory
+            .get_mut(buf_ptr..buf_ptr + buf_len)
+            .ok_or(anyhow!("out of bounds memory access"))?;
 
         let mut rng = rand::rng();
-        for i in 0..buf_len {
-            memory.data[buf_ptr + i] = rng.random();
+        for byte in buf {
+            *byte = rng.random();
         }
 
-        Ok(Some(0.into()))
+        Ok(0)
     }
 }
 
 fn memory_read(buf: &[u8], start: usize) -> Result<i32> {
     let end = start + 4;
-    Ok(<i32>::from_le_bytes(buf[start..end].try_into()?))
+    let bytes = buf
+        .get(start..end)
+        .ok_or(anyhow!("out of bounds memory access"))?;
+    Ok(<i32>::from_le_bytes(bytes.try_into()?))
 }
 
 fn memory_write(buf: &mut [u8], start: usize, data: &[u8]) -> Result<()> {
     let end = start + data.len();
-    buf[start..end].copy_from_slice(data);
+    buf.get_mut(start..end)
+        .ok_or(anyhow!("out of bounds memory access"))?
+        .copy_from_slice(data);
     Ok(())
 }
+
+fn fd_write<T>(
+    get: fn(&mut T) -> &mut WasiSnapshotPreview1,
+) -> impl Fn(Caller<'_, T>, i32, i32, i32, i32) -> Result<i32> {
+    move |mut caller, fd, iovs, iovs_len, rp| {
+        let memory = caller.get_memory("memory")?;
+        let store = &mut caller.store;
+        let wasi = get(&mut store.data);
+        let memory = &mut store.memories[memory.idx].data;
+        wasi.fd_write(memory, fd, iovs, iovs_len, rp)
+    }
+}
+
+fn random_get<T>(
+    get: fn(&mut T) -> &mut WasiSnapshotPreview1,
+) -> impl Fn(Caller<'_, T>, i32, i32) -> Result<i32> {
+    move |mut caller, buf_ptr, buf_len| {
+        let memory = caller.get_memory("memory")?;
+        let store = &mut caller.store;
+        let wasi = get(&mut store.data);
+        let memory = &mut store.memories[memory.idx].data;
+        wasi.random_get(memory, buf_ptr, buf_len)
+    }
+}
@@ -0,0 +1,11 @@
+(module
+  (import "env" "greet" (func $greet (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 16) "wasm")
+  (func (export "malloc") (param i32) (result i32)
+    (i32.const 1024)
+  )
+  (func (export "call_greet") (result i32)
+    (call $greet (i32.const 16) (i32.const 4))
+  )
+)
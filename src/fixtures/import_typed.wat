@@ -0,0 +1,15 @@
+(module
+  (import "env" "add" (func $add (param i32 i64) (result i64)))
+  (import "env" "pair" (func $pair (param i32) (result i32 i32)))
+  (import "env" "store" (func $store (param i32)))
+  (memory 1)
+  (func (export "call_add") (param i32 i64) (result i64)
+    (call $add (local.get 0) (local.get 1))
+  )
+  (func (export "call_pair") (param i32) (result i32 i32)
+    (call $pair (local.get 0))
+  )
+  (func (export "call_store") (param i32)
+    (call $store (local.get 0))
+  )
+)
@@ -0,0 +1,23 @@
+(module
+  (type $unary (func (param i32) (result i32)))
+  (import "provider" "double" (func $double (type $unary)))
+  (import "provider" "memory" (memory 1))
+  (import "provider" "table" (table 2 funcref))
+  (import "provider" "counter" (global $counter (mut i32)))
+  (func $triple (param i32) (result i32)
+    (i32.add (local.get 0) (call $double (local.get 0)))
+  )
+  (func (export "call_double") (param i32) (result i32)
+    (call $double (local.get 0))
+  )
+  (func (export "store") (param i32)
+    (i32.store (i32.const 0) (local.get 0))
+  )
+  (func (export "call_indirect") (param i32 i32) (result i32)
+    (call_indirect (type $unary) (local.get 1) (local.get 0))
+  )
+  (func (export "add_counter") (param i32)
+    (global.set $counter (i32.add (global.get $counter) (local.get 0)))
+  )
+  (elem (i32.const 1) $triple)
+)
@@ -0,0 +1,14 @@
+(module
+  (memory (export "memory") 1)
+  (table (export "table") 2 funcref)
+  (global $counter (export "counter") (mut i32) (i32.const 0))
+  (global (export "answer") i64 (i64.const 42))
+  (func $double (export "double") (param i32) (result i32)
+    (i32.add (local.get 0) (local.get 0))
+  )
+  (func (export "incr") (result i32)
+    (global.set $counter (i32.add (global.get $counter) (i32.const 1)))
+    (global.get $counter)
+  )
+  (elem (i32.const 0) $double)
+)
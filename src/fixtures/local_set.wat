@@ -4,5 +4,12 @@
     (local.set $x (i32.const 42))
     (local.get 0)
   )
+  (func $copy_locals (param i32) (result i32)
+    (local i32 i32)
+    (local.set 2 (local.get 0))
+    (local.set 1 (local.get 2))
+    (local.get 2)
+  )
   (export "local_set" (func $local_set))
+  (export "copy_locals" (func $copy_locals))
 )
@@ -0,0 +1,49 @@
+(module
+  (func $swap (export "swap") (param i32 i32) (result i32 i32)
+    (local.get 1)
+    (local.get 0)
+  )
+  (func (export "call_swap") (param i32 i32) (result i32 i32)
+    (call $swap (local.get 0) (local.get 1))
+  )
+  (func (export "block_param") (param i32 i32) (result i32)
+    (local.get 0)
+    (local.get 1)
+    (block (param i32 i32) (result i32)
+      i32.sub
+    )
+  )
+  (func (export "br_results") (result i32 i32)
+    (block (result i32 i32)
+      (i32.const 1)
+      (i32.const 2)
+      (i32.const 3)
+      (i32.const 4)
+      (br 0)
+    )
+  )
+  (func (export "if_else") (param i32) (result i32 i32)
+    (if (result i32 i32) (local.get 0)
+      (then (i32.const 1) (i32.const 2))
+      (else (i32.const 3) (i32.const 4))
+    )
+  )
+  (func (export "loop_sum") (param $n i32) (result i32)
+    (i32.const 0)
+    (loop $l (param i32) (result i32)
+      (i32.add (local.get $n))
+      (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+      (br_if $l (i32.lt_s (i32.const 0) (local.get $n)))
+    )
+  )
+  (func (export "return_results") (result i32 i32)
+    (block
+      (i32.const 1)
+      (i32.const 2)
+      (i32.const 3)
+      (return)
+    )
+    (i32.const 4)
+    (i32.const 5)
+  )
+)
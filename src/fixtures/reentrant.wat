@@ -0,0 +1,24 @@
+(module
+  (import "env" "apply" (func $apply (param i32 i32) (result i32)))
+  (import "env" "try_apply" (func $try_apply (param i32 i32) (result i32)))
+  (table (export "table") 3 funcref)
+  (elem (i32.const 0) $double $trap $apply_twice)
+  (func $double (param i32) (result i32)
+    (i32.add (local.get 0) (local.get 0))
+  )
+  (func $trap (param i32) (result i32)
+    unreachable
+  )
+  (func $apply_twice (param i32) (result i32)
+    (call $apply (i32.const 0) (call $apply (i32.const 0) (local.get 0)))
+  )
+  (func (export "apply") (param i32 i32) (result i32)
+    (call $apply (local.get 0) (local.get 1))
+  )
+  (func (export "try_apply") (param i32 i32) (result i32)
+    (i32.add (i32.const 100) (call $try_apply (local.get 0) (local.get 1)))
+  )
+  (func (export "call_indirect") (param i32 i32) (result i32)
+    (call_indirect (param i32) (result i32) (local.get 1) (local.get 0))
+  )
+)
@@ -0,0 +1,41 @@
+(module
+  (import "env" "fail" (func $fail))
+  (memory (export "memory") 1)
+  (table 1 funcref)
+  (type $void (func))
+  (func (export "answer") (result i32)
+    (i32.const 42)
+  )
+  (func (export "store") (param i32)
+    (i32.store (local.get 0) (i32.const 7))
+  )
+  (func (export "store_then_trap")
+    (i32.store (i32.const 0) (i32.const 42))
+    (unreachable)
+  )
+  (func $deep (param i32) (result i32)
+    (block (result i32)
+      (local.get 0)
+      (unreachable)
+    )
+  )
+  (func (export "nested") (result i32)
+    (i32.add
+      (i32.const 1)
+      (block (result i32)
+        (call $deep (i32.const 2))
+      )
+    )
+  )
+  (func (export "undefined_element")
+    (call_indirect (type $void) (i32.const 5))
+  )
+  (func (export "call_fail")
+    (call $fail)
+  )
+  (func (export "spin")
+    (loop $l
+      (br $l)
+    )
+  )
+)